@@ -0,0 +1,132 @@
+// Turning the output of an image classification model into the most likely classes, shared by
+// the OpenVINO, PyTorch and TFLite examples.
+use std::fs;
+
+// Whether the values already are probabilities, e.g. if the model ends with a softmax layer. They
+// may sum up to less than one if a background class was left out.
+pub fn is_probabilities(values: &[f32]) -> bool {
+    let sum: f32 = values.iter().sum();
+    values.iter().all(|v| (0.0..=1.0).contains(v)) && sum > 0.0 && sum <= 1.0 + 1e-3
+}
+
+// Convert the logits into probabilities. A NaN logit gets a probability of zero and an infinite
+// one is clamped, so a single broken value can't turn all the probabilities into NaN.
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let logits: Vec<f32> = logits
+        .iter()
+        .map(|&x| {
+            if x.is_nan() {
+                f32::NEG_INFINITY
+            } else {
+                x.min(f32::MAX)
+            }
+        })
+        .collect();
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return vec![0.0; logits.len()];
+    }
+    let exps: Vec<f32> = logits.iter().map(|x| (x - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.iter().map(|x| x / sum).collect()
+}
+
+// The indices and probabilities of the `k` most likely classes, the most likely first.
+pub fn top_k(probabilities: &[f32], k: usize) -> Vec<(usize, f32)> {
+    let mut results: Vec<(usize, f32)> = probabilities.iter().copied().enumerate().collect();
+    results.sort_by(|a, b| b.1.total_cmp(&a.1));
+    results.truncate(k);
+    results
+}
+
+// The values of the first image in the output. The number of classes follows from the size of
+// the output and the batch size of the input, not from the labels, which may not match the model.
+// A single value more than there are labels is taken as a background class in front of the
+// others.
+pub fn class_values(output: &[f32], batch: usize, labels: usize) -> &[f32] {
+    let classes = if batch > 1 && output.len().is_multiple_of(batch) {
+        output.len() / batch
+    } else {
+        output.len()
+    };
+    let values = &output[..classes];
+    if classes == labels + 1 {
+        &values[1..]
+    } else {
+        values
+    }
+}
+
+// Read a labels file with one label per line, the line number being the class index.
+pub fn load_labels(path: &str) -> Result<Vec<String>, String> {
+    let labels = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read the labels file {}: {}", path, e))?;
+    Ok(labels
+        .lines()
+        .map(|label| label.trim().to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn softmax_of_known_logits() {
+        assert_close(
+            &softmax(&[1.0, 2.0, 3.0]),
+            &[0.090_030_57, 0.244_728_47, 0.665_240_94],
+        );
+        assert_close(&softmax(&[5.0, 5.0]), &[0.5, 0.5]);
+    }
+
+    #[test]
+    fn softmax_handles_nan_and_infinite_logits() {
+        assert_close(&softmax(&[f32::NAN, 0.0]), &[0.0, 1.0]);
+        assert_close(&softmax(&[f32::INFINITY, 0.0]), &[1.0, 0.0]);
+        assert_close(&softmax(&[f32::NAN, f32::NEG_INFINITY]), &[0.0, 0.0]);
+    }
+
+    #[test]
+    fn top_k_orders_by_probability() {
+        assert_eq!(
+            top_k(&[0.1, 0.5, 0.2, 0.2], 3),
+            vec![(1, 0.5), (2, 0.2), (3, 0.2)]
+        );
+        assert_eq!(top_k(&[0.3, 0.7], 5), vec![(1, 0.7), (0, 0.3)]);
+    }
+
+    #[test]
+    fn is_probabilities_tells_logits_apart() {
+        assert!(is_probabilities(&[0.2, 0.3, 0.5]));
+        assert!(is_probabilities(&[0.2, 0.3]));
+        assert!(!is_probabilities(&[1.5, -0.3, 2.0]));
+        assert!(!is_probabilities(&[0.0, 0.0]));
+    }
+
+    #[test]
+    fn class_values_takes_the_first_image_of_a_batch() {
+        let output = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        assert_eq!(class_values(&output, 2, 3), &[1.0, 2.0, 3.0]);
+        assert_eq!(class_values(&output, 1, 6), &output);
+    }
+
+    #[test]
+    fn class_values_drops_a_background_class() {
+        assert_eq!(class_values(&[9.0, 1.0, 2.0], 1, 2), &[1.0, 2.0]);
+    }
+
+    #[test]
+    fn class_values_ignores_mismatched_labels() {
+        let output = vec![0.5; 1000];
+        assert_eq!(class_values(&output, 1, 10).len(), 1000);
+        assert_eq!(class_values(&output, 2, 10).len(), 500);
+    }
+}
@@ -0,0 +1,119 @@
+// Typed helpers for setting input tensors, shared by the llava and whisper examples.
+
+use std::error::Error;
+use wasmedge_wasi_nn::{GraphExecutionContext, TensorType};
+
+// Encode f32 values as little-endian bytes, the byte order of the tensor data.
+pub fn f32_to_le_bytes(values: &[f32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+// Decode little-endian f32 values, as stored in a raw pcm file.
+pub fn f32_from_le_bytes(bytes: &[u8]) -> Result<Vec<f32>, Box<dyn Error>> {
+    if !bytes.len().is_multiple_of(4) {
+        return Err(format!("{} bytes is not a whole number of f32 values", bytes.len()).into());
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+// Check that the shape holds exactly the given number of values.
+fn check_shape(
+    index: usize,
+    tensor_type: TensorType,
+    shape: &[usize],
+    len: usize,
+) -> Result<(), Box<dyn Error>> {
+    let expected: usize = shape.iter().product();
+    if expected != len {
+        return Err(format!(
+            "Failed to set input {} as {:?}: shape {:?} needs {} values, got {}",
+            index, tensor_type, shape, expected, len
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn set_input(
+    context: &mut GraphExecutionContext,
+    index: usize,
+    tensor_type: TensorType,
+    shape: &[usize],
+    bytes: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    context
+        .set_input(index, tensor_type, shape, bytes)
+        .map_err(|e| format!("Failed to set input {} as {:?}: {}", index, tensor_type, e).into())
+}
+
+// Set a u8 input tensor, e.g. a prompt or raw image bytes, checking the shape against the data.
+pub fn set_bytes_input(
+    context: &mut GraphExecutionContext,
+    index: usize,
+    shape: &[usize],
+    data: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    check_shape(index, TensorType::U8, shape, data.len())?;
+    set_input(context, index, TensorType::U8, shape, data)
+}
+
+// Set an f32 input tensor, checking the shape against the data.
+pub fn set_f32_input(
+    context: &mut GraphExecutionContext,
+    index: usize,
+    shape: &[usize],
+    data: &[f32],
+) -> Result<(), Box<dyn Error>> {
+    check_shape(index, TensorType::F32, shape, data.len())?;
+    set_input(
+        context,
+        index,
+        TensorType::F32,
+        shape,
+        &f32_to_le_bytes(data),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn f32_round_trips_through_bytes() {
+        let values = [
+            0.0,
+            -1.5,
+            3.25,
+            f32::MIN_POSITIVE,
+            f32::MAX,
+            f32::NEG_INFINITY,
+        ];
+        let bytes = f32_to_le_bytes(&values);
+        assert_eq!(bytes.len(), values.len() * 4);
+        assert_eq!(f32_from_le_bytes(&bytes).unwrap(), values);
+    }
+
+    #[test]
+    fn f32_bytes_are_little_endian() {
+        assert_eq!(f32_to_le_bytes(&[1.0]), [0x00, 0x00, 0x80, 0x3f]);
+        assert_eq!(f32_from_le_bytes(&[0x00, 0x00, 0x80, 0x3f]).unwrap(), [1.0]);
+    }
+
+    #[test]
+    fn f32_from_le_bytes_rejects_partial_values() {
+        assert!(f32_from_le_bytes(&[0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn check_shape_compares_the_number_of_values() {
+        assert!(check_shape(0, TensorType::U8, &[2, 3], 6).is_ok());
+        let err = check_shape(1, TensorType::F32, &[1, 4], 3).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to set input 1 as F32: shape [1, 4] needs 4 values, got 3"
+        );
+    }
+}
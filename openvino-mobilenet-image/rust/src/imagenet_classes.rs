@@ -1,4 +1,4 @@
-/**
+/*
  * @license
  * Copyright 2019 Google LLC. All Rights Reserved.
  * Licensed under the Apache License, Version 2.0 (the "License");
@@ -1018,4 +1018,4 @@ pub const IMAGENET_CLASSES: [&str; 1000] = [
     "bolete",
     "ear, spike, capitulum",
     "toilet tissue, toilet paper, bathroom tissue"
-];
\ No newline at end of file
+];
@@ -1,20 +1,80 @@
 use image::io::Reader;
-use image::DynamicImage;
 use std::env;
-use wasi_nn;
+use std::error::Error;
 mod imagenet_classes;
 
 use wasi_nn::{ExecutionTarget, GraphBuilder, GraphEncoding, TensorType};
 
-pub fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    let model_xml_path: &str = &args[1];
-    let model_bin_path: &str = &args[2];
-    let image_name: &str = &args[3];
+// Room for the output of the model, e.g. a batch of a few images.
+const MAX_OUTPUT_VALUES: usize = 16 * 1024;
+
+const USAGE: &str = "usage: wasmedge-wasinn-example-mobilenet-image [--encoding openvino|pytorch|tflite] [--layout nchw|nhwc] [--size N] [--input-shape N,C,H,W] [--extra-input INDEX:SHAPE:VALUES] [--rgb|--bgr] [--mean R,G,B] [--std R,G,B] [--labels PATH] <model files...> <image>";
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // The encoding picks the defaults of the preprocessing, the other flags override them.
+    let encoding = match take_option(&mut args, "--encoding")?.as_deref() {
+        None | Some("openvino") => GraphEncoding::Openvino,
+        Some("pytorch") => GraphEncoding::Pytorch,
+        Some("tflite") => GraphEncoding::TensorflowLite,
+        Some(value) => return Err(format!("unknown encoding '{}'", value).into()),
+    };
+    let mut preprocess = Preprocess::for_encoding(encoding);
+    match take_option(&mut args, "--layout")?.as_deref() {
+        None => (),
+        Some("nchw") => preprocess.layout = Layout::Nchw,
+        Some("nhwc") => preprocess.layout = Layout::Nhwc,
+        Some(value) => return Err(format!("unknown layout '{}'", value).into()),
+    }
+    if let Some(size) = take_option(&mut args, "--size")? {
+        let size = size
+            .parse()
+            .ok()
+            .filter(|&size| size > 0)
+            .ok_or_else(|| format!("invalid value '{}' for --size", size))?;
+        preprocess.width = size;
+        preprocess.height = size;
+    }
+    // An explicit input shape overrides the size, for models with a dynamic or non-square input.
+    if let Some(shape) = take_option(&mut args, "--input-shape")? {
+        preprocess.set_input_shape(&parse_shape("--input-shape", &shape)?)?;
+    }
+    let mut extra_inputs = Vec::new();
+    while let Some(input) = take_option(&mut args, "--extra-input")? {
+        extra_inputs.push(parse_extra_input(&input)?);
+    }
+    if take_flag(&mut args, "--rgb") {
+        preprocess.bgr = false;
+    }
+    if take_flag(&mut args, "--bgr") {
+        preprocess.bgr = true;
+    }
+    if let Some(mean) = take_option(&mut args, "--mean")? {
+        preprocess.mean = parse_channels("--mean", &mean)?;
+    }
+    if let Some(std) = take_option(&mut args, "--std")? {
+        preprocess.std = parse_channels("--std", &std)?;
+        if preprocess.std.contains(&0.0) {
+            return Err("--std can't be zero".into());
+        }
+    }
+    let labels = match take_option(&mut args, "--labels")? {
+        Some(path) => classification_postprocess::load_labels(&path)?,
+        None => imagenet_classes::IMAGENET_CLASSES
+            .iter()
+            .map(|label| label.to_string())
+            .collect(),
+    };
+    // The model files come first, the image is the last argument.
+    if args.len() < 2 {
+        return Err(USAGE.into());
+    }
+    let image_name = args.pop().unwrap();
+    let model_files = args;
 
     print!("Load graph ...");
-    let graph = GraphBuilder::new(GraphEncoding::Openvino, ExecutionTarget::CPU)
-        .build_from_files([model_xml_path, model_bin_path])?;
+    let graph = GraphBuilder::new(encoding, ExecutionTarget::CPU).build_from_files(&model_files)?;
     println!("done");
 
     print!("Init execution context ...");
@@ -22,9 +82,38 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("done");
 
     print!("Set input tensor ...");
-    let input_dims = vec![1, 3, 224, 224];
-    let tensor_data = image_to_tensor(image_name.to_string(), 224, 224);
-    context.set_input(0, TensorType::F32, &input_dims, tensor_data)?;
+    let input_dims = preprocess.input_dims();
+    let tensor_data = preprocess.image_to_tensor(&image_name)?;
+    let elements: usize = input_dims.iter().product();
+    if elements * 4 != tensor_data.len() {
+        return Err(format!(
+            "the input shape {:?} has {} elements, but the preprocessed image has {}",
+            input_dims,
+            elements,
+            tensor_data.len() / 4
+        )
+        .into());
+    }
+    context
+        .set_input(0, TensorType::F32, &input_dims, &tensor_data)
+        .map_err(|e| {
+            format!(
+                "the model rejected the image as input 0 with shape {:?} ({}x{} pixels, {:?}), set the shape the model expects with --input-shape: {}",
+                input_dims, preprocess.width, preprocess.height, preprocess.layout, e
+            )
+        })?;
+    // Further inputs of models with more than one, e.g. a scalar next to the image.
+    for (index, shape, values) in &extra_inputs {
+        let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        context
+            .set_input(*index, TensorType::F32, shape, &data)
+            .map_err(|e| {
+                format!(
+                    "the model rejected input {} with shape {:?}: {}",
+                    index, shape, e
+                )
+            })?;
+    }
     println!("done");
 
     print!("Perform graph inference ...");
@@ -33,63 +122,301 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     print!("Retrieve the output ...");
     // Copy output to abuffer.
-    let mut output_buffer = vec![0f32; 1001];
+    let mut output_buffer = vec![0f32; MAX_OUTPUT_VALUES];
     let size_in_bytes = context.get_output(0, &mut output_buffer)?;
     println!("done");
     println!("The size of the output buffer is {} bytes", size_in_bytes);
 
-    let results = sort_results(&output_buffer);
-    for i in 0..5 {
+    let output = &output_buffer[..(size_in_bytes / 4).min(output_buffer.len())];
+    let values = classification_postprocess::class_values(output, input_dims[0], labels.len());
+    if values.len() != labels.len() {
+        eprintln!(
+            "[WARN] The model has {} classes but there are {} labels, classes without a label are printed as indices only",
+            values.len(),
+            labels.len()
+        );
+    }
+    let probabilities = if classification_postprocess::is_probabilities(values) {
+        values.to_vec()
+    } else {
+        classification_postprocess::softmax(values)
+    };
+    for (i, (class, probability)) in classification_postprocess::top_k(&probabilities, 5)
+        .into_iter()
+        .enumerate()
+    {
         println!(
-            "   {}.) [{}]({:.4}){}",
+            "   {}.) [{}]({:.2}){}",
             i + 1,
-            results[i].0,
-            results[i].1,
-            imagenet_classes::IMAGENET_CLASSES[results[i].0]
+            class,
+            probability,
+            labels.get(class).map_or("", String::as_str)
         );
     }
 
     Ok(())
 }
 
-// Sort the buffer of probabilities. The graph places the match probability for each class at the
-// index for that class (e.g. the probability of class 42 is placed at buffer[42]). Here we convert
-// to a wrapping InferenceResult and sort the results.
-fn sort_results(buffer: &[f32]) -> Vec<InferenceResult> {
-    let mut results: Vec<InferenceResult> = buffer
-        .iter()
-        .skip(1)
-        .enumerate()
-        .map(|(c, p)| InferenceResult(c, *p))
-        .collect();
-    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-    results
+// Remove a flag from the arguments, returning whether it was there.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != len
+}
+
+// Remove an option and its value from the arguments.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let Some(pos) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    if pos + 1 >= args.len() {
+        return Err(format!("{} requires a value", name));
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Ok(Some(value))
+}
+
+// Parse a value per color channel in RGB order, e.g. `123.675,116.28,103.53`.
+fn parse_channels(flag: &str, value: &str) -> Result<[f32; 3], String> {
+    let invalid = || format!("invalid value '{}' for {}, expected R,G,B", value, flag);
+    let values = value
+        .split(',')
+        .map(|v| v.trim().parse::<f32>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+    values.try_into().map_err(|_| invalid())
+}
+
+// Parse a tensor shape, e.g. `1,3,224,224`.
+fn parse_shape(flag: &str, value: &str) -> Result<Vec<usize>, String> {
+    value
+        .split(',')
+        .map(|dim| {
+            dim.trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|&dim| dim > 0)
+                .ok_or_else(|| {
+                    format!(
+                        "invalid value '{}' for {}, expected a shape like 1,3,224,224",
+                        value, flag
+                    )
+                })
+        })
+        .collect()
+}
+
+// Parse an additional f32 input tensor given as `INDEX:SHAPE:VALUES`, e.g. `1:1:0.5` for a
+// scalar as input 1, or `2:1,2:0.5,0.25` for a 1x2 tensor as input 2.
+fn parse_extra_input(value: &str) -> Result<(usize, Vec<usize>, Vec<f32>), String> {
+    let invalid = || {
+        format!(
+            "invalid value '{}' for --extra-input, expected INDEX:SHAPE:VALUES",
+            value
+        )
+    };
+    let mut parts = value.splitn(3, ':');
+    let (Some(index), Some(shape), Some(values)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    let index = index.trim().parse::<usize>().map_err(|_| invalid())?;
+    if index == 0 {
+        return Err("--extra-input can't set input 0, which is the image".to_string());
+    }
+    let shape = parse_shape("--extra-input", shape)?;
+    let values = values
+        .split(',')
+        .map(|v| v.trim().parse::<f32>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let elements: usize = shape.iter().product();
+    if elements != values.len() {
+        return Err(format!(
+            "--extra-input {}: the shape {:?} has {} elements, but {} values are given",
+            index,
+            shape,
+            elements,
+            values.len()
+        ));
+    }
+    Ok((index, shape, values))
 }
 
-// Take the image located at 'path', open it, resize it to height x width, and then converts
-// the pixel precision to FP32. The resulting BGR pixel vector is then returned.
-fn image_to_tensor(path: String, height: u32, width: u32) -> Vec<u8> {
-    let pixels = Reader::open(path).unwrap().decode().unwrap();
-    let dyn_img: DynamicImage = pixels.resize_exact(width, height, image::imageops::Triangle);
-    let bgr_img = dyn_img.to_bgr8();
-    // Get an array of the pixel values
-    let raw_u8_arr: &[u8] = &bgr_img.as_raw()[..];
-    // Create an array to hold the f32 value of those pixels
-    let bytes_required = raw_u8_arr.len() * 4;
-    let mut u8_f32_arr: Vec<u8> = vec![0; bytes_required];
-
-    for i in 0..raw_u8_arr.len() {
-        // Read the number as a f32 and break it into u8 bytes
-        let u8_f32: f32 = raw_u8_arr[i] as f32;
-        let u8_bytes = u8_f32.to_ne_bytes();
-
-        for j in 0..4 {
-            u8_f32_arr[(i * 4) + j] = u8_bytes[j];
+// How the pixels are laid out in the input tensor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Layout {
+    // All values of the first channel, then the second and the third, as PyTorch and OpenVINO expect.
+    Nchw,
+    // The channels of every pixel next to each other, as TFLite expects.
+    Nhwc,
+}
+
+// How the image is converted into the input tensor.
+struct Preprocess {
+    // The width and height the image is resized to.
+    width: u32,
+    height: u32,
+    layout: Layout,
+    // Put the channels in BGR instead of RGB order.
+    bgr: bool,
+    // Every pixel value in 0-255 becomes (value - mean) / std, per channel in RGB order.
+    mean: [f32; 3],
+    std: [f32; 3],
+}
+
+impl Preprocess {
+    // The preprocessing the usual exports of MobileNet expect for each backend.
+    fn for_encoding(encoding: GraphEncoding) -> Self {
+        match encoding {
+            GraphEncoding::Pytorch => Preprocess {
+                width: 224,
+                height: 224,
+                layout: Layout::Nchw,
+                bgr: false,
+                mean: [123.675, 116.28, 103.53],
+                std: [58.395, 57.12, 57.375],
+            },
+            GraphEncoding::TensorflowLite => Preprocess {
+                width: 224,
+                height: 224,
+                layout: Layout::Nhwc,
+                bgr: false,
+                mean: [127.5, 127.5, 127.5],
+                std: [127.5, 127.5, 127.5],
+            },
+            _ => Preprocess {
+                width: 224,
+                height: 224,
+                layout: Layout::Nchw,
+                bgr: true,
+                mean: [0.0, 0.0, 0.0],
+                std: [1.0, 1.0, 1.0],
+            },
         }
     }
-    return u8_f32_arr;
+
+    fn input_dims(&self) -> [usize; 4] {
+        let (width, height) = (self.width as usize, self.height as usize);
+        match self.layout {
+            Layout::Nchw => [1, 3, height, width],
+            Layout::Nhwc => [1, height, width, 3],
+        }
+    }
+
+    // Take the width and height from the input shape the model expects, in the order of the
+    // layout. The shape must be that of a single image with three color channels.
+    fn set_input_shape(&mut self, shape: &[usize]) -> Result<(), String> {
+        let (batch, channels, height, width) = match (self.layout, shape) {
+            (Layout::Nchw, &[n, c, h, w]) => (n, c, h, w),
+            (Layout::Nhwc, &[n, h, w, c]) => (n, c, h, w),
+            _ => {
+                return Err(format!(
+                    "the input shape {:?} doesn't have four dimensions as the {:?} layout requires",
+                    shape, self.layout
+                ))
+            }
+        };
+        if batch != 1 || channels != 3 {
+            return Err(format!(
+                "the input shape {:?} must be a single image with 3 channels in the {:?} layout",
+                shape, self.layout
+            ));
+        }
+        self.width =
+            u32::try_from(width).map_err(|_| format!("the width {} is too large", width))?;
+        self.height =
+            u32::try_from(height).map_err(|_| format!("the height {} is too large", height))?;
+        Ok(())
+    }
+
+    // Decode the image located at 'path', whatever its format, convert it to RGB, e.g. if it's
+    // grayscale or has an alpha channel, resize it to width x height, and then normalize and lay out
+    // the pixels as FP32 values. The bytes of the resulting tensor are returned.
+    fn image_to_tensor(&self, path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let image = Reader::open(path)?.with_guessed_format()?.decode()?;
+        let rgb = image
+            .resize_exact(self.width, self.height, image::imageops::Triangle)
+            .to_rgb8();
+        let pixels = self.width as usize * self.height as usize;
+        let mut tensor = vec![0f32; pixels * 3];
+        for (i, pixel) in rgb.pixels().enumerate() {
+            for c in 0..3 {
+                let value = (pixel[c] as f32 - self.mean[c]) / self.std[c];
+                let channel = if self.bgr { 2 - c } else { c };
+                let index = match self.layout {
+                    Layout::Nchw => channel * pixels + i,
+                    Layout::Nhwc => i * 3 + channel,
+                };
+                tensor[index] = value;
+            }
+        }
+        Ok(tensor.iter().flat_map(|v| v.to_le_bytes()).collect())
+    }
 }
 
-// A wrapper for class ID and match probabilities.
-#[derive(Debug, PartialEq)]
-struct InferenceResult(usize, f32);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_removed_with_their_value() {
+        let mut args: Vec<String> = ["model.xml", "--bgr", "--mean", "1,2,3", "image.jpg"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert_eq!(
+            take_option(&mut args, "--mean"),
+            Ok(Some("1,2,3".to_string()))
+        );
+        assert!(take_flag(&mut args, "--bgr"));
+        assert_eq!(args, ["model.xml", "image.jpg"]);
+        assert!(take_option(&mut vec!["--std".to_string()], "--std").is_err());
+    }
+
+    #[test]
+    fn channels() {
+        assert_eq!(
+            parse_channels("--mean", "123.675, 116.28,103.53"),
+            Ok([123.675, 116.28, 103.53])
+        );
+        assert!(parse_channels("--mean", "1,2").is_err());
+        assert!(parse_channels("--mean", "1,2,x").is_err());
+    }
+
+    #[test]
+    fn shapes() {
+        assert_eq!(
+            parse_shape("--input-shape", "1,3,224,224"),
+            Ok(vec![1, 3, 224, 224])
+        );
+        assert!(parse_shape("--input-shape", "1,0,224").is_err());
+    }
+
+    #[test]
+    fn extra_inputs() {
+        assert_eq!(parse_extra_input("1:1:0.5"), Ok((1, vec![1], vec![0.5])));
+        assert_eq!(
+            parse_extra_input("2:1,2:0.5,0.25"),
+            Ok((2, vec![1, 2], vec![0.5, 0.25]))
+        );
+        assert!(parse_extra_input("0:1:0.5").is_err());
+        assert!(parse_extra_input("1:1,2:0.5").is_err());
+        assert!(parse_extra_input("1:1").is_err());
+    }
+
+    #[test]
+    fn input_shape_in_the_order_of_the_layout() {
+        let mut nchw = Preprocess::for_encoding(GraphEncoding::Openvino);
+        nchw.set_input_shape(&[1, 3, 192, 256]).unwrap();
+        assert_eq!((nchw.width, nchw.height), (256, 192));
+        assert_eq!(nchw.input_dims(), [1, 3, 192, 256]);
+
+        let mut nhwc = Preprocess::for_encoding(GraphEncoding::TensorflowLite);
+        nhwc.set_input_shape(&[1, 192, 256, 3]).unwrap();
+        assert_eq!(nhwc.input_dims(), [1, 192, 256, 3]);
+
+        assert!(nchw.set_input_shape(&[2, 3, 224, 224]).is_err());
+        assert!(nchw.set_input_shape(&[1, 224, 224, 3]).is_err());
+        assert!(nchw.set_input_shape(&[1, 3, 224]).is_err());
+    }
+}
@@ -1,12 +1,22 @@
-use image;
 use std::env;
-use std::fs::File;
-use std::io::Read;
-use wasi_nn;
 mod imagenet_classes;
+mod tensor;
+
+use tensor::{take_option, TensorOptions};
 
 pub fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let tensor_options = TensorOptions::from_args(&mut args);
+    let labels = match take_option(&mut args, "--labels") {
+        Some(path) => classification_postprocess::load_labels(&path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        None => imagenet_classes::IMAGENET_CLASSES
+            .iter()
+            .map(|label| label.to_string())
+            .collect(),
+    };
     let model_name: &str = &args[1];
     let image_name: &str = &args[2];
 
@@ -22,75 +32,40 @@ pub fn main() {
     println!("Created wasi-nn execution context with ID: {:?}", context);
 
     // Load a tensor that precisely matches the graph input tensor (see
-    let tensor_data = image_to_tensor(image_name.to_string(), 224, 224);
+    let tensor_data = tensor_options.image_to_tensor(image_name, 224, 224);
     println!("Read input tensor, size in bytes: {}", tensor_data.len());
-    context
-        .set_input(0, wasi_nn::TensorType::F32, &[1, 3, 224, 224], &tensor_data)
-        .unwrap();
+    let input_dims = tensor_options.input_dims(224, 224);
+    tensor_options.set_input(&mut context, &input_dims, &tensor_data);
     // Execute the inference.
     context.compute().unwrap();
     println!("Executed graph inference");
     // Retrieve the output.
-    let mut output_buffer = vec![0f32; 1000];
-    context.get_output(0, &mut output_buffer).unwrap();
+    let output = tensor_options.get_output(&context, labels.len());
 
-    let results = sort_results(&output_buffer);
-    for i in 0..5 {
-        println!(
-            "   {}.) [{}]({:.4}){}",
-            i + 1,
-            results[i].0,
-            results[i].1,
-            imagenet_classes::IMAGENET_CLASSES[results[i].0]
+    // Print the probabilities of the first image, applying a softmax if the model outputs logits.
+    let values = classification_postprocess::class_values(&output, input_dims[0], labels.len());
+    if values.len() != labels.len() {
+        eprintln!(
+            "[WARN] The model has {} classes but there are {} labels, classes without a label are printed as indices only",
+            values.len(),
+            labels.len()
         );
     }
-}
-
-// Sort the buffer of probabilities. The graph places the match probability for each class at the
-// index for that class (e.g. the probability of class 42 is placed at buffer[42]). Here we convert
-// to a wrapping InferenceResult and sort the results.
-fn sort_results(buffer: &[f32]) -> Vec<InferenceResult> {
-    let mut results: Vec<InferenceResult> = buffer
-        .iter()
+    let probabilities = if classification_postprocess::is_probabilities(values) {
+        values.to_vec()
+    } else {
+        classification_postprocess::softmax(values)
+    };
+    for (i, (class, probability)) in classification_postprocess::top_k(&probabilities, 5)
+        .into_iter()
         .enumerate()
-        .map(|(c, p)| InferenceResult(c, *p))
-        .collect();
-    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-    results
-}
-
-// Take the image located at 'path', open it, resize it to height x width, and then converts
-// the pixel precision to FP32. The resulting BGR pixel vector is then returned.
-fn image_to_tensor(path: String, height: u32, width: u32) -> Vec<u8> {
-    let mut file_img = File::open(path).unwrap();
-    let mut img_buf = Vec::new();
-    file_img.read_to_end(&mut img_buf).unwrap();
-    let img = image::load_from_memory(&img_buf).unwrap().to_rgb8();
-    let resized =
-        image::imageops::resize(&img, height, width, ::image::imageops::FilterType::Triangle);
-    let mut flat_img: Vec<f32> = Vec::new();
-    for rgb in resized.pixels() {
-        flat_img.push((rgb[0] as f32 / 255. - 0.485) / 0.229);
-        flat_img.push((rgb[1] as f32 / 255. - 0.456) / 0.224);
-        flat_img.push((rgb[2] as f32 / 255. - 0.406) / 0.225);
-    }
-    let bytes_required = flat_img.len() * 4;
-    let mut u8_f32_arr: Vec<u8> = vec![0; bytes_required];
-
-    for c in 0..3 {
-        for i in 0..(flat_img.len() / 3) {
-            // Read the number as a f32 and break it into u8 bytes
-            let u8_f32: f32 = flat_img[i * 3 + c] as f32;
-            let u8_bytes = u8_f32.to_ne_bytes();
-
-            for j in 0..4 {
-                u8_f32_arr[((flat_img.len() / 3 * c + i) * 4) + j] = u8_bytes[j];
-            }
-        }
+    {
+        println!(
+            "   {}.) [{}]({:.2}){}",
+            i + 1,
+            class,
+            probability,
+            labels.get(class).map_or("", String::as_str)
+        );
     }
-    return u8_f32_arr;
 }
-
-// A wrapper for class ID and match probabilities.
-#[derive(Debug, PartialEq)]
-struct InferenceResult(usize, f32);
@@ -0,0 +1,285 @@
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use wasi_nn::{GraphExecutionContext, TensorType};
+
+// The element type of a tensor, f32 for float exports and u8 for quantized ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dtype {
+    F32,
+    U8,
+}
+
+impl Dtype {
+    fn size(self) -> usize {
+        match self {
+            Dtype::F32 => 4,
+            Dtype::U8 => 1,
+        }
+    }
+
+    fn tensor_type(self) -> TensorType {
+        match self {
+            Dtype::F32 => TensorType::F32,
+            Dtype::U8 => TensorType::U8,
+        }
+    }
+}
+
+// How the pixels are laid out in the input tensor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    Nchw,
+    Nhwc,
+}
+
+// The parameters of an affine quantization, a quantized value q stands for (q - zero_point) * scale.
+#[derive(Clone, Copy, Debug)]
+pub struct Quantization {
+    pub scale: f32,
+    pub zero_point: i32,
+}
+
+impl Quantization {
+    pub fn quantize(&self, value: f32) -> u8 {
+        ((value / self.scale).round() as i32 + self.zero_point).clamp(0, 255) as u8
+    }
+
+    pub fn dequantize(&self, value: u8) -> f32 {
+        (value as i32 - self.zero_point) as f32 * self.scale
+    }
+}
+
+// The input and output tensor types, selected by the command line flags.
+pub struct TensorOptions {
+    pub input_dtype: Dtype,
+    pub layout: Layout,
+    // Quantize the normalized image with these, for a u8 input. Without them the raw pixel
+    // values 0-255 are passed.
+    pub input_quantization: Option<Quantization>,
+    pub output_dtype: Dtype,
+    // Dequantize a u8 output with these, required for a u8 output.
+    pub output_quantization: Option<Quantization>,
+}
+
+impl TensorOptions {
+    // Take the tensor flags out of the arguments, exiting with a message on invalid values.
+    pub fn from_args(args: &mut Vec<String>) -> Self {
+        let dtype = |args: &mut Vec<String>, name: &str| match take_option(args, name).as_deref() {
+            None | Some("f32") => Dtype::F32,
+            Some("u8") => Dtype::U8,
+            Some(value) => exit_with_usage(&format!(
+                "invalid value '{}' for {}, expected f32 or u8",
+                value, name
+            )),
+        };
+        let input_dtype = dtype(args, "--dtype");
+        let output_dtype = dtype(args, "--output-dtype");
+        let layout = match take_option(args, "--layout").as_deref() {
+            None | Some("nchw") => Layout::Nchw,
+            Some("nhwc") => Layout::Nhwc,
+            Some(value) => exit_with_usage(&format!(
+                "invalid value '{}' for --layout, expected nchw or nhwc",
+                value
+            )),
+        };
+        let input_quantization = take_quantization(args, "--input-scale", "--input-zero-point");
+        let output_quantization = take_quantization(args, "--output-scale", "--output-zero-point");
+        if input_quantization.is_some() && input_dtype != Dtype::U8 {
+            exit_with_usage("--input-scale and --input-zero-point require --dtype u8");
+        }
+        if output_dtype == Dtype::U8 && output_quantization.is_none() {
+            exit_with_usage("--output-dtype u8 requires --output-scale and --output-zero-point to dequantize the output");
+        }
+        if output_quantization.is_some() && output_dtype != Dtype::U8 {
+            exit_with_usage("--output-scale and --output-zero-point require --output-dtype u8");
+        }
+        TensorOptions {
+            input_dtype,
+            layout,
+            input_quantization,
+            output_dtype,
+            output_quantization,
+        }
+    }
+
+    pub fn input_dims(&self, height: usize, width: usize) -> [usize; 4] {
+        match self.layout {
+            Layout::Nchw => [1, 3, height, width],
+            Layout::Nhwc => [1, height, width, 3],
+        }
+    }
+
+    // Take the image located at 'path', open it, resize it to height x width, normalize it with
+    // the ImageNet mean and standard deviation and lay it out as the input tensor. The bytes of
+    // the tensor are returned.
+    pub fn image_to_tensor(&self, path: &str, height: u32, width: u32) -> Vec<u8> {
+        let mut file_img = File::open(path).unwrap();
+        let mut img_buf = Vec::new();
+        file_img.read_to_end(&mut img_buf).unwrap();
+        let img = image::load_from_memory(&img_buf).unwrap().to_rgb8();
+        let resized =
+            image::imageops::resize(&img, width, height, ::image::imageops::FilterType::Triangle);
+        const MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+        const STD: [f32; 3] = [0.229, 0.224, 0.225];
+        let pixels = (height * width) as usize;
+        let mut tensor = vec![0u8; pixels * 3 * self.input_dtype.size()];
+        for (i, rgb) in resized.pixels().enumerate() {
+            for c in 0..3 {
+                let index = match self.layout {
+                    Layout::Nchw => c * pixels + i,
+                    Layout::Nhwc => i * 3 + c,
+                };
+                let normalized = (rgb[c] as f32 / 255. - MEAN[c]) / STD[c];
+                match (self.input_dtype, self.input_quantization) {
+                    (Dtype::F32, _) => {
+                        tensor[index * 4..index * 4 + 4].copy_from_slice(&normalized.to_le_bytes())
+                    }
+                    (Dtype::U8, Some(quantization)) => {
+                        tensor[index] = quantization.quantize(normalized)
+                    }
+                    (Dtype::U8, None) => tensor[index] = rgb[c],
+                }
+            }
+        }
+        tensor
+    }
+
+    // Set the image tensor as input 0, explaining a rejection in terms of the flags.
+    pub fn set_input(&self, context: &mut GraphExecutionContext, dims: &[usize], tensor: &[u8]) {
+        let result = context.set_input(0, self.input_dtype.tensor_type(), dims, tensor);
+        if let Err(e) = result {
+            eprintln!(
+                "The model rejected the input as a {:?} tensor of shape {:?} in the {:?} layout, check --dtype and --layout: {}",
+                self.input_dtype, dims, self.layout, e
+            );
+            std::process::exit(1);
+        }
+    }
+
+    // Read output 0 as logits, dequantizing a u8 output.
+    pub fn get_output(&self, context: &GraphExecutionContext, classes: usize) -> Vec<f32> {
+        let mut output_buffer = vec![0u8; MAX_OUTPUT_BYTES];
+        let size_in_bytes = context.get_output(0, &mut output_buffer).unwrap();
+        let output = &output_buffer[..size_in_bytes.min(output_buffer.len())];
+        // An output of exactly one byte or four bytes per class hints at the other dtype.
+        let hint = match self.output_dtype {
+            Dtype::F32 if output.len() == classes || !output.len().is_multiple_of(4) => {
+                Some(Dtype::U8)
+            }
+            Dtype::U8 if output.len() == classes * 4 => Some(Dtype::F32),
+            _ => None,
+        };
+        if let Some(dtype) = hint {
+            eprintln!(
+                "[WARN] The output has {} bytes, which looks like a {:?} output for {} classes, check --output-dtype",
+                output.len(),
+                dtype,
+                classes
+            );
+        }
+        match self.output_quantization {
+            Some(quantization) if self.output_dtype == Dtype::U8 => {
+                output.iter().map(|&q| quantization.dequantize(q)).collect()
+            }
+            _ => output
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        }
+    }
+}
+
+// Room for the output of the model, e.g. a batch of a few images.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+fn exit_with_usage(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+// Remove an option and its value from the arguments.
+pub fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let pos = args.iter().position(|arg| arg == name)?;
+    if pos + 1 >= args.len() {
+        exit_with_usage(&format!("{} requires a value", name));
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Some(value)
+}
+
+fn parse_option<T: FromStr>(args: &mut Vec<String>, name: &str) -> Option<T> {
+    take_option(args, name).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| exit_with_usage(&format!("invalid value '{}' for {}", value, name)))
+    })
+}
+
+// Take a scale and zero point given together, e.g. `--output-scale 0.1 --output-zero-point 128`.
+fn take_quantization(
+    args: &mut Vec<String>,
+    scale: &str,
+    zero_point: &str,
+) -> Option<Quantization> {
+    match (
+        parse_option::<f32>(args, scale),
+        parse_option::<i32>(args, zero_point),
+    ) {
+        (None, None) => None,
+        (Some(scale), Some(zero_point)) if scale > 0.0 && (0..=255).contains(&zero_point) => {
+            Some(Quantization { scale, zero_point })
+        }
+        (Some(_), Some(_)) => exit_with_usage(&format!(
+            "{} must be positive and {} in 0-255",
+            scale, zero_point
+        )),
+        _ => exit_with_usage(&format!(
+            "{} and {} must be given together",
+            scale, zero_point
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantization_round_trip() {
+        let quantization = Quantization {
+            scale: 0.1,
+            zero_point: 128,
+        };
+        assert_eq!(quantization.quantize(0.0), 128);
+        assert_eq!(quantization.quantize(1.0), 138);
+        assert!((quantization.dequantize(138) - 1.0).abs() < 1e-6);
+        // Values out of range saturate.
+        assert_eq!(quantization.quantize(100.0), 255);
+        assert_eq!(quantization.quantize(-100.0), 0);
+    }
+
+    #[test]
+    fn input_dims_follow_the_layout() {
+        let options = |layout| TensorOptions {
+            input_dtype: Dtype::F32,
+            layout,
+            input_quantization: None,
+            output_dtype: Dtype::F32,
+            output_quantization: None,
+        };
+        assert_eq!(options(Layout::Nchw).input_dims(192, 256), [1, 3, 192, 256]);
+        assert_eq!(options(Layout::Nhwc).input_dims(192, 256), [1, 192, 256, 3]);
+    }
+
+    #[test]
+    fn default_tensor_options() {
+        let mut args = vec![String::from("model.pt"), String::from("image.jpg")];
+        let options = TensorOptions::from_args(&mut args);
+        assert_eq!(options.input_dtype, Dtype::F32);
+        assert_eq!(options.output_dtype, Dtype::F32);
+        assert_eq!(options.layout, Layout::Nchw);
+        assert_eq!(args.len(), 2);
+    }
+}
@@ -1,76 +1,312 @@
 use image::io::Reader;
 use image::DynamicImage;
 use std::env;
-use std::fs;
 use std::error::Error;
-use wasi_nn::{GraphBuilder, GraphEncoding, ExecutionTarget, TensorType};
+use std::fs;
+use std::time::{Duration, Instant};
+use wasi_nn::{ExecutionTarget, GraphBuilder, GraphEncoding, TensorType};
 mod imagenet_classes;
 
+// Room for the output of the model, one byte per class.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
 pub fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let int8 = take_flag(&mut args, "--int8");
+    let input_quantization =
+        take_quantization(&mut args, "--input-scale", "--input-zero-point", int8)?;
+    let output_quantization =
+        take_quantization(&mut args, "--output-scale", "--output-zero-point", int8)?;
+    let benchmark = match take_option(&mut args, "--benchmark")? {
+        Some(runs) => runs
+            .parse::<usize>()
+            .ok()
+            .filter(|&runs| runs > 0)
+            .ok_or_else(|| format!("invalid value '{}' for --benchmark", runs))?,
+        None => 0,
+    };
+    let labels = match take_option(&mut args, "--labels")? {
+        Some(path) => classification_postprocess::load_labels(&path)?,
+        None => imagenet_classes::AIY_BIRDS_V1
+            .iter()
+            .map(|label| label.to_string())
+            .collect::<Vec<_>>(),
+    };
+    if args.len() < 3 {
+        return Err("usage: wasmedge-wasinn-example-tflite-bird-image [--int8] [--input-scale S --input-zero-point Z] [--output-scale S --output-zero-point Z] [--labels PATH] [--benchmark N] <model> <image>".into());
+    }
     let model_bin_name: &str = &args[1];
     let image_name: &str = &args[2];
 
     let weights = fs::read(model_bin_name)?;
     println!("Read graph weights, size in bytes: {}", weights.len());
 
-    let graph = GraphBuilder::new(GraphEncoding::TensorflowLite, ExecutionTarget::CPU).build_from_bytes(&[&weights])?;
+    let graph = GraphBuilder::new(GraphEncoding::TensorflowLite, ExecutionTarget::CPU)
+        .build_from_bytes([&weights])?;
     let mut ctx = graph.init_execution_context()?;
     println!("Loaded graph into wasi-nn with ID: {}", graph);
 
     // Load a tensor that precisely matches the graph input tensor (see
-    let tensor_data = image_to_tensor(image_name.to_string(), 224, 224);
+    let tensor_data = image_to_tensor(image_name, 224, 224, input_quantization, int8)?;
     println!("Read input tensor, size in bytes: {}", tensor_data.len());
-
-    // Pass tensor data into the TFLite runtime
+    // Pass tensor data into the TFLite runtime. An int8 tensor is passed as the same bytes.
     ctx.set_input(0, TensorType::U8, &[1, 224, 224, 3], &tensor_data)?;
 
     // Execute the inference.
     ctx.compute()?;
+    if benchmark > 0 {
+        let mut latencies = Vec::with_capacity(benchmark);
+        for _ in 0..benchmark {
+            let start = Instant::now();
+            ctx.compute()?;
+            latencies.push(start.elapsed());
+        }
+        let mean = latencies.iter().sum::<Duration>() / benchmark as u32;
+        let min = latencies.iter().min().unwrap();
+        println!(
+            "Benchmark: {} runs, mean {:.2} ms, min {:.2} ms",
+            benchmark,
+            mean.as_secs_f64() * 1000.0,
+            min.as_secs_f64() * 1000.0
+        );
+    }
 
     // Retrieve the output.
-    let mut output_buffer = vec![0u8; imagenet_classes::AIY_BIRDS_V1.len()];
-    _ = ctx.get_output(0, &mut output_buffer)?;
+    let mut output_buffer = vec![0u8; MAX_OUTPUT_BYTES];
+    let size_in_bytes = ctx.get_output(0, &mut output_buffer)?;
+    output_buffer.truncate(size_in_bytes);
+
+    // Dequantize the output. Without explicit parameters, the output is assumed to be the
+    // probabilities scaled to 0-255, as quantized models ending with a softmax have it.
+    let probabilities: Vec<f32> = match output_quantization {
+        Some(quantization) => {
+            let values: Vec<f32> = output_buffer
+                .iter()
+                .map(|&q| quantization.dequantize(q, int8))
+                .collect();
+            if classification_postprocess::is_probabilities(&values) {
+                values
+            } else {
+                classification_postprocess::softmax(&values)
+            }
+        }
+        None => {
+            let assumed = Quantization {
+                scale: 1.0 / 255.0,
+                zero_point: if int8 { -128 } else { 0 },
+            };
+            output_buffer
+                .iter()
+                .map(|&q| assumed.dequantize(q, int8))
+                .collect()
+        }
+    };
+
+    let probabilities = classification_postprocess::class_values(&probabilities, 1, labels.len());
+    if probabilities.len() != labels.len() {
+        eprintln!(
+            "[WARN] The model has {} classes but there are {} labels, classes without a label are printed as indices only",
+            probabilities.len(),
+            labels.len()
+        );
+    }
 
     // Sort the result with the highest probability result first
-    let results = sort_results(&output_buffer);
-    for i in 0..5 {
+    for (i, (class, probability)) in classification_postprocess::top_k(probabilities, 5)
+        .into_iter()
+        .enumerate()
+    {
         println!(
-            "   {}.) [{}]({:.4}){}",
+            "   {}.) [{}]({:.2}){}",
             i + 1,
-            results[i].0,
-            results[i].1,
-            imagenet_classes::AIY_BIRDS_V1[results[i].0]
+            class,
+            probability,
+            labels.get(class).map_or("", String::as_str)
         );
     }
 
     Ok(())
 }
 
-// Sort the buffer of probabilities. The graph places the match probability for each class at the
-// index for that class (e.g. the probability of class 42 is placed at buffer[42]). Here we convert
-// to a wrapping InferenceResult and sort the results.
-fn sort_results(buffer: &[u8]) -> Vec<InferenceResult> {
-    let mut results: Vec<InferenceResult> = buffer
-        .iter()
-        .enumerate()
-        .map(|(c, p)| InferenceResult(c, *p))
-        .collect();
-    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-    results
+// Remove a flag from the arguments, returning whether it was there.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != len
+}
+
+// Remove an option and its value from the arguments.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let Some(pos) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    if pos + 1 >= args.len() {
+        return Err(format!("{} requires a value", name));
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Ok(Some(value))
+}
+
+// The per-tensor parameters of an affine quantization, a quantized value q stands for
+// (q - zero_point) * scale.
+#[derive(Clone, Copy, Debug)]
+struct Quantization {
+    scale: f32,
+    zero_point: i32,
 }
 
-// Take the image located at 'path', open it, resize it to height x width. The resulting RGB
-// pixel vector is then returned.
-fn image_to_tensor(path: String, height: u32, width: u32) -> Vec<u8> {
-    let pixels = Reader::open(path).unwrap().decode().unwrap();
+impl Quantization {
+    // Quantize into the uint8 range, or the int8 range stored as its two's complement bytes.
+    fn quantize(&self, value: f32, int8: bool) -> u8 {
+        let q = (value / self.scale).round() as i32 + self.zero_point;
+        if int8 {
+            q.clamp(-128, 127) as i8 as u8
+        } else {
+            q.clamp(0, 255) as u8
+        }
+    }
+
+    fn dequantize(&self, q: u8, int8: bool) -> f32 {
+        let q = if int8 { q as i8 as i32 } else { q as i32 };
+        (q - self.zero_point) as f32 * self.scale
+    }
+}
+
+// Take a scale and zero point given together, e.g. `--input-scale 0.0078125 --input-zero-point 128`.
+fn take_quantization(
+    args: &mut Vec<String>,
+    scale: &str,
+    zero_point: &str,
+    int8: bool,
+) -> Result<Option<Quantization>, String> {
+    let (scale_value, zero_point_value) =
+        match (take_option(args, scale)?, take_option(args, zero_point)?) {
+            (None, None) => return Ok(None),
+            (Some(scale_value), Some(zero_point_value)) => (scale_value, zero_point_value),
+            _ => {
+                return Err(format!(
+                    "{} and {} must be given together",
+                    scale, zero_point
+                ))
+            }
+        };
+    let range = if int8 { -128..=127 } else { 0..=255 };
+    let quantization = Quantization {
+        scale: scale_value
+            .parse()
+            .ok()
+            .filter(|&scale: &f32| scale > 0.0)
+            .ok_or_else(|| format!("invalid value '{}' for {}", scale_value, scale))?,
+        zero_point: zero_point_value
+            .parse()
+            .ok()
+            .filter(|zero_point| range.contains(zero_point))
+            .ok_or_else(|| {
+                format!(
+                    "invalid value '{}' for {}, expected {:?}",
+                    zero_point_value, zero_point, range
+                )
+            })?,
+    };
+    Ok(Some(quantization))
+}
+
+// Take the image located at 'path', open it, resize it to height x width. Without quantization
+// parameters, the resulting RGB pixel vector is returned as is, which assumes that the model takes
+// the raw pixel values. Otherwise the pixels are scaled to -1..1 and quantized with them.
+fn image_to_tensor(
+    path: &str,
+    height: u32,
+    width: u32,
+    quantization: Option<Quantization>,
+    int8: bool,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let pixels = Reader::open(path)?.with_guessed_format()?.decode()?;
     let dyn_img: DynamicImage = pixels.resize_exact(width, height, image::imageops::Triangle);
-    let bgr_img = dyn_img.to_rgb8();
+    let rgb_img = dyn_img.to_rgb8();
     // Get an array of the pixel values
-    let raw_u8_arr: &[u8] = &bgr_img.as_raw()[..];
-    return raw_u8_arr.to_vec();
+    let raw_u8_arr: &[u8] = &rgb_img.as_raw()[..];
+    Ok(match quantization {
+        Some(quantization) => raw_u8_arr
+            .iter()
+            .map(|&pixel| quantization.quantize((pixel as f32 - 127.5) / 127.5, int8))
+            .collect(),
+        // An int8 model takes the pixel values shifted into its range.
+        None if int8 => raw_u8_arr
+            .iter()
+            .map(|&pixel| (pixel as i32 - 128) as i8 as u8)
+            .collect(),
+        None => raw_u8_arr.to_vec(),
+    })
 }
 
-// A wrapper for class ID and match probabilities.
-#[derive(Debug, PartialEq)]
-struct InferenceResult(usize, u8);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn uint8_and_int8_quantization() {
+        let uint8 = Quantization {
+            scale: 0.0078125,
+            zero_point: 128,
+        };
+        assert_eq!(uint8.quantize(-1.0, false), 0);
+        assert_eq!(uint8.quantize(0.0, false), 128);
+        assert_eq!(uint8.dequantize(255, false), 0.9921875);
+        let int8 = Quantization {
+            scale: 0.0078125,
+            zero_point: 0,
+        };
+        assert_eq!(int8.quantize(-1.0, true), (-128i8) as u8);
+        assert_eq!(int8.quantize(2.0, true), 127);
+        assert_eq!(int8.dequantize((-128i8) as u8, true), -1.0);
+    }
+
+    #[test]
+    fn quantization_flags_go_together() {
+        let mut given = args(&["--input-scale", "0.5", "--input-zero-point", "-3", "model"]);
+        let quantization =
+            take_quantization(&mut given, "--input-scale", "--input-zero-point", true).unwrap();
+        assert!(
+            matches!(quantization, Some(Quantization { scale, zero_point: -3 }) if scale == 0.5)
+        );
+        assert_eq!(given, args(&["model"]));
+        assert!(matches!(
+            take_quantization(&mut given, "--input-scale", "--input-zero-point", false),
+            Ok(None)
+        ));
+        let mut only_scale = args(&["--input-scale", "0.5"]);
+        assert!(take_quantization(
+            &mut only_scale,
+            "--input-scale",
+            "--input-zero-point",
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn invalid_quantization_values() {
+        let mut negative_zero_point = args(&["--input-scale", "0.5", "--input-zero-point", "-3"]);
+        assert!(take_quantization(
+            &mut negative_zero_point,
+            "--input-scale",
+            "--input-zero-point",
+            false
+        )
+        .is_err());
+        let mut zero_scale = args(&["--input-scale", "0", "--input-zero-point", "3"]);
+        assert!(take_quantization(
+            &mut zero_scale,
+            "--input-scale",
+            "--input-zero-point",
+            false
+        )
+        .is_err());
+    }
+}
@@ -48,13 +48,22 @@ fn set_metadata_to_context(
     context.set_input(1, TensorType::U8, &[1], &data)
 }
 
+// Preserve for 4096 embedding size and each embedding number is length 20,
+// and add 128 bytes for other information such as "n_embedding" of other symbols.
+const EMBEDDING_BUFFER_SIZE: usize = 4096 * 20;
+
 fn get_data_from_context(context: &GraphExecutionContext, index: usize) -> String {
-    // Preserve for 4096 embedding size and each embedding number is length 20,
-    // and add 128 bytes for other information such as "n_embedding" of other symbols.
-    const MAX_OUTPUT_BUFFER_SIZE: usize = 4096 * 20 + 128;
-    let mut output_buffer = vec![0u8; MAX_OUTPUT_BUFFER_SIZE];
+    get_data_from_context_with_size(context, index, EMBEDDING_BUFFER_SIZE + 128)
+}
+
+fn get_data_from_context_with_size(
+    context: &GraphExecutionContext,
+    index: usize,
+    max_output_buffer_size: usize,
+) -> String {
+    let mut output_buffer = vec![0u8; max_output_buffer_size];
     let mut output_size = context.get_output(index, &mut output_buffer).unwrap();
-    output_size = std::cmp::min(MAX_OUTPUT_BUFFER_SIZE, output_size);
+    output_size = std::cmp::min(max_output_buffer_size, output_size);
 
     String::from_utf8_lossy(&output_buffer[..output_size]).to_string()
 }
@@ -67,15 +76,243 @@ fn get_metadata_from_context(context: &GraphExecutionContext) -> Value {
     serde_json::from_str(&get_data_from_context(context, 1)).unwrap()
 }
 
-fn get_embd_from_context(context: &GraphExecutionContext) -> Value {
-    serde_json::from_str(&get_data_from_context(context, 0)).unwrap()
+// The number of input tokens in the metadata, which is needed to split the
+// output of `--pooling none` into the embeddings of the tokens.
+fn input_tokens(metadata: &Value) -> Result<usize, String> {
+    match metadata["input_tokens"].as_u64() {
+        Some(n_tokens) if n_tokens > 0 => Ok(n_tokens as usize),
+        _ => Err(format!(
+            "The metadata has no number of input tokens, which --pooling none needs: {}",
+            metadata
+        )),
+    }
+}
+
+// Read the raw output and the embedding, pooled as selected with `--pooling`.
+// Without pooling the output has an embedding per token, which takes a buffer
+// as large, and the pooling applied to it is returned as well.
+fn get_embd_from_context(
+    context: &GraphExecutionContext,
+    pooling: Option<Pooling>,
+) -> Result<(String, Value, Option<AppliedPooling>), String> {
+    if pooling != Some(Pooling::None) {
+        let output = get_output_from_context(context);
+        let embd = serde_json::from_str(&output).map_err(|e| e.to_string())?;
+        return Ok((output, embd, None));
+    }
+    let n_tokens = input_tokens(&get_metadata_from_context(context))?;
+    let output =
+        get_data_from_context_with_size(context, 0, n_tokens * EMBEDDING_BUFFER_SIZE + 128);
+    let embd = serde_json::from_str(&output).map_err(|e| e.to_string())?;
+    let (embd, applied) = mean_pool_embedding(embd, n_tokens)?;
+    Ok((output, embd, Some(applied)))
+}
+
+// Read the raw output and the embedding, or exit with the reason they can't be
+// read.
+fn read_embedding(
+    context: &GraphExecutionContext,
+    pooling: Option<Pooling>,
+) -> (String, Value, Option<AppliedPooling>) {
+    match get_embd_from_context(context, pooling) {
+        Ok(embedding) => embedding,
+        Err(message) => {
+            eprintln!("[ERROR] {}", message);
+            std::process::exit(1);
+        }
+    }
+}
+
+// How the embeddings of the tokens are pooled into one, set by `--pooling`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pooling {
+    Mean,
+    Cls,
+    Last,
+    None,
+}
+
+impl Pooling {
+    fn parse(value: &str) -> Option<Pooling> {
+        match value {
+            "mean" => Some(Pooling::Mean),
+            "cls" => Some(Pooling::Cls),
+            "last" => Some(Pooling::Last),
+            "none" => Some(Pooling::None),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Pooling::Mean => "mean",
+            Pooling::Cls => "cls",
+            Pooling::Last => "last",
+            Pooling::None => "none",
+        }
+    }
+}
+
+// Average the embeddings of the tokens, a row-major matrix with a row of
+// `n_embd` values per token. The mean of every column is one value of the
+// pooled embedding.
+fn mean_pool(values: &[f64], n_tokens: usize) -> Result<Vec<f64>, String> {
+    if n_tokens == 0 || values.is_empty() || !values.len().is_multiple_of(n_tokens) {
+        return Err(format!(
+            "The output has {} values, which are not the embeddings of {} tokens",
+            values.len(),
+            n_tokens
+        ));
+    }
+    let n_embd = values.len() / n_tokens;
+    let mut pooled = vec![0.0; n_embd];
+    for row in values.chunks_exact(n_embd) {
+        for (sum, value) in pooled.iter_mut().zip(row) {
+            *sum += value;
+        }
+    }
+    Ok(pooled
+        .into_iter()
+        .map(|sum| sum / n_tokens as f64)
+        .collect())
+}
+
+// The pooling applied to the output of `--pooling none`.
+#[derive(Debug, PartialEq)]
+enum AppliedPooling {
+    // The plugin pooled the embeddings anyway.
+    Backend,
+    // The mean of the embeddings of this many tokens, computed here.
+    ClientMean(usize),
+}
+
+impl AppliedPooling {
+    fn describe(&self) -> String {
+        match self {
+            AppliedPooling::Backend => String::from("the backend pooled the embeddings itself"),
+            AppliedPooling::ClientMean(n_tokens) => {
+                format!("mean of {} token embeddings in the client", n_tokens)
+            }
+        }
+    }
+}
+
+// Mean pool the embeddings of the tokens with `--pooling none`. A plugin
+// which pools anyway returns a single embedding, which is kept as it is.
+fn mean_pool_embedding(embd: Value, n_tokens: usize) -> Result<(Value, AppliedPooling), String> {
+    let values: Vec<f64> = embd["embedding"]
+        .as_array()
+        .ok_or_else(|| String::from("The output has no embedding"))?
+        .iter()
+        .map(|value| value.as_f64().unwrap_or(0.0))
+        .collect();
+    if embd["n_embedding"].as_u64() == Some(values.len() as u64) {
+        return Ok((embd, AppliedPooling::Backend));
+    }
+    let pooled = mean_pool(&values, n_tokens)?;
+    Ok((
+        json!({ "n_embedding": pooled.len(), "embedding": pooled }),
+        AppliedPooling::ClientMean(n_tokens),
+    ))
+}
+
+// The dimension of a computed embedding, which must match the `n_embedding`
+// the backend reports along with it.
+fn embedding_dim(embd: &Value) -> Result<usize, String> {
+    let dim = embd["embedding"]
+        .as_array()
+        .ok_or_else(|| String::from("The output has no embedding"))?
+        .len();
+    match embd["n_embedding"].as_u64() {
+        Some(n_embd) if n_embd as usize != dim => Err(format!(
+            "The embedding has {} values, but the backend reports n_embedding {}",
+            dim, n_embd
+        )),
+        _ => Ok(dim),
+    }
+}
+
+// Check the dimension of an embedding against the expected one, which is
+// taken from the first embedding unless it's given with `--expect-dim`.
+fn check_embedding_dim(dim: usize, expected: &mut Option<usize>) -> Result<(), String> {
+    match *expected {
+        Some(expected) if dim != expected => Err(format!(
+            "The embedding has {} dimensions, expected {}. The preloaded model is likely not the embedding model the vectors are meant for, vectors of different models can't be compared",
+            dim, expected
+        )),
+        Some(_) => Ok(()),
+        None => {
+            println!("[INFO] Embedding dimension: {}", dim);
+            *expected = Some(dim);
+            Ok(())
+        }
+    }
+}
+
+// Check the dimension of a computed embedding, exiting with the reason if it's
+// not the expected one.
+fn validate_embedding(embd: &Value, expected: &mut Option<usize>) {
+    if let Err(message) = embedding_dim(embd).and_then(|dim| check_embedding_dim(dim, expected)) {
+        eprintln!("[ERROR] {}", message);
+        std::process::exit(1);
+    }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // `--expect-dim <n>` asserts the dimension of the embeddings.
+    let mut expected_dim = match args.iter().position(|arg| arg == "--expect-dim") {
+        Some(pos) if pos + 1 < args.len() => {
+            let value = args.remove(pos + 1);
+            args.remove(pos);
+            match value.parse::<usize>() {
+                Ok(dim) if dim > 0 => Some(dim),
+                _ => {
+                    eprintln!(
+                        "invalid value '{}' for --expect-dim, expected a positive integer",
+                        value
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(_) => {
+            eprintln!("--expect-dim requires a value");
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    // `--pooling <mean|cls|last|none>` selects the pooling of the model.
+    let pooling = match args.iter().position(|arg| arg == "--pooling") {
+        Some(pos) if pos + 1 < args.len() => {
+            let value = args.remove(pos + 1);
+            args.remove(pos);
+            match Pooling::parse(&value) {
+                Some(pooling) => Some(pooling),
+                None => {
+                    eprintln!(
+                        "invalid value '{}' for --pooling, expected mean, cls, last or none",
+                        value
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(_) => {
+            eprintln!("--pooling requires a value");
+            std::process::exit(1);
+        }
+        None => None,
+    };
     let model_name: &str = &args[1];
     let mut options = get_options_from_env();
     options["embedding"] = serde_json::Value::Bool(true);
+    if let Some(pooling) = pooling {
+        options["pooling"] = json!(pooling.name());
+        if pooling != Pooling::None {
+            println!("[INFO] Pooling: {}", pooling.name());
+        }
+    }
 
     // Create graph and initialize context.
     let graph = GraphBuilder::new(GraphEncoding::Ggml, ExecutionTarget::AUTO)
@@ -102,10 +339,13 @@ fn main() {
             .unwrap();
         println!("Raw Embedding Output:");
         context.compute().unwrap();
-        let output = get_output_from_context(&context);
+        let (output, embd, applied) = read_embedding(&context, pooling);
         println!("{}", output.trim());
+        if let Some(applied) = applied {
+            println!("[INFO] Pooling: {}", applied.describe());
+        }
 
-        let embd = get_embd_from_context(&context);
+        validate_embedding(&embd, &mut expected_dim);
         println!("Interact with Embedding:");
         let n_embd = embd["n_embedding"].as_u64().unwrap();
         println!("N_Embd: {}", n_embd);
@@ -150,12 +390,14 @@ fn main() {
             }
         }
 
-        // Retrieve the output.
-        let output = get_output_from_context(&context);
-
+        // Retrieve the output. Without pooling, it's the embedding of every token.
+        let (output, embd, applied) = read_embedding(&context, pooling);
         println!("Raw Embedding Output: {}", output.trim());
+        if let Some(applied) = applied {
+            println!("[INFO] Pooling: {}", applied.describe());
+        }
 
-        let embd = get_embd_from_context(&context);
+        validate_embedding(&embd, &mut expected_dim);
         println!("Interact with Embedding:");
         let n_embd = embd["n_embedding"].as_u64().unwrap();
         println!("N_Embd: {}", n_embd);
@@ -178,3 +420,77 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_tokens_from_metadata() {
+        assert_eq!(input_tokens(&json!({ "input_tokens": 7 })), Ok(7));
+        assert!(input_tokens(&json!({ "input_tokens": 0 })).is_err());
+        assert!(input_tokens(&json!({ "output_tokens": 7 })).is_err());
+    }
+
+    #[test]
+    fn mean_pool_averages_the_columns() {
+        let values = [1.0, 2.0, 3.0, 3.0, 4.0, 5.0];
+        assert_eq!(mean_pool(&values, 2), Ok(vec![2.0, 3.0, 4.0]));
+        assert_eq!(mean_pool(&values, 1), Ok(values.to_vec()));
+    }
+
+    #[test]
+    fn mean_pool_rejects_a_ragged_output() {
+        assert!(mean_pool(&[1.0, 2.0, 3.0], 2).is_err());
+        assert!(mean_pool(&[1.0, 2.0], 0).is_err());
+        assert!(mean_pool(&[], 1).is_err());
+    }
+
+    #[test]
+    fn mean_pool_embedding_reports_the_pooling() {
+        let pooled = json!({ "n_embedding": 2, "embedding": [0.5, 1.5] });
+        assert_eq!(
+            mean_pool_embedding(pooled.clone(), 3),
+            Ok((pooled, AppliedPooling::Backend))
+        );
+        assert_eq!(
+            mean_pool_embedding(json!({ "embedding": [1.0, 2.0, 3.0, 4.0] }), 2),
+            Ok((
+                json!({ "n_embedding": 2, "embedding": [2.0, 3.0] }),
+                AppliedPooling::ClientMean(2)
+            ))
+        );
+        assert_eq!(
+            AppliedPooling::ClientMean(2).describe(),
+            "mean of 2 token embeddings in the client"
+        );
+    }
+
+    #[test]
+    fn embedding_dim_matches_n_embedding() {
+        assert_eq!(embedding_dim(&json!({ "embedding": [0.1, 0.2] })), Ok(2));
+        assert_eq!(
+            embedding_dim(&json!({ "n_embedding": 2, "embedding": [0.1, 0.2] })),
+            Ok(2)
+        );
+        assert!(embedding_dim(&json!({ "n_embedding": 3, "embedding": [0.1, 0.2] })).is_err());
+        assert!(embedding_dim(&json!({ "n_embedding": 3 })).is_err());
+    }
+
+    #[test]
+    fn expected_dim_is_taken_from_the_first_embedding() {
+        let mut expected = None;
+        assert_eq!(check_embedding_dim(384, &mut expected), Ok(()));
+        assert_eq!(expected, Some(384));
+        assert_eq!(check_embedding_dim(384, &mut expected), Ok(()));
+        assert!(check_embedding_dim(768, &mut expected).is_err());
+    }
+
+    #[test]
+    fn pooling_names_round_trip() {
+        for pooling in [Pooling::Mean, Pooling::Cls, Pooling::Last, Pooling::None] {
+            assert_eq!(Pooling::parse(pooling.name()), Some(pooling));
+        }
+        assert_eq!(Pooling::parse("max"), None);
+    }
+}
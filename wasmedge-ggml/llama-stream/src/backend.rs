@@ -0,0 +1,152 @@
+// Reading and writing the tensors of the execution context.
+use crate::error::ExampleError;
+use serde_json::Value;
+use wasmedge_wasi_nn::{BackendError, Error, GraphExecutionContext, TensorType};
+
+// The calls the example makes on the execution context. The tensors are all
+// bytes, set with the dimensions `[1]`.
+pub trait Backend {
+    fn set_input(&mut self, index: usize, data: &[u8]) -> Result<(), Error>;
+    fn compute(&mut self) -> Result<(), Error>;
+    fn compute_single(&mut self) -> Result<(), Error>;
+    fn fini_single(&mut self) -> Result<(), Error>;
+    fn get_output(&self, index: usize, buffer: &mut [u8]) -> Result<usize, Error>;
+    fn get_output_single(&self, index: usize, buffer: &mut [u8]) -> Result<usize, Error>;
+    // The size of the buffer the outputs are read into.
+    fn max_output_bytes(&self) -> usize;
+}
+
+// Preserve for 4096 tokens with average token length 6 by default.
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 4096 * 6;
+
+// The execution context of the model, with the size of the buffer its outputs
+// are read into, set by `--max-output-bytes`.
+pub struct Context {
+    pub inner: GraphExecutionContext,
+    pub max_output_bytes: usize,
+}
+
+impl Backend for Context {
+    fn set_input(&mut self, index: usize, data: &[u8]) -> Result<(), Error> {
+        self.inner.set_input(index, TensorType::U8, &[1], data)
+    }
+
+    fn compute(&mut self) -> Result<(), Error> {
+        self.inner.compute()
+    }
+
+    fn compute_single(&mut self) -> Result<(), Error> {
+        self.inner.compute_single()
+    }
+
+    fn fini_single(&mut self) -> Result<(), Error> {
+        self.inner.fini_single()
+    }
+
+    fn get_output(&self, index: usize, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.inner.get_output(index, buffer)
+    }
+
+    fn get_output_single(&self, index: usize, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.inner.get_output_single(index, buffer)
+    }
+
+    fn max_output_bytes(&self) -> usize {
+        self.max_output_bytes
+    }
+}
+
+pub fn set_data_to_context(context: &mut dyn Backend, data: Vec<u8>) -> Result<(), Error> {
+    context.set_input(0, &data)
+}
+
+pub fn set_metadata_to_context(context: &mut dyn Backend, data: Vec<u8>) -> Result<(), Error> {
+    context.set_input(1, &data)
+}
+
+fn try_get_bytes_from_context(
+    context: &dyn Backend,
+    index: usize,
+    is_single: bool,
+) -> Result<Vec<u8>, Error> {
+    let max_output_bytes = context.max_output_bytes();
+    let mut output_buffer = vec![0u8; max_output_bytes];
+    let mut output_size = if is_single {
+        context.get_output_single(index, &mut output_buffer)?
+    } else {
+        context.get_output(index, &mut output_buffer)?
+    };
+    // The backend reports the full size of the output even if it didn't fit.
+    if output_size > max_output_bytes {
+        eprintln!(
+            "[WARN] Output truncated: the backend returned {} bytes but the output buffer holds only {} bytes, increase --max-output-bytes",
+            output_size, max_output_bytes
+        );
+        output_size = max_output_bytes;
+    }
+    output_buffer.truncate(output_size);
+    Ok(output_buffer)
+}
+
+fn try_get_data_from_context(
+    context: &dyn Backend,
+    index: usize,
+    is_single: bool,
+) -> Result<String, Error> {
+    let bytes = try_get_bytes_from_context(context, index, is_single)?;
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
+
+pub fn get_output_from_context(context: &dyn Backend) -> Result<String, Error> {
+    try_get_data_from_context(context, 0, false)
+}
+
+// The bytes of the last single output. A token may end in the middle of a
+// multi-byte character, so they're decoded with a `Utf8Sink`.
+pub fn get_single_output_from_context(context: &dyn Backend) -> Result<Vec<u8>, Error> {
+    try_get_bytes_from_context(context, 0, true)
+}
+
+pub fn get_metadata_from_context(context: &dyn Backend) -> Result<Value, ExampleError> {
+    let metadata = try_get_data_from_context(context, 1, false).map_err(|err| {
+        ExampleError::backend(format!("Failed to get the metadata: {}", err), err)
+    })?;
+    serde_json::from_str(&metadata).map_err(|err| {
+        ExampleError::Backend(format!("Failed to parse the metadata: {}", err), None)
+    })
+}
+
+// Like `get_metadata_from_context`, but returns `None` when the backend can't
+// provide the metadata, e.g. on older plugins or before any input is set.
+pub fn try_get_metadata_from_context(context: &dyn Backend) -> Option<Value> {
+    let metadata = try_get_data_from_context(context, 1, false).ok()?;
+    serde_json::from_str(&metadata).ok()
+}
+
+// The metadata of the last single output, `None` if the backend has none.
+pub fn try_get_single_metadata_from_context(context: &dyn Backend) -> Option<Value> {
+    let metadata = try_get_data_from_context(context, 1, true).ok()?;
+    serde_json::from_str(&metadata).ok()
+}
+
+// How the answers are computed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComputeMode {
+    // Streaming hasn't produced a token yet. If the backend rejects the single
+    // token API on the first attempt, the session falls back to `Batch`.
+    Probe,
+    // Stream the tokens one by one with `compute_single`.
+    Stream,
+    // Call `compute` once and read the whole output with `get_output`.
+    Batch,
+}
+
+// Whether the error returned by the first `compute_single` call means that the
+// backend doesn't implement the single token API at all.
+pub fn is_streaming_unsupported(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::BackendError(BackendError::UnsupportedOperation)
+            | Error::BackendError(BackendError::InvalidArgument)
+    )
+}
@@ -0,0 +1,193 @@
+// What the installed GGML plugin supports, as far as its metadata tells.
+// Streaming, embeddings and grammars are derived from the llama.cpp build it
+// reports, the flags which need them are rejected on older builds. Plugins
+// which don't report a build are assumed to support everything, streaming is
+// still probed with the first answer then. Other options are ignored rather
+// than rejected by older plugins, so they aren't checked.
+use serde_json::Value;
+
+// The first llama.cpp builds shipped in a plugin with the feature.
+const STREAMING_MIN_BUILD_NUMBER: u64 = 1600;
+const EMBEDDINGS_MIN_BUILD_NUMBER: u64 = 1600;
+const GRAMMAR_MIN_BUILD_NUMBER: u64 = 1000;
+// The samplers of the sampler flags which older plugins don't have, by option.
+const SAMPLER_MIN_BUILD_NUMBERS: [(&str, u64); 2] =
+    [("xtc-probability", 3930), ("xtc-threshold", 3930)];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    // `None` for plugins which don't report it.
+    pub build_number: Option<u64>,
+    pub supports_streaming: bool,
+    pub supports_embeddings: bool,
+    pub supports_grammar: bool,
+    // The metadata has the `chat_template` of the model, for `--strict-template`.
+    pub reports_chat_template: bool,
+    // The metadata has the memory used by the model or the KV cache, for
+    // `--memory-stats`.
+    pub reports_memory_usage: bool,
+    // The metadata has the context size the model was trained with, for the
+    // ctx-size check.
+    pub reports_n_ctx_train: bool,
+}
+
+impl Capabilities {
+    // The capabilities of a plugin with the given metadata, which is `None`
+    // for plugins that report none at all.
+    pub fn from_metadata(metadata: Option<&Value>) -> Self {
+        let metadata = metadata.unwrap_or(&Value::Null);
+        let build_number = metadata["llama_build_number"].as_u64();
+        let since = |min: u64| build_number.is_none_or(|n| n >= min);
+        Capabilities {
+            build_number,
+            supports_streaming: since(STREAMING_MIN_BUILD_NUMBER),
+            supports_embeddings: since(EMBEDDINGS_MIN_BUILD_NUMBER),
+            supports_grammar: since(GRAMMAR_MIN_BUILD_NUMBER),
+            reports_chat_template: metadata["chat_template"].is_string(),
+            reports_memory_usage: metadata["model_bytes"].is_u64()
+                || metadata["kv_cache_bytes"].is_u64(),
+            reports_n_ctx_train: metadata["n_ctx_train"].is_u64(),
+        }
+    }
+
+    // A one-line summary, e.g. "llama.cpp b3400: streaming, embeddings,
+    // grammar, reports chat template, n_ctx_train".
+    pub fn describe(&self) -> String {
+        let features: Vec<&str> = [
+            (self.supports_streaming, "streaming"),
+            (self.supports_embeddings, "embeddings"),
+            (self.supports_grammar, "grammar"),
+        ]
+        .iter()
+        .filter(|(supported, _)| *supported)
+        .map(|(_, name)| *name)
+        .collect();
+        let reports: Vec<&str> = [
+            (self.reports_chat_template, "chat template"),
+            (self.reports_memory_usage, "memory usage"),
+            (self.reports_n_ctx_train, "n_ctx_train"),
+        ]
+        .iter()
+        .filter(|(reported, _)| *reported)
+        .map(|(_, name)| *name)
+        .collect();
+        let build = match self.build_number {
+            Some(n) => format!("llama.cpp b{}", n),
+            None => String::from("unknown llama.cpp build"),
+        };
+        let mut description = if features.is_empty() {
+            build
+        } else {
+            format!("{}: {}", build, features.join(", "))
+        };
+        if !reports.is_empty() {
+            description += &format!(", reports {}", reports.join(", "));
+        }
+        description
+    }
+
+    // Whether the plugin has the sampler set by the option, e.g. `xtc-threshold`.
+    pub fn supports_sampler(&self, key: &str) -> bool {
+        SAMPLER_MIN_BUILD_NUMBERS
+            .iter()
+            .filter(|(sampler, _)| *sampler == key)
+            .all(|(_, min)| self.build_number.is_none_or(|n| n >= *min))
+    }
+
+    // The message for a flag the plugin doesn't support.
+    pub fn unsupported(&self, feature: &str) -> String {
+        match self.build_number {
+            Some(n) => format!(
+                "{} is not supported by your plugin build (llama.cpp b{})",
+                feature, n
+            ),
+            None => format!("{} is not supported by your plugin build", feature),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn build(n: u64) -> Capabilities {
+        Capabilities::from_metadata(Some(&json!({ "llama_build_number": n })))
+    }
+
+    #[test]
+    fn capabilities_of_plugins_without_metadata() {
+        let capabilities = Capabilities::from_metadata(None);
+        assert_eq!(capabilities, Capabilities::from_metadata(Some(&json!({}))));
+        assert_eq!(capabilities.build_number, None);
+        assert!(capabilities.supports_streaming);
+        assert!(capabilities.supports_embeddings);
+        assert!(capabilities.supports_grammar);
+        assert_eq!(
+            capabilities.describe(),
+            "unknown llama.cpp build: streaming, embeddings, grammar"
+        );
+    }
+
+    #[test]
+    fn capabilities_of_build_numbers() {
+        let features = |c: Capabilities| {
+            (
+                c.supports_streaming,
+                c.supports_embeddings,
+                c.supports_grammar,
+            )
+        };
+        assert_eq!(features(build(900)), (false, false, false));
+        assert_eq!(features(build(1000)), (false, false, true));
+        assert_eq!(features(build(1599)), (false, false, true));
+        assert_eq!(features(build(1600)), (true, true, true));
+        assert_eq!(features(build(3400)), (true, true, true));
+        assert_eq!(build(900).describe(), "llama.cpp b900");
+    }
+
+    #[test]
+    fn capabilities_from_metadata() {
+        let metadata = json!({
+            "llama_build_number": 1200,
+            "chat_template": "{{ messages }}",
+            "kv_cache_bytes": 1024,
+            "n_ctx_train": "4096",
+        });
+        let capabilities = Capabilities::from_metadata(Some(&metadata));
+        assert_eq!(
+            capabilities,
+            Capabilities {
+                build_number: Some(1200),
+                supports_streaming: false,
+                supports_embeddings: false,
+                supports_grammar: true,
+                reports_chat_template: true,
+                reports_memory_usage: true,
+                // Not a number, so it isn't usable.
+                reports_n_ctx_train: false,
+            }
+        );
+        assert_eq!(
+            capabilities.describe(),
+            "llama.cpp b1200: grammar, reports chat template, memory usage"
+        );
+    }
+
+    #[test]
+    fn samplers_of_build_numbers() {
+        assert!(!build(3929).supports_sampler("xtc-probability"));
+        assert!(!build(3929).supports_sampler("xtc-threshold"));
+        assert!(build(3930).supports_sampler("xtc-threshold"));
+        assert!(build(1000).supports_sampler("min-p"));
+        assert!(Capabilities::from_metadata(None).supports_sampler("xtc-probability"));
+    }
+
+    #[test]
+    fn unsupported_names_the_build() {
+        assert_eq!(
+            build(900).unsupported("Streaming"),
+            "Streaming is not supported by your plugin build (llama.cpp b900)"
+        );
+    }
+}
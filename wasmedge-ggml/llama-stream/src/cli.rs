@@ -0,0 +1,1276 @@
+// Parsing of the command line flags and the options from the environment.
+use crate::backend::DEFAULT_MAX_OUTPUT_BYTES;
+use crate::error::ExampleError;
+use crate::generate::{GenerationConfig, RepetitionConfig};
+use crate::output::{redact_regex, BanAction, BanFilter};
+use crate::repl::{parse_logit_bias, parse_token_id, BAN_LOGIT_BIAS};
+use crate::sink::{FlushPolicy, FlushPolicySink, JsonEventSink, StdoutSink, TeeSink, TokenSink};
+use crate::template::{check_role_name, PromptTemplate, RoleNames};
+use crate::Log;
+use encoding_rs::Encoding;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+// The type of an option set by an environment variable.
+#[derive(Clone, Copy)]
+enum EnvOption {
+    Bool,
+    UnsignedInteger,
+}
+
+// Parse the value of an environment variable, e.g. `ctx_size=4096`.
+fn parse_env_option(name: &str, value: &str, kind: EnvOption) -> Result<Value, String> {
+    let parsed: Option<Value> = serde_json::from_str(value.trim()).ok();
+    match (kind, parsed) {
+        (EnvOption::Bool, Some(value @ Value::Bool(_))) => Ok(value),
+        (EnvOption::UnsignedInteger, Some(value)) if value.is_u64() => Ok(value),
+        (EnvOption::Bool, _) => Err(format!(
+            "invalid value '{}' for the {} environment variable, expected true or false",
+            value, name
+        )),
+        (EnvOption::UnsignedInteger, _) => Err(format!(
+            "invalid value '{}' for the {} environment variable, expected a non-negative integer",
+            value, name
+        )),
+    }
+}
+
+pub fn get_options_from_env() -> Result<Value, ExampleError> {
+    let mut options = json!({});
+    let variables = [
+        ("enable_log", "enable-log", EnvOption::Bool, json!(false)),
+        ("llama3", "llama3", EnvOption::Bool, json!(false)),
+        (
+            "ctx_size",
+            "ctx-size",
+            EnvOption::UnsignedInteger,
+            json!(1024),
+        ),
+        (
+            "n_gpu_layers",
+            "n-gpu-layers",
+            EnvOption::UnsignedInteger,
+            json!(0),
+        ),
+    ];
+    for (name, key, kind, default) in variables {
+        options[key] = match env::var(name) {
+            Ok(value) => parse_env_option(name, &value, kind).map_err(ExampleError::Usage)?,
+            Err(_) => default,
+        };
+    }
+    Ok(options)
+}
+
+// Sampler flags with the option they set, all of them taking a probability.
+// `min-p` and `typical-p` must be greater than 0, the XTC ones may be 0.
+pub const SAMPLER_FLAGS: [(&str, &str, bool); 4] = [
+    ("--min-p", "min-p", false),
+    ("--typical-p", "typical-p", false),
+    ("--xtc-probability", "xtc-probability", true),
+    ("--xtc-threshold", "xtc-threshold", true),
+];
+
+// The z parameter of tail free sampling has to be a positive number, 1.0
+// disables it.
+pub fn check_tfs_z(name: &str, value: f64) -> Result<f64, String> {
+    if value.is_finite() && value > 0.0 {
+        return Ok(value);
+    }
+    Err(format!(
+        "invalid value '{}' for {}, expected a positive number",
+        value, name
+    ))
+}
+
+pub fn check_non_negative(name: &str, value: f64) -> Result<f64, String> {
+    if value.is_finite() && value >= 0.0 {
+        return Ok(value);
+    }
+    Err(format!(
+        "invalid value '{}' for {}, expected a non-negative number",
+        value, name
+    ))
+}
+
+// Dynamic temperature varies the temperature by up to the range around `temp`,
+// which does nothing if `temp` is 0.
+pub fn dynatemp_without_temp(options: &Value) -> bool {
+    options["dynatemp-range"]
+        .as_f64()
+        .is_some_and(|range| range > 0.0)
+        && options["temp"].as_f64() == Some(0.0)
+}
+
+// With `ignore-eos` the backend never ends the answer by itself, so one of
+// the client-side bounds has to: the number of tokens to predict, a stop
+// sequence or the turn timeout.
+pub fn check_ignore_eos_bounds(
+    n_predict: Option<u64>,
+    has_stop_sequences: bool,
+    has_turn_timeout: bool,
+) -> Result<(), String> {
+    if n_predict.is_some_and(|n| n > 0) || has_stop_sequences || has_turn_timeout {
+        return Ok(());
+    }
+    Err(String::from(
+        "--ignore-eos never ends an answer by itself, it needs --n-predict, --eos-token or --turn-timeout-secs",
+    ))
+}
+
+// The seed `--deterministic` uses unless `--seed` is given.
+pub const DETERMINISTIC_SEED: u64 = 42;
+
+// The options of `--deterministic`: greedy sampling, a fixed seed and no
+// mirostat, so the same prompt gets the same answer with the same model build.
+pub fn apply_deterministic(options: &mut Value, seed: u64) {
+    options["temp"] = json!(0.0);
+    options["top-k"] = json!(1);
+    options["seed"] = json!(seed);
+    options["mirostat"] = json!(0);
+}
+
+// Parse the value of `--options-json`, which must be a JSON object of options.
+pub fn parse_options_json(value: &str) -> Result<serde_json::Map<String, Value>, String> {
+    match serde_json::from_str(value) {
+        Ok(Value::Object(options)) => Ok(options),
+        Ok(_) => Err(format!(
+            "invalid value '{}' for --options-json, expected a JSON object such as '{{\"temp\":0.2}}'",
+            value
+        )),
+        Err(err) => Err(format!(
+            "invalid value '{}' for --options-json: {}",
+            value, err
+        )),
+    }
+}
+
+pub fn check_probability(flag: &str, value: f64, allow_zero: bool) -> Result<f64, String> {
+    if value <= 1.0 && (value > 0.0 || (allow_zero && value == 0.0)) {
+        return Ok(value);
+    }
+    let range = if allow_zero { "[0, 1]" } else { "(0, 1]" };
+    Err(format!(
+        "invalid value '{}' for {}, expected a number in {}",
+        value, flag, range
+    ))
+}
+
+// Compare the requested context size with the context size the model was
+// trained with (`n_ctx_train`). A larger context silently degrades the quality
+// of the answers, so return a message describing the problem in that case.
+pub fn check_ctx_size(ctx_size: u64, n_ctx_train: Option<u64>) -> Option<String> {
+    match n_ctx_train {
+        Some(n_ctx_train) if ctx_size > n_ctx_train => Some(format!(
+            "ctx-size {} exceeds the context size the model was trained with ({}), consider enabling RoPE scaling or lowering ctx-size",
+            ctx_size, n_ctx_train
+        )),
+        _ => None,
+    }
+}
+
+// Remove a boolean flag such as `--no-stream` from the arguments and report
+// whether it was present, so that the positional arguments keep their indices.
+pub fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != len
+}
+
+// Remove an option with a value such as `--turn-timeout-secs 30` from the
+// arguments and return the value.
+pub fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let Some(pos) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    if pos + 1 >= args.len() {
+        return Err(format!("missing value for {}", name));
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Ok(Some(value))
+}
+
+// The types of the values of flags and REPL commands, with what is expected of
+// a value for the error message.
+pub trait FlagValue: FromStr {
+    const EXPECTED: &'static str;
+}
+
+impl FlagValue for u64 {
+    const EXPECTED: &'static str = "a non-negative integer";
+}
+
+impl FlagValue for usize {
+    const EXPECTED: &'static str = "a non-negative integer";
+}
+
+impl FlagValue for f64 {
+    const EXPECTED: &'static str = "a number";
+}
+
+// Parse the value of the flag or REPL command `name`, describing what was
+// expected if the value is invalid.
+pub fn parse_value<T: FlagValue>(name: &str, value: &str) -> Result<T, String> {
+    value.parse::<T>().map_err(|_| {
+        format!(
+            "invalid value '{}' for {}, expected {}",
+            value,
+            name,
+            T::EXPECTED
+        )
+    })
+}
+
+// Remove an option such as `--turn-timeout-secs 30` from the arguments and
+// parse its value.
+pub fn parse_flag<T: FlagValue>(args: &mut Vec<String>, name: &str) -> Result<Option<T>, String> {
+    take_option(args, name)?
+        .map(|value| parse_value(name, &value))
+        .transpose()
+}
+
+// Split a `--arg key=value` pair. The value may contain further `=`.
+pub fn parse_template_arg(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!(
+            "invalid value '{}' for --arg, expected key=value",
+            arg
+        )),
+    }
+}
+
+// Replace every `{key}` placeholder in the prompt with the value of its
+// `--arg`. The values are inserted as is, so they aren't searched for
+// placeholders again. A placeholder without a value is an error listing all
+// of them.
+pub fn interpolate(prompt: &str, values: &[(String, String)]) -> Result<String, String> {
+    let placeholder = Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    let mut missing = Vec::new();
+    let result = placeholder.replace_all(prompt, |caps: &regex::Captures| {
+        match values.iter().rev().find(|(key, _)| key == &caps[1]) {
+            Some((_, value)) => value.clone(),
+            None => {
+                if !missing.contains(&caps[0].to_string()) {
+                    missing.push(caps[0].to_string());
+                }
+                caps[0].to_string()
+            }
+        }
+    });
+    if !missing.is_empty() {
+        return Err(format!(
+            "the prompt has placeholders without an --arg: {}",
+            missing.join(", ")
+        ));
+    }
+    Ok(result.into_owned())
+}
+
+// Parse the value of `--choices`, the allowed answers separated by `|`.
+pub fn parse_choices(value: &str) -> Result<Vec<String>, String> {
+    let choices: Vec<String> = value.split('|').map(|c| c.trim().to_string()).collect();
+    if choices.len() < 2 || choices.iter().any(|c| c.is_empty()) {
+        return Err(format!(
+            "invalid value '{}' for --choices, expected at least two non-empty choices separated by '|'",
+            value
+        ));
+    }
+    Ok(choices)
+}
+
+// The encoding of `--input-encoding`, e.g. `utf-8` or `latin1`, by any of
+// the labels of the WHATWG Encoding Standard.
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| {
+        format!(
+            "unknown encoding '{}' for --input-encoding, e.g. utf-8, latin1 or shift_jis",
+            label
+        )
+    })
+}
+
+// Read a text file in the given encoding. Bytes which aren't valid in the
+// encoding are an error rather than silently replaced.
+fn read_text_file(path: &str, encoding: &'static Encoding, what: &str) -> Result<String, String> {
+    let bytes = std::fs::read(path)
+        .map_err(|err| format!("Failed to read the {} {}: {}", what, path, err))?;
+    encoding
+        .decode_without_bom_handling_and_without_replacement(&bytes)
+        .map(|content| content.into_owned())
+        .ok_or_else(|| {
+            format!(
+                "The {} {} is not valid {}, set its encoding with --input-encoding",
+                what,
+                path,
+                encoding.name()
+            )
+        })
+}
+
+// The prompts of a batch file, one per non-empty line.
+pub fn read_batch_file(path: &str, encoding: &'static Encoding) -> Result<Vec<String>, String> {
+    let content = read_text_file(path, encoding, "batch file")?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+// The prompt of an input file, the whole file as a single prompt.
+pub fn read_input_file(path: &str, encoding: &'static Encoding) -> Result<String, String> {
+    let content = read_text_file(path, encoding, "input file")?;
+    let prompt = content.trim();
+    if prompt.is_empty() {
+        return Err(format!("The input file {} is empty", path));
+    }
+    Ok(prompt.to_string())
+}
+
+// The instructions of a prompt prefix file, prepended to every user input.
+pub fn read_prompt_prefix_file(path: &str, encoding: &'static Encoding) -> Result<String, String> {
+    let content = read_text_file(path, encoding, "prompt prefix file")?;
+    let prefix = content.trim();
+    if prefix.is_empty() {
+        return Err(format!("The prompt prefix file {} is empty", path));
+    }
+    Ok(prefix.to_string())
+}
+
+// A model given with `--model <name>[:key=value,...]`, with the options it's
+// loaded with in addition to the others, e.g. `small:n-gpu-layers=20`.
+pub struct ModelCandidate {
+    pub name: String,
+    pub options: serde_json::Map<String, Value>,
+}
+
+impl ModelCandidate {
+    // The values are parsed as JSON like the environment options, and taken
+    // as strings otherwise.
+    pub fn parse(value: &str) -> Result<ModelCandidate, String> {
+        let invalid = || {
+            format!(
+                "invalid value '{}' for --model, expected <name>[:key=value,...]",
+                value
+            )
+        };
+        let (name, overrides) = value.split_once(':').unwrap_or((value, ""));
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        let mut options = serde_json::Map::new();
+        for pair in overrides.split(',').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or_else(invalid)?;
+            let value = serde_json::from_str(value).unwrap_or_else(|_| json!(value));
+            options.insert(key.to_string(), value);
+        }
+        Ok(ModelCandidate {
+            name: name.to_string(),
+            options,
+        })
+    }
+}
+
+// The command line flags, parsed and checked against each other. The
+// remaining arguments are the model name and the prompt, if any.
+pub struct Cli {
+    pub args: Vec<String>,
+    pub log: Log,
+    pub max_output_bytes: usize,
+    pub model_candidates: Vec<ModelCandidate>,
+    pub no_stream: bool,
+    pub dry_run: bool,
+    pub print_prompt: bool,
+    pub stdio_jsonrpc: bool,
+    pub final_metadata: bool,
+    pub strict_template: bool,
+    pub strict_ctx: bool,
+    pub stateless: bool,
+    pub allow_empty_turn: bool,
+    pub resume: bool,
+    pub no_autosave: bool,
+    pub cumulative_stats: bool,
+    pub show_prompt_tokens: bool,
+    pub truncate_long_prompts: bool,
+    pub max_session_duration: Option<Duration>,
+    pub history_window_tokens: Option<u64>,
+    pub max_prompt_chars: Option<usize>,
+    pub reserve_tokens: Option<u64>,
+    pub samplers: Vec<(&'static str, f64)>,
+    pub seed: Option<u64>,
+    pub n_predict: Option<u64>,
+    pub ignore_eos: bool,
+    pub logit_biases: Vec<(u64, f64)>,
+    pub n_keep: Option<u64>,
+    pub n_probs: Option<usize>,
+    pub deterministic: bool,
+    pub options_json: Option<serde_json::Map<String, Value>>,
+    pub config: GenerationConfig,
+    pub sink: Box<dyn TokenSink>,
+    pub persona_name: Option<String>,
+    pub lang: Option<String>,
+    pub roles: RoleNames,
+    pub system_prompt_override: Option<String>,
+    pub model_family: Option<PromptTemplate>,
+    pub prefill: Option<String>,
+    pub continue_answer: Option<String>,
+    pub first_message: Option<String>,
+    pub input_encoding: &'static Encoding,
+    pub input_file: Option<String>,
+    pub prompt_prefix: Option<String>,
+    pub raw: bool,
+    pub batch_file: Option<String>,
+    pub watch: Option<String>,
+    pub count_only: bool,
+    pub choices: Option<Vec<String>>,
+    pub best_of: u64,
+    pub judge: bool,
+    pub show_all: bool,
+}
+
+impl Cli {
+    pub fn parse(mut args: Vec<String>) -> Result<Cli, ExampleError> {
+        let mut model_candidates = Vec::new();
+        while let Some(model) = take_option(&mut args, "--model").map_err(ExampleError::Usage)? {
+            model_candidates.push(ModelCandidate::parse(&model).map_err(ExampleError::Usage)?);
+        }
+        // `--model` takes the place of the model name argument, so the prompt
+        // argument stays where it is.
+        if let Some(candidate) = model_candidates.first() {
+            args.insert(1.min(args.len()), candidate.name.clone());
+        }
+        let no_stream = take_flag(&mut args, "--no-stream");
+        let bare = take_flag(&mut args, "--bare");
+        let quiet = take_flag(&mut args, "--quiet");
+        let emit_json_events = take_flag(&mut args, "--emit-json-events");
+        let dry_run = take_flag(&mut args, "--dry-run");
+        let print_prompt = take_flag(&mut args, "--print-prompt");
+        let stdio_jsonrpc = take_flag(&mut args, "--stdio-jsonrpc");
+        let final_metadata = take_flag(&mut args, "--final-metadata");
+        let strict_template = take_flag(&mut args, "--strict-template");
+        let strict_ctx = take_flag(&mut args, "--strict-ctx");
+        let stateless = take_flag(&mut args, "--stateless");
+        let allow_empty_turn = take_flag(&mut args, "--allow-empty-turn");
+        let resume = take_flag(&mut args, "--resume");
+        let no_autosave = take_flag(&mut args, "--no-autosave");
+        let cumulative_stats = take_flag(&mut args, "--cumulative-stats");
+        let detect_repetition_loops = take_flag(&mut args, "--detect-repetition");
+        let show_prompt_tokens = take_flag(&mut args, "--show-prompt-tokens");
+        let truncate_long_prompts = take_flag(&mut args, "--truncate-long-prompts");
+        let turn_timeout = parse_flag::<u64>(&mut args, "--turn-timeout-secs")
+            .map_err(ExampleError::Usage)?
+            .map(Duration::from_secs);
+        let max_session_duration = parse_flag::<u64>(&mut args, "--max-session-duration")
+            .map_err(ExampleError::Usage)?
+            .map(Duration::from_secs);
+        let max_output_bytes = parse_flag::<usize>(&mut args, "--max-output-bytes")
+            .map_err(ExampleError::Usage)?
+            .unwrap_or(DEFAULT_MAX_OUTPUT_BYTES);
+        let history_window_tokens =
+            parse_flag::<u64>(&mut args, "--history-window-tokens").map_err(ExampleError::Usage)?;
+        let max_prompt_chars =
+            parse_flag::<usize>(&mut args, "--max-prompt-chars").map_err(ExampleError::Usage)?;
+        let reserve_tokens =
+            parse_flag::<u64>(&mut args, "--reserve-tokens").map_err(ExampleError::Usage)?;
+        let mut parse_count = |name: &str, default: usize| {
+            parse_flag::<usize>(&mut args, name)
+                .map(|count| count.unwrap_or(default))
+                .map_err(ExampleError::Usage)
+        };
+        let repetition = RepetitionConfig {
+            window: parse_count("--repetition-window", 512)?,
+            min_len: parse_count("--repetition-min-len", 16)?,
+            max_repeats: parse_count("--repetition-max-repeats", 3)?,
+        };
+        let mut samplers = Vec::new();
+        for (flag, key, allow_zero) in SAMPLER_FLAGS {
+            if let Some(value) = parse_flag::<f64>(&mut args, flag).map_err(ExampleError::Usage)? {
+                let value =
+                    check_probability(flag, value, allow_zero).map_err(ExampleError::Usage)?;
+                samplers.push((key, value));
+            }
+        }
+        if let Some(value) = parse_flag(&mut args, "--tfs-z").map_err(ExampleError::Usage)? {
+            samplers.push((
+                "tfs-z",
+                check_tfs_z("--tfs-z", value).map_err(ExampleError::Usage)?,
+            ));
+        }
+        for flag in ["--dynatemp-range", "--dynatemp-exponent"] {
+            if let Some(value) = parse_flag(&mut args, flag).map_err(ExampleError::Usage)? {
+                let value = check_non_negative(flag, value).map_err(ExampleError::Usage)?;
+                samplers.push((&flag[2..], value));
+            }
+        }
+        let seed = parse_flag::<u64>(&mut args, "--seed").map_err(ExampleError::Usage)?;
+        let n_predict = parse_flag::<u64>(&mut args, "--n-predict").map_err(ExampleError::Usage)?;
+        let ignore_eos = take_flag(&mut args, "--ignore-eos");
+        let mut logit_biases = Vec::new();
+        while let Some(value) =
+            take_option(&mut args, "--logit-bias").map_err(ExampleError::Usage)?
+        {
+            logit_biases
+                .push(parse_logit_bias("--logit-bias", &value).map_err(ExampleError::Usage)?);
+        }
+        while let Some(id) =
+            take_option(&mut args, "--ban-token-id").map_err(ExampleError::Usage)?
+        {
+            let id = parse_token_id("--ban-token-id", &id).map_err(ExampleError::Usage)?;
+            logit_biases.push((id, BAN_LOGIT_BIAS));
+        }
+        let n_keep = parse_flag::<u64>(&mut args, "--n-keep").map_err(ExampleError::Usage)?;
+        let n_probs = parse_flag::<usize>(&mut args, "--n-probs")
+            .map_err(ExampleError::Usage)?
+            .filter(|&n_probs| n_probs > 0);
+        // The alternatives are shown in the text stream only when asked for.
+        let verbose = take_flag(&mut args, "--verbose");
+        if n_probs.is_some() && !verbose && !emit_json_events {
+            return Err(ExampleError::Usage(String::from(
+                "--n-probs shows the alternatives of every token with --verbose, or in the token events of --emit-json-events",
+            )));
+        }
+        let deterministic = take_flag(&mut args, "--deterministic");
+        let options_json = take_option(&mut args, "--options-json")
+            .map_err(ExampleError::Usage)?
+            .map(|value| parse_options_json(&value))
+            .transpose()
+            .map_err(ExampleError::Usage)?;
+        let mut ban_words = Vec::new();
+        while let Some(word) = take_option(&mut args, "--ban-word").map_err(ExampleError::Usage)? {
+            ban_words.push(word);
+        }
+        let mut ban_patterns = Vec::new();
+        while let Some(pattern) =
+            take_option(&mut args, "--ban-pattern").map_err(ExampleError::Usage)?
+        {
+            ban_patterns.push(pattern);
+        }
+        let mut redact_patterns = Vec::new();
+        while let Some(pattern) = take_option(&mut args, "--redact").map_err(ExampleError::Usage)? {
+            redact_patterns.push(pattern);
+        }
+        let ban_action = match take_option(&mut args, "--ban-action")
+            .map_err(ExampleError::Usage)?
+            .as_deref()
+        {
+            None | Some("mask") => BanAction::Mask,
+            Some("stop") => BanAction::Stop,
+            Some(value) => {
+                return Err(ExampleError::Usage(format!(
+                    "invalid value '{}' for --ban-action, expected mask or stop",
+                    value
+                )))
+            }
+        };
+        let mut stop_sequences = Vec::new();
+        while let Some(token) =
+            take_option(&mut args, "--eos-token").map_err(ExampleError::Usage)?
+        {
+            if token.is_empty() {
+                return Err(ExampleError::Usage(String::from(
+                    "--eos-token can't be empty",
+                )));
+            }
+            stop_sequences.push(token);
+        }
+        let ban_case_sensitive = take_flag(&mut args, "--ban-case-sensitive");
+        let ban_filter = if ban_words.is_empty() && ban_patterns.is_empty() {
+            None
+        } else {
+            Some(
+                BanFilter::new(&ban_words, &ban_patterns, ban_action, ban_case_sensitive)
+                    .map_err(ExampleError::Usage)?,
+            )
+        };
+        let flush_policy = take_option(&mut args, "--flush")
+            .map_err(ExampleError::Usage)?
+            .map(|value| FlushPolicy::parse(&value).map_err(ExampleError::Usage))
+            .transpose()?;
+        let flush_every =
+            parse_flag::<usize>(&mut args, "--flush-every").map_err(ExampleError::Usage)?;
+        let flush_interval = parse_flag::<u64>(&mut args, "--flush-every-ms")
+            .map_err(ExampleError::Usage)?
+            .map(Duration::from_millis);
+        if flush_policy.is_some() && (flush_every.is_some() || flush_interval.is_some()) {
+            return Err(ExampleError::Usage(String::from(
+                "--flush can't be combined with --flush-every or --flush-every-ms",
+            )));
+        }
+        let flush_every = flush_every.unwrap_or(1);
+        if flush_every == 0 {
+            return Err(ExampleError::Usage(String::from(
+                "--flush-every must be at least 1",
+            )));
+        }
+        let mut config = GenerationConfig {
+            turn_timeout,
+            repetition: detect_repetition_loops.then_some(repetition),
+            ban_filter,
+            stop_sequences,
+            primer: "",
+            answer_prefix: take_option(&mut args, "--answer-prefix")
+                .map_err(ExampleError::Usage)?
+                .unwrap_or_default(),
+            answer_suffix: take_option(&mut args, "--answer-suffix")
+                .map_err(ExampleError::Usage)?
+                .unwrap_or_default(),
+            logprobs: take_flag(&mut args, "--logprobs"),
+            flush_every,
+            flush_interval,
+            strip_markdown: take_flag(&mut args, "--strip-markdown"),
+            output_hash: deterministic,
+            tail: parse_flag::<usize>(&mut args, "--tail").map_err(ExampleError::Usage)?,
+            token_timings: take_flag(&mut args, "--token-timings"),
+            redact: redact_regex(&redact_patterns).map_err(ExampleError::Usage)?,
+            memory_stats: take_flag(&mut args, "--memory-stats"),
+            n_probs,
+            extract_json: take_flag(&mut args, "--extract-json"),
+            context_shift: take_flag(&mut args, "--context-shift"),
+            n_predict: None,
+            log: Log::default(),
+        };
+        if n_keep.is_some() && !config.context_shift {
+            return Err(ExampleError::Usage(String::from(
+                "--n-keep needs --context-shift, it's the number of tokens kept when the context is shifted",
+            )));
+        }
+        if config.tail == Some(0) {
+            return Err(ExampleError::Usage(String::from(
+                "--tail needs at least one line",
+            )));
+        }
+        if config.tail.is_some() && !no_stream {
+            return Err(ExampleError::Usage(String::from(
+                "--tail needs --no-stream, a streamed answer is printed before it's complete",
+            )));
+        }
+        if config.extract_json && !no_stream {
+            return Err(ExampleError::Usage(String::from(
+                "--extract-json needs --no-stream, a streamed answer is printed before it's complete",
+            )));
+        }
+        if config.extract_json && (config.strip_markdown || config.tail.is_some()) {
+            return Err(ExampleError::Usage(String::from(
+                "--extract-json can't be combined with --strip-markdown or --tail",
+            )));
+        }
+        if config.redact.is_some() && !no_stream {
+            return Err(ExampleError::Usage(String::from(
+                "--redact needs --no-stream, a match may span several streamed tokens",
+            )));
+        }
+        if config.strip_markdown && !no_stream {
+            return Err(ExampleError::Usage(String::from(
+                "--strip-markdown needs --no-stream, a streamed answer is printed before it's complete",
+            )));
+        }
+        let tee = take_option(&mut args, "--tee").map_err(ExampleError::Usage)?;
+        let mut sink: Box<dyn TokenSink> = match tee {
+            Some(_) if emit_json_events => {
+                return Err(ExampleError::Usage(String::from(
+                    "--tee can't be combined with --emit-json-events",
+                )))
+            }
+            None if emit_json_events => Box::new(JsonEventSink::new()),
+            Some(path) => Box::new(TeeSink::open(&path).map_err(ExampleError::Usage)?),
+            None => Box::new(StdoutSink),
+        };
+        // Flushing after every token is what the sinks do anyway.
+        if let Some(policy) = flush_policy.filter(|&policy| policy != FlushPolicy::Token) {
+            sink = Box::new(FlushPolicySink::new(sink, policy));
+        }
+        let persona_name = take_option(&mut args, "--persona").map_err(ExampleError::Usage)?;
+        let lang = take_option(&mut args, "--lang").map_err(ExampleError::Usage)?;
+        let roles = RoleNames {
+            user: take_option(&mut args, "--user-name")
+                .map_err(ExampleError::Usage)?
+                .map(|name| check_role_name("--user-name", name))
+                .transpose()
+                .map_err(ExampleError::Usage)?,
+            assistant: take_option(&mut args, "--assistant-name")
+                .map_err(ExampleError::Usage)?
+                .map(|name| check_role_name("--assistant-name", name))
+                .transpose()
+                .map_err(ExampleError::Usage)?,
+        };
+        let system_prompt_override =
+            take_option(&mut args, "--system-prompt").map_err(ExampleError::Usage)?;
+        let model_family = take_option(&mut args, "--model-family")
+            .map_err(ExampleError::Usage)?
+            .map(|name| {
+                PromptTemplate::from_name(&name).ok_or_else(|| {
+                    ExampleError::Usage(format!(
+                        "unknown model family '{}', expected one of {}",
+                        name,
+                        PromptTemplate::names()
+                    ))
+                })
+            })
+            .transpose()?;
+        let prefill = take_option(&mut args, "--prefill").map_err(ExampleError::Usage)?;
+        let continue_answer = take_option(&mut args, "--continue").map_err(ExampleError::Usage)?;
+        if continue_answer
+            .as_ref()
+            .is_some_and(|answer| answer.trim().is_empty())
+        {
+            return Err(ExampleError::Usage(String::from(
+                "There is no answer to continue, --continue is empty",
+            )));
+        }
+        let first_message = take_option(&mut args, "--first").map_err(ExampleError::Usage)?;
+        if first_message.is_some() && args.len() >= 3 {
+            return Err(ExampleError::Usage(String::from(
+                "--first can't be combined with a prompt argument, which runs a single turn without the interactive loop",
+            )));
+        }
+        // The prompt of `--input-file` takes the place of the prompt argument.
+        let input_encoding = take_option(&mut args, "--input-encoding")
+            .map_err(ExampleError::Usage)?
+            .map(|label| parse_encoding(&label))
+            .transpose()
+            .map_err(ExampleError::Usage)?
+            .unwrap_or(encoding_rs::UTF_8);
+        let input_file = take_option(&mut args, "--input-file").map_err(ExampleError::Usage)?;
+        if let Some(path) = &input_file {
+            if args.len() >= 3 || first_message.is_some() {
+                return Err(ExampleError::Usage(String::from(
+                    "--input-file can't be combined with a prompt argument or --first",
+                )));
+            }
+            let prompt = read_input_file(path, input_encoding).map_err(ExampleError::Usage)?;
+            if args.len() == 2 {
+                args.push(prompt);
+            }
+        }
+        let mut template_args = Vec::new();
+        while let Some(arg) = take_option(&mut args, "--arg").map_err(ExampleError::Usage)? {
+            template_args.push(parse_template_arg(&arg).map_err(ExampleError::Usage)?);
+        }
+        if !template_args.is_empty() {
+            if args.len() < 3 {
+                return Err(ExampleError::Usage(String::from(
+                    "--arg needs a prompt argument or --input-file with {key} placeholders",
+                )));
+            }
+            args[2] = interpolate(&args[2], &template_args).map_err(ExampleError::Usage)?;
+        }
+        let prompt_prefix = take_option(&mut args, "--prompt-prefix-file")
+            .map_err(ExampleError::Usage)?
+            .map(|path| read_prompt_prefix_file(&path, input_encoding))
+            .transpose()
+            .map_err(ExampleError::Usage)?;
+        let raw = take_flag(&mut args, "--raw");
+        let raw_continue = take_flag(&mut args, "--raw-continue");
+        if raw_continue && !raw {
+            return Err(ExampleError::Usage(String::from(
+                "--raw-continue needs --raw",
+            )));
+        }
+        if raw && (persona_name.is_some() || model_family.is_some() || prompt_prefix.is_some()) {
+            return Err(ExampleError::Usage(String::from(
+                "--raw sends the input verbatim and can't be combined with --persona, --model-family or --prompt-prefix-file",
+            )));
+        }
+        // Without `--raw-continue`, nothing is carried over between raw turns.
+        let stateless = stateless || (raw && !raw_continue);
+        if resume && stateless {
+            return Err(ExampleError::Usage(String::from(
+                "--resume needs a conversation to continue, it can't be combined with --stateless or --raw without --raw-continue",
+            )));
+        }
+        let batch_file = take_option(&mut args, "--batch-file").map_err(ExampleError::Usage)?;
+        if batch_file.is_some() && (args.len() >= 3 || first_message.is_some()) {
+            return Err(ExampleError::Usage(String::from(
+                "--batch-file can't be combined with a prompt argument or --first",
+            )));
+        }
+        let watch = take_option(&mut args, "--watch").map_err(ExampleError::Usage)?;
+        if watch.is_some() && (args.len() >= 3 || first_message.is_some() || batch_file.is_some()) {
+            return Err(ExampleError::Usage(String::from(
+                "--watch can't be combined with a prompt argument, --input-file, --first or --batch-file",
+            )));
+        }
+        let count_only = take_flag(&mut args, "--count-only");
+        if count_only && batch_file.is_none() {
+            return Err(ExampleError::Usage(String::from(
+                "--count-only needs --batch-file",
+            )));
+        }
+        let choices = take_option(&mut args, "--choices")
+            .map_err(ExampleError::Usage)?
+            .map(|value| parse_choices(&value))
+            .transpose()
+            .map_err(ExampleError::Usage)?;
+        if choices.is_some() && args.len() < 3 && batch_file.is_none() {
+            return Err(ExampleError::Usage(String::from(
+                "--choices needs a prompt argument or --batch-file",
+            )));
+        }
+        if count_only && choices.is_some() {
+            return Err(ExampleError::Usage(String::from(
+                "--count-only can't be combined with --choices, whose summary already counts the answers",
+            )));
+        }
+        let best_of = parse_flag::<u64>(&mut args, "--best-of")
+            .map_err(ExampleError::Usage)?
+            .unwrap_or(1);
+        let judge = take_flag(&mut args, "--judge");
+        let show_all = take_flag(&mut args, "--show-all");
+        if continue_answer.is_some() && (args.len() < 3 || prefill.is_some() || best_of > 1) {
+            return Err(ExampleError::Usage(String::from(
+                "--continue needs a prompt argument and can't be combined with --prefill or --best-of",
+            )));
+        }
+        if best_of == 0 {
+            return Err(ExampleError::Usage(String::from(
+                "--best-of must be at least 1",
+            )));
+        }
+        if best_of > 1 && deterministic {
+            return Err(ExampleError::Usage(String::from(
+                "--best-of can't be combined with --deterministic, which makes all the candidates the same",
+            )));
+        }
+        if best_of > 1 && (args.len() < 3 || choices.is_some()) {
+            return Err(ExampleError::Usage(String::from(
+                "--best-of needs a prompt argument and can't be combined with --choices",
+            )));
+        }
+        // Only the interactive mode keeps its labels and notices on stdout by default.
+        let log = Log {
+            quiet: quiet
+                || emit_json_events
+                || stdio_jsonrpc
+                || args.len() >= 3
+                || batch_file.is_some(),
+            bare,
+        };
+        config.log = log;
+        if args.len() < 2 {
+            return Err(ExampleError::Usage(format!(
+                "usage: {} <model_name> [prompt]",
+                args[0]
+            )));
+        }
+        Ok(Cli {
+            args,
+            log,
+            max_output_bytes,
+            model_candidates,
+            no_stream,
+            dry_run,
+            print_prompt,
+            stdio_jsonrpc,
+            final_metadata,
+            strict_template,
+            strict_ctx,
+            stateless,
+            allow_empty_turn,
+            resume,
+            no_autosave,
+            cumulative_stats,
+            show_prompt_tokens,
+            truncate_long_prompts,
+            max_session_duration,
+            history_window_tokens,
+            max_prompt_chars,
+            reserve_tokens,
+            samplers,
+            seed,
+            n_predict,
+            ignore_eos,
+            logit_biases,
+            n_keep,
+            n_probs,
+            deterministic,
+            options_json,
+            config,
+            sink,
+            persona_name,
+            lang,
+            roles,
+            system_prompt_override,
+            model_family,
+            prefill,
+            continue_answer,
+            first_message,
+            input_encoding,
+            input_file,
+            prompt_prefix,
+            raw,
+            batch_file,
+            watch,
+            count_only,
+            choices,
+            best_of,
+            judge,
+            show_all,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        let args = ["llama-stream.wasm", "default"]
+            .iter()
+            .chain(args)
+            .map(|arg| arg.to_string())
+            .collect();
+        Cli::parse(args).unwrap()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn options_are_removed_with_their_value() {
+        let mut list = args(&["example", "--seed", "42", "default", "--no-stream"]);
+        assert_eq!(parse_flag::<u64>(&mut list, "--seed"), Ok(Some(42)));
+        assert!(take_flag(&mut list, "--no-stream"));
+        assert!(!take_flag(&mut list, "--no-stream"));
+        assert_eq!(list, args(&["example", "default"]));
+        assert_eq!(parse_flag::<u64>(&mut list, "--seed"), Ok(None));
+    }
+
+    #[test]
+    fn missing_flag_value() {
+        assert_eq!(
+            take_option(&mut args(&["example", "--seed"]), "--seed"),
+            Err(String::from("missing value for --seed"))
+        );
+        assert_eq!(
+            parse_flag::<f64>(&mut args(&["example", "--tfs-z"]), "--tfs-z"),
+            Err(String::from("missing value for --tfs-z"))
+        );
+    }
+
+    #[test]
+    fn invalid_flag_values_say_what_was_expected() {
+        assert_eq!(
+            parse_flag::<u64>(&mut args(&["--seed", "-1"]), "--seed"),
+            Err(String::from(
+                "invalid value '-1' for --seed, expected a non-negative integer"
+            ))
+        );
+        assert_eq!(
+            parse_flag::<usize>(&mut args(&["--tail", "-5"]), "--tail"),
+            Err(String::from(
+                "invalid value '-5' for --tail, expected a non-negative integer"
+            ))
+        );
+        assert_eq!(
+            parse_flag::<f64>(&mut args(&["--min-p", "low"]), "--min-p"),
+            Err(String::from(
+                "invalid value 'low' for --min-p, expected a number"
+            ))
+        );
+        assert_eq!(
+            parse_flag::<f64>(&mut args(&["--dynatemp-range", "-0.5"]), "--dynatemp-range"),
+            Ok(Some(-0.5))
+        );
+    }
+
+    #[test]
+    fn tfs_z_and_dynatemp_flags_are_checked() {
+        let cli = parse(&["--tfs-z", "0.95", "--dynatemp-range", "0.5"]);
+        assert!(cli.samplers.contains(&("tfs-z", 0.95)));
+        assert!(cli.samplers.contains(&("dynatemp-range", 0.5)));
+        let error = |flags: &[&str]| {
+            let args = ["llama-stream.wasm", "default"]
+                .iter()
+                .chain(flags)
+                .map(|arg| arg.to_string())
+                .collect();
+            Cli::parse(args).err().unwrap().to_string()
+        };
+        assert_eq!(
+            error(&["--tfs-z", "0"]),
+            "invalid value '0' for --tfs-z, expected a positive number"
+        );
+        assert_eq!(
+            error(&["--dynatemp-exponent", "x"]),
+            "invalid value 'x' for --dynatemp-exponent, expected a number"
+        );
+        assert_eq!(
+            error(&["--dynatemp-range", "-1"]),
+            "invalid value '-1' for --dynatemp-range, expected a non-negative number"
+        );
+    }
+
+    #[test]
+    fn probability_ranges() {
+        assert_eq!(check_probability("--min-p", 0.05, false), Ok(0.05));
+        assert_eq!(check_probability("--min-p", 1.0, false), Ok(1.0));
+        assert_eq!(
+            check_probability("--min-p", 0.0, false),
+            Err(String::from(
+                "invalid value '0' for --min-p, expected a number in (0, 1]"
+            ))
+        );
+        assert_eq!(check_probability("--xtc-threshold", 0.0, true), Ok(0.0));
+        assert_eq!(
+            check_probability("--xtc-threshold", 1.5, true),
+            Err(String::from(
+                "invalid value '1.5' for --xtc-threshold, expected a number in [0, 1]"
+            ))
+        );
+        assert!(check_probability("--typical-p", -0.1, true).is_err());
+        assert!(check_probability("--typical-p", f64::NAN, true).is_err());
+    }
+
+    #[test]
+    fn sampler_flags_set_their_options() {
+        let cli = parse(&["--xtc-probability", "0.5", "--typical-p", "0.9"]);
+        assert_eq!(
+            cli.samplers,
+            vec![("typical-p", 0.9), ("xtc-probability", 0.5)]
+        );
+    }
+
+    #[test]
+    fn output_flags_are_cli_fields() {
+        let cli = parse(&[]);
+        assert_eq!(cli.log, Log::default());
+        assert_eq!(cli.max_output_bytes, DEFAULT_MAX_OUTPUT_BYTES);
+        let cli = parse(&["--bare", "--quiet", "--max-output-bytes", "64"]);
+        assert_eq!(
+            cli.log,
+            Log {
+                quiet: true,
+                bare: true
+            }
+        );
+        assert_eq!(cli.config.log, cli.log);
+        assert_eq!(cli.max_output_bytes, 64);
+    }
+
+    #[test]
+    fn n_probs_needs_verbose_or_json_events() {
+        let error = Cli::parse(args(&["llama-stream.wasm", "default", "--n-probs", "2"]))
+            .err()
+            .unwrap();
+        assert!(matches!(error, ExampleError::Usage(_)));
+        assert_eq!(parse(&["--n-probs", "2", "--verbose"]).n_probs, Some(2));
+        assert_eq!(
+            parse(&["--n-probs", "2", "--emit-json-events"]).n_probs,
+            Some(2)
+        );
+        assert_eq!(parse(&["--n-probs", "0"]).n_probs, None);
+    }
+
+    #[test]
+    fn one_shot_prompt_implies_quiet() {
+        let cli = parse(&["Why is the sky blue?"]);
+        assert!(cli.log.quiet);
+        assert!(!cli.log.bare);
+    }
+
+    #[test]
+    fn ctx_size_within_the_trained_context() {
+        assert_eq!(check_ctx_size(4096, Some(4096)), None);
+        assert_eq!(check_ctx_size(2048, Some(4096)), None);
+        // Older plugins don't report `n_ctx_train`.
+        assert_eq!(check_ctx_size(1 << 20, None), None);
+    }
+
+    #[test]
+    fn ctx_size_beyond_the_trained_context() {
+        let message = check_ctx_size(8192, Some(4096)).unwrap();
+        assert!(message.starts_with("ctx-size 8192 exceeds"));
+        assert!(message.contains("(4096)"));
+    }
+
+    #[test]
+    fn input_file_is_one_prompt() {
+        let path = env::temp_dir().join(format!("llama-stream-input-{}.txt", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        std::fs::write(&path, "\n  First line.\n\nSecond line.\n\n").unwrap();
+        assert_eq!(
+            read_input_file(path_str, encoding_rs::UTF_8).unwrap(),
+            "First line.\n\nSecond line."
+        );
+        std::fs::write(&path, " \n\n").unwrap();
+        assert_eq!(
+            read_input_file(path_str, encoding_rs::UTF_8).unwrap_err(),
+            format!("The input file {} is empty", path_str)
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(read_input_file(path_str, encoding_rs::UTF_8)
+            .unwrap_err()
+            .starts_with("Failed to read the input file"));
+    }
+
+    #[test]
+    fn eos_tokens_are_stop_sequences() {
+        let cli = parse(&["--eos-token", "<|end|>", "--eos-token", "\nUSER:"]);
+        assert_eq!(cli.config.stop_sequences, ["<|end|>", "\nUSER:"]);
+        let error = Cli::parse(args(&["llama-stream.wasm", "default", "--eos-token", ""]))
+            .err()
+            .unwrap();
+        assert!(
+            matches!(error, ExampleError::Usage(message) if message == "--eos-token can't be empty")
+        );
+        assert_eq!(check_ignore_eos_bounds(None, true, false), Ok(()));
+        assert!(check_ignore_eos_bounds(Some(0), false, false).is_err());
+    }
+
+    #[test]
+    fn deterministic_options() {
+        let mut options = json!({"temp": 0.8, "top-k": 40, "mirostat": 2, "n-predict": 64});
+        apply_deterministic(&mut options, DETERMINISTIC_SEED);
+        assert_eq!(
+            options,
+            json!({"temp": 0.0, "top-k": 1, "seed": 42, "mirostat": 0, "n-predict": 64})
+        );
+        let cli = parse(&["--deterministic"]);
+        assert!(cli.deterministic);
+        assert!(cli.config.output_hash);
+    }
+
+    #[test]
+    fn options_json_must_be_an_object() {
+        let overrides =
+            parse_options_json(r#"{"temp": 0.2, "grammar": "root ::= \"a\""}"#).unwrap();
+        let mut options = json!({"temp": 0.8, "n-predict": 64});
+        crate::conversation::merge_options(&mut options, &overrides);
+        assert_eq!(
+            options,
+            json!({"temp": 0.2, "n-predict": 64, "grammar": "root ::= \"a\""})
+        );
+        assert!(parse_options_json("[1]")
+            .unwrap_err()
+            .contains("expected a JSON object"));
+        assert!(parse_options_json("{temp}")
+            .unwrap_err()
+            .starts_with("invalid value '{temp}' for --options-json: "));
+    }
+
+    #[test]
+    fn interpolate_replaces_placeholders() {
+        let values = vec![
+            (String::from("name"), String::from("{city}")),
+            (String::from("city"), String::from("Paris")),
+            (String::from("city"), String::from("Rome")),
+        ];
+        // The last value wins and values aren't interpolated again.
+        assert_eq!(
+            interpolate("{name} lives in {city}, {city}.", &values).unwrap(),
+            "{city} lives in Rome, Rome."
+        );
+        assert_eq!(
+            interpolate("{ not a placeholder }", &[]).unwrap(),
+            "{ not a placeholder }"
+        );
+    }
+
+    #[test]
+    fn interpolate_lists_missing_placeholders_once() {
+        assert_eq!(
+            interpolate("{a} {b} {a}", &[]),
+            Err(String::from(
+                "the prompt has placeholders without an --arg: {a}, {b}"
+            ))
+        );
+    }
+
+    #[test]
+    fn model_candidates() {
+        let candidate =
+            ModelCandidate::parse("small:n-gpu-layers=20,embedding=true,model-alias=x").unwrap();
+        assert_eq!(candidate.name, "small");
+        assert_eq!(
+            Value::Object(candidate.options),
+            json!({"n-gpu-layers": 20, "embedding": true, "model-alias": "x"})
+        );
+        assert!(ModelCandidate::parse("default").unwrap().options.is_empty());
+        for invalid in [":n-gpu-layers=20", "small:n-gpu-layers"] {
+            assert_eq!(
+                ModelCandidate::parse(invalid).err(),
+                Some(format!(
+                    "invalid value '{}' for --model, expected <name>[:key=value,...]",
+                    invalid
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn latin1_batch_file() {
+        let path = env::temp_dir().join(format!("llama-stream-latin1-{}.txt", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        // "Café?\n\nÀ bientôt\n" in Latin-1.
+        std::fs::write(&path, b"Caf\xe9?\n\n\xc0 bient\xf4t\n").unwrap();
+        let latin1 = parse_encoding("latin1").unwrap();
+        assert_eq!(
+            read_batch_file(path_str, latin1).unwrap(),
+            ["Café?", "À bientôt"]
+        );
+        assert_eq!(
+            read_batch_file(path_str, encoding_rs::UTF_8).unwrap_err(),
+            format!(
+                "The batch file {} is not valid UTF-8, set its encoding with --input-encoding",
+                path_str
+            )
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(parse_encoding("klingon").is_err());
+    }
+
+    #[test]
+    fn logit_bias_flags() {
+        let cli = parse(&["--logit-bias", "15043:-1.5", "--ban-token-id", "2"]);
+        assert_eq!(cli.logit_biases, [(15043, -1.5), (2, BAN_LOGIT_BIAS)]);
+        for (flag, value, error) in [
+            (
+                "--logit-bias",
+                "hello:1",
+                "invalid token id 'hello' for --logit-bias",
+            ),
+            (
+                "--logit-bias",
+                "15043",
+                "invalid value '15043' for --logit-bias",
+            ),
+            (
+                "--logit-bias",
+                "15043:inf",
+                "invalid bias 'inf' for --logit-bias",
+            ),
+            (
+                "--ban-token-id",
+                "-1",
+                "invalid token id '-1' for --ban-token-id",
+            ),
+        ] {
+            let err = Cli::parse(args(&["llama-stream.wasm", "default", flag, value]))
+                .err()
+                .unwrap();
+            assert!(
+                matches!(&err, ExampleError::Usage(message) if message.starts_with(error)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn n_keep_needs_context_shift() {
+        let cli = parse(&["--context-shift", "--n-keep", "64"]);
+        assert!(cli.config.context_shift);
+        assert_eq!(cli.n_keep, Some(64));
+        let error = Cli::parse(args(&["llama-stream.wasm", "default", "--n-keep", "64"]))
+            .err()
+            .unwrap();
+        assert!(matches!(error, ExampleError::Usage(_)));
+    }
+}
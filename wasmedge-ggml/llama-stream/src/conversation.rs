@@ -0,0 +1,568 @@
+// The conversation history, the personas and the languages of the system
+// prompt, and the estimates of the prompt size.
+use crate::template::{PromptTemplate, RoleNames};
+use crate::Log;
+use serde_json::Value;
+
+// A question and its answer in the conversation history.
+#[derive(Clone)]
+pub struct Turn {
+    pub user: String,
+    pub assistant: String,
+    // The number of tokens the turn takes up in the prompt, from the output
+    // tokens reported by the backend where known, estimated otherwise.
+    pub tokens: u64,
+}
+
+// The conversation so far. The rendered history is cached, so every turn only
+// appends the markup of the new turns instead of re-formatting everything.
+pub struct Conversation {
+    pub template: PromptTemplate,
+    pub roles: RoleNames,
+    pub system_prompt: String,
+    // Few-shot examples the history starts with, kept when it's cleared.
+    pub examples: Vec<Turn>,
+    pub history: Vec<Turn>,
+    pub log: Log,
+    // The rendering of the first `cached_turns` turns of the history.
+    cached_prefix: String,
+    cached_turns: usize,
+}
+
+impl Conversation {
+    pub fn new(
+        template: PromptTemplate,
+        roles: RoleNames,
+        system_prompt: String,
+        examples: Vec<Turn>,
+        log: Log,
+    ) -> Self {
+        Conversation {
+            template,
+            roles,
+            system_prompt,
+            history: examples.clone(),
+            examples,
+            log,
+            cached_prefix: String::new(),
+            cached_turns: 0,
+        }
+    }
+
+    // Render the prompt for the next question. The result is identical to
+    // `PromptTemplate::format` over the whole history, with the roles renamed.
+    pub fn prompt(&mut self, input: &str) -> String {
+        if self.cached_turns > self.history.len() {
+            self.invalidate_cache();
+        }
+        for (i, turn) in self.history.iter().enumerate().skip(self.cached_turns) {
+            self.cached_prefix +=
+                &self
+                    .template
+                    .format_question(&self.system_prompt, &turn.user, i == 0);
+            self.cached_prefix += &self.template.format_answer(&turn.assistant);
+        }
+        self.cached_turns = self.history.len();
+        let prompt = self.cached_prefix.clone()
+            + &self
+                .template
+                .format_question(&self.system_prompt, input, self.history.is_empty());
+        debug_assert_eq!(
+            prompt,
+            self.template
+                .format(&self.system_prompt, &self.history, input)
+        );
+        let injected = self.system_prompt_count(&prompt, input);
+        if injected > 1 {
+            warn!(
+                self.log,
+                "The system prompt appears {} times in the prompt instead of once, the prompt template is broken.",
+                injected
+            );
+        }
+        self.template.rename_roles(prompt, &self.roles)
+    }
+
+    // How often the template put the system prompt into the prompt, which
+    // has to be at most once. Occurrences quoted in the turns themselves
+    // aren't counted.
+    fn system_prompt_count(&self, prompt: &str, input: &str) -> usize {
+        let system_prompt = self.system_prompt.trim();
+        if system_prompt.is_empty() {
+            return 0;
+        }
+        let quoted: usize = self
+            .history
+            .iter()
+            .flat_map(|turn| [turn.user.as_str(), turn.assistant.as_str()])
+            .chain([input])
+            .map(|text| text.matches(system_prompt).count())
+            .sum();
+        prompt.matches(system_prompt).count().saturating_sub(quoted)
+    }
+
+    // Take back the last turn, e.g. to continue its answer. The few-shot
+    // examples can't be taken back.
+    pub fn pop(&mut self) -> Option<Turn> {
+        if self.history.len() <= self.examples.len() {
+            return None;
+        }
+        self.history.pop()
+    }
+
+    // Add a turn, with the number of tokens of the answer reported by the
+    // backend, or 0 if it's unknown.
+    pub fn push(&mut self, user: String, assistant: String, answer_tokens: u64) {
+        let answer_tokens = match answer_tokens {
+            0 => estimate_tokens(&assistant),
+            tokens => tokens,
+        };
+        let tokens = estimate_tokens(&self.template.format_question(
+            &self.system_prompt,
+            &user,
+            false,
+        )) + answer_tokens;
+        self.history.push(Turn {
+            user,
+            assistant,
+            tokens,
+        });
+    }
+
+    // Drop the oldest turns until the prompt for a question of
+    // `question_tokens` fits into `budget` tokens. The system prompt and the
+    // few-shot examples are always kept. Returns the number of dropped turns.
+    pub fn trim_to_tokens(&mut self, budget: u64, question_tokens: u64) -> usize {
+        let mut total = estimate_tokens(&self.system_prompt)
+            + question_tokens
+            + self.history.iter().map(|turn| turn.tokens).sum::<u64>();
+        let mut dropped = 0;
+        while total > budget && self.history.len() > self.examples.len() {
+            total -= self.history.remove(self.examples.len()).tokens;
+            dropped += 1;
+        }
+        if dropped > 0 {
+            self.invalidate_cache();
+        }
+        dropped
+    }
+
+    // Drop the oldest turn after the few-shot examples, if any.
+    pub fn drop_oldest(&mut self) -> bool {
+        if self.history.len() <= self.examples.len() {
+            return false;
+        }
+        self.history.remove(self.examples.len());
+        self.invalidate_cache();
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.history = self.examples.clone();
+        self.invalidate_cache();
+    }
+
+    // The turns of the conversation itself, after the few-shot examples.
+    pub fn turns(&self) -> &[Turn] {
+        &self.history[self.examples.len()..]
+    }
+
+    // Continue a conversation saved earlier, e.g. with `--resume`.
+    pub fn restore(&mut self, turns: Vec<Turn>) {
+        self.history.extend(turns);
+        self.invalidate_cache();
+    }
+
+    // Must be called whenever the history is changed other than by `push`, or
+    // the template or the system prompt change.
+    fn invalidate_cache(&mut self) {
+        self.cached_prefix.clear();
+        self.cached_turns = 0;
+    }
+}
+
+// Appended to an answer kept in the history when its turn timed out.
+pub const TIMED_OUT_MARKER: &str = " [timed out]";
+
+pub const SYSTEM_PROMPT: &str = "You are a helpful, respectful and honest assistant. Always answer as short as possible, while being safe.";
+
+// The built-in system prompt and the interactive labels in a language
+// selected with `--lang`, so that the model answers in that language.
+pub struct Language {
+    pub code: &'static str,
+    pub system_prompt: &'static str,
+    pub user_label: &'static str,
+    pub assistant_label: &'static str,
+}
+
+const LANGUAGES: [Language; 5] = [
+    Language {
+        code: "en",
+        system_prompt: SYSTEM_PROMPT,
+        user_label: "USER:",
+        assistant_label: "ASSISTANT:",
+    },
+    Language {
+        code: "zh",
+        system_prompt: "你是一个乐于助人、尊重他人且诚实的助手。请在确保安全的前提下，始终用中文尽可能简短地回答。",
+        user_label: "用户：",
+        assistant_label: "助手：",
+    },
+    Language {
+        code: "ja",
+        system_prompt: "あなたは親切で、礼儀正しく、誠実なアシスタントです。安全に配慮しながら、常に日本語でできるだけ簡潔に答えてください。",
+        user_label: "ユーザー：",
+        assistant_label: "アシスタント：",
+    },
+    Language {
+        code: "es",
+        system_prompt: "Eres un asistente útil, respetuoso y honesto. Responde siempre en español, de la forma más breve posible y de manera segura.",
+        user_label: "USUARIO:",
+        assistant_label: "ASISTENTE:",
+    },
+    Language {
+        code: "de",
+        system_prompt: "Du bist ein hilfsbereiter, respektvoller und ehrlicher Assistent. Antworte immer auf Deutsch, so kurz wie möglich und auf sichere Weise.",
+        user_label: "BENUTZER:",
+        assistant_label: "ASSISTENT:",
+    },
+];
+
+// The language with the given code, English for an unknown one.
+pub fn language(code: &str, log: Log) -> &'static Language {
+    LANGUAGES
+        .iter()
+        .find(|language| language.code == code)
+        .unwrap_or_else(|| {
+            warn!(
+                log,
+                "Unknown language '{}', expected one of {}, falling back to en.",
+                code,
+                LANGUAGES
+                    .iter()
+                    .map(|language| language.code)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            &LANGUAGES[0]
+        })
+}
+
+// The directory the persona profiles are loaded from, one `<name>.json` each.
+pub const PERSONAS_DIR: &str = "personas";
+
+// A persona profile, replacing the default system prompt and optionally the
+// prompt template, sampling options and few-shot examples.
+pub struct Persona {
+    pub system_prompt: String,
+    pub template: Option<PromptTemplate>,
+    pub options: serde_json::Map<String, Value>,
+    pub examples: Vec<Turn>,
+}
+
+impl Persona {
+    // Load and validate `<PERSONAS_DIR>/<name>.json`. Errors name the file and
+    // the malformed field.
+    pub fn load(name: &str) -> Result<Persona, String> {
+        let path = format!("{}/{}.json", PERSONAS_DIR, name);
+        let content = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Failed to read the persona {}: {}", path, err))?;
+        let value: Value = serde_json::from_str(&content)
+            .map_err(|err| format!("Failed to parse the persona {}: {}", path, err))?;
+        let invalid = |field: &str, expected: &str| {
+            format!(
+                "Invalid persona {}: field '{}' must be {}",
+                path, field, expected
+            )
+        };
+        let object = value
+            .as_object()
+            .ok_or_else(|| format!("Invalid persona {}: expected a JSON object", path))?;
+        if let Some(field) = object.keys().find(|key| {
+            !matches!(
+                key.as_str(),
+                "system_prompt" | "template" | "options" | "examples"
+            )
+        }) {
+            return Err(format!(
+                "Invalid persona {}: unknown field '{}'",
+                path, field
+            ));
+        }
+
+        let system_prompt = object
+            .get("system_prompt")
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid("system_prompt", "a string"))?
+            .to_string();
+        let template = match object.get("template") {
+            None => None,
+            Some(value) => match value.as_str().and_then(PromptTemplate::from_name) {
+                Some(template) => Some(template),
+                None => {
+                    return Err(invalid(
+                        "template",
+                        &format!("one of {}", PromptTemplate::names()),
+                    ))
+                }
+            },
+        };
+        let options = match object.get("options") {
+            None => serde_json::Map::new(),
+            Some(Value::Object(options)) => options.clone(),
+            Some(_) => return Err(invalid("options", "an object")),
+        };
+        for (key, value) in &options {
+            let (valid, expected) = match key.as_str() {
+                "temp" | "top-p" | "min-p" | "typical-p" | "tfs-z" | "dynatemp-range"
+                | "dynatemp-exponent" | "repeat-penalty" | "presence-penalty"
+                | "frequency-penalty" => (value.is_number(), "a number"),
+                "top-k" | "n-predict" => (value.is_u64(), "a non-negative integer"),
+                _ => (
+                    value.is_number() || value.is_string() || value.is_boolean(),
+                    "a number, a string or a boolean",
+                ),
+            };
+            if !valid {
+                return Err(invalid(&format!("options.{}", key), expected));
+            }
+        }
+        let examples = match object.get("examples") {
+            None => Vec::new(),
+            Some(Value::Array(examples)) => examples
+                .iter()
+                .enumerate()
+                .map(|(i, example)| {
+                    let field = |name: &str| {
+                        example[name].as_str().map(String::from).ok_or_else(|| {
+                            invalid(&format!("examples[{}].{}", i, name), "a string")
+                        })
+                    };
+                    let user = field("user")?;
+                    let assistant = field("assistant")?;
+                    Ok(Turn {
+                        tokens: estimate_tokens(&user) + estimate_tokens(&assistant),
+                        user,
+                        assistant,
+                    })
+                })
+                .collect::<Result<_, String>>()?,
+            Some(_) => return Err(invalid("examples", "an array")),
+        };
+
+        Ok(Persona {
+            system_prompt,
+            template,
+            options,
+            examples,
+        })
+    }
+
+    // The names of the personas in `PERSONAS_DIR`, sorted.
+    pub fn list() -> Result<Vec<String>, String> {
+        let entries = std::fs::read_dir(PERSONAS_DIR).map_err(|err| {
+            format!(
+                "Failed to read the personas directory {}: {}",
+                PERSONAS_DIR, err
+            )
+        })?;
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| Some(path.file_stem()?.to_string_lossy().into_owned()))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn apply_options(&self, options: &mut Value) {
+        merge_options(options, &self.options);
+    }
+}
+
+// Set the given options, replacing the values they had.
+pub fn merge_options(options: &mut Value, overrides: &serde_json::Map<String, Value>) {
+    for (key, value) in overrides {
+        options[key.as_str()] = value.clone();
+    }
+}
+
+// A rough number of bytes per token, used to estimate the size of a prompt
+// without asking the backend to tokenize it.
+pub const BYTES_PER_TOKEN: usize = 3;
+
+pub fn estimate_tokens(text: &str) -> u64 {
+    text.len().div_ceil(BYTES_PER_TOKEN) as u64
+}
+
+// The number of tokens a prompt may have, leaving `reserve` tokens of the
+// context for the answer.
+pub fn prompt_token_limit(ctx_size: u64, reserve: u64) -> u64 {
+    ctx_size.saturating_sub(reserve)
+}
+
+// Return the last `max_bytes` bytes of `text`, or a little less so that the
+// result starts on a character boundary.
+pub fn keep_tail(text: &str, max_bytes: usize) -> &str {
+    let mut start = text.len().saturating_sub(max_bytes);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    &text[start..]
+}
+
+// Return the last `max_chars` characters of `text`.
+pub fn keep_tail_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().rev().nth(max_chars.saturating_sub(1)) {
+        Some((start, _)) if max_chars > 0 => &text[start..],
+        Some(_) => "",
+        None => text,
+    }
+}
+
+// The number of characters the prompt exceeds the `--max-prompt-chars` limit
+// by, if there is a limit and it's exceeded.
+pub fn prompt_chars_excess(prompt: &str, max_chars: Option<usize>) -> Option<usize> {
+    let chars = prompt.chars().count();
+    max_chars
+        .filter(|&max_chars| chars > max_chars)
+        .map(|max_chars| chars - max_chars)
+}
+
+// Prepend the prompt prefix, if any, to the user input, separated by a blank
+// line. This happens before templating, so the prefix is part of the user turn.
+pub fn apply_prompt_prefix(prefix: Option<&str>, input: &str) -> String {
+    match prefix {
+        Some(prefix) => format!("{}\n\n{}", prefix, input),
+        None => input.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn(user: &str, tokens: u64) -> Turn {
+        Turn {
+            user: user.to_string(),
+            assistant: format!("answer to {}", user),
+            tokens,
+        }
+    }
+
+    // A conversation with one few-shot example and three turns of 100 tokens
+    // each, with a system prompt of 10 estimated tokens.
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new(
+            PromptTemplate::ChatMl,
+            RoleNames::default(),
+            "x".repeat(10 * BYTES_PER_TOKEN),
+            vec![turn("example", 50)],
+            Log::default(),
+        );
+        conversation.restore(vec![turn("one", 100), turn("two", 100), turn("three", 100)]);
+        conversation
+    }
+
+    fn users(conversation: &Conversation) -> Vec<&str> {
+        conversation
+            .history
+            .iter()
+            .map(|turn| turn.user.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest_stops_at_the_examples() {
+        let mut conversation = conversation();
+        assert!(conversation.drop_oldest());
+        assert!(conversation.drop_oldest());
+        assert_eq!(users(&conversation), ["example", "three"]);
+        assert!(conversation.drop_oldest());
+        assert!(!conversation.drop_oldest());
+        assert_eq!(users(&conversation), ["example"]);
+    }
+
+    #[test]
+    fn estimate_rounds_up() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("a"), 1);
+        assert_eq!(estimate_tokens(&"a".repeat(BYTES_PER_TOKEN + 1)), 2);
+    }
+
+    #[test]
+    fn prompt_chars_excess_counts_characters() {
+        assert_eq!(prompt_chars_excess("héllo", None), None);
+        assert_eq!(prompt_chars_excess("héllo", Some(5)), None);
+        assert_eq!(prompt_chars_excess("héllo", Some(3)), Some(2));
+    }
+
+    #[test]
+    fn keep_tail_chars_keeps_whole_characters() {
+        assert_eq!(keep_tail_chars("añadir", 4), "adir");
+        assert_eq!(keep_tail_chars("añadir", 5), "ñadir");
+        assert_eq!(keep_tail_chars("añadir", 10), "añadir");
+        assert_eq!(keep_tail_chars("añadir", 0), "");
+        assert_eq!(keep_tail("añadir", 5), "adir");
+    }
+
+    #[test]
+    fn trim_to_tokens_drops_the_oldest_turns() {
+        let mut conversation = conversation();
+        // 10 for the system prompt, 5 for the question, 50 for the example
+        // and 300 for the turns.
+        assert_eq!(conversation.trim_to_tokens(365, 5), 0);
+        assert_eq!(conversation.trim_to_tokens(300, 5), 1);
+        assert_eq!(users(&conversation), ["example", "two", "three"]);
+        assert_eq!(conversation.trim_to_tokens(10, 5), 2);
+        assert_eq!(users(&conversation), ["example"]);
+    }
+
+    #[test]
+    fn trimmed_prompt_matches_the_template() {
+        let mut conversation = conversation();
+        conversation.prompt("four");
+        conversation.trim_to_tokens(200, 5);
+        assert_eq!(
+            conversation.prompt("four"),
+            conversation.template.format(
+                &conversation.system_prompt,
+                &conversation.history,
+                "four"
+            )
+        );
+    }
+
+    #[test]
+    fn prompt_prefix_goes_before_the_input() {
+        assert_eq!(
+            apply_prompt_prefix(Some("Answer in French."), "Why?"),
+            "Answer in French.\n\nWhy?"
+        );
+        assert_eq!(apply_prompt_prefix(None, "Why?"), "Why?");
+    }
+
+    #[test]
+    fn system_prompt_count_ignores_quotes() {
+        let mut conversation = Conversation::new(
+            PromptTemplate::ChatMl,
+            RoleNames::default(),
+            String::from("Be brief."),
+            Vec::new(),
+            Log::default(),
+        );
+        conversation.restore(vec![Turn {
+            user: String::from("Why did you say \"Be brief.\"?"),
+            assistant: String::from("I was told to."),
+            tokens: 10,
+        }]);
+        let prompt = conversation.prompt("Be brief.");
+        assert_eq!(conversation.system_prompt_count(&prompt, "Be brief."), 1);
+        // A broken template which repeats the system prompt every turn.
+        let broken = format!("Be brief.{}", prompt);
+        assert_eq!(conversation.system_prompt_count(&broken, "Be brief."), 2);
+        conversation.system_prompt.clear();
+        assert_eq!(conversation.system_prompt_count(&broken, ""), 0);
+    }
+}
@@ -0,0 +1,333 @@
+// The errors of the example, their exit codes, and the recovery from
+// repeated backend errors.
+use crate::backend::Context;
+use crate::Log;
+use serde_json::{json, Value};
+use std::io;
+use std::process::ExitCode;
+use wasmedge_wasi_nn::{BackendError, Error, Graph};
+
+// Rebuild the execution context after this many failed turns in a row, as it
+// may be left unusable by some errors.
+const REBUILD_AFTER_ERRORS: u32 = 3;
+// Give up once the rebuilt contexts failed this many times in a row.
+const MAX_REBUILDS: u32 = 3;
+
+// What to do about the execution context after a turn.
+#[derive(Debug, PartialEq)]
+pub enum Recovery {
+    None,
+    Rebuild,
+    GiveUp,
+}
+
+// Counts the consecutive failed turns to decide when to rebuild the context.
+#[derive(Default)]
+pub struct ErrorTracker {
+    consecutive_errors: u32,
+    // Rebuilds since the last successful turn.
+    rebuilds: u32,
+}
+
+impl ErrorTracker {
+    pub fn record(&mut self, failed: bool) -> Recovery {
+        if !failed {
+            *self = ErrorTracker::default();
+            return Recovery::None;
+        }
+        self.consecutive_errors += 1;
+        if self.consecutive_errors < REBUILD_AFTER_ERRORS {
+            return Recovery::None;
+        }
+        if self.rebuilds >= MAX_REBUILDS {
+            return Recovery::GiveUp;
+        }
+        self.consecutive_errors = 0;
+        self.rebuilds += 1;
+        Recovery::Rebuild
+    }
+}
+
+// Act on the `Recovery` of a turn: rebuild the execution context from the
+// loaded model, keeping the conversation, or give up. The options have to be
+// set again on a rebuilt context.
+pub fn recover(
+    recovery: Recovery,
+    graph: &Graph,
+    context: &mut Context,
+    options_changed: &mut bool,
+    log: Log,
+) -> Result<(), ExampleError> {
+    match recovery {
+        Recovery::None => (),
+        Recovery::Rebuild => match graph.init_execution_context() {
+            Ok(new_context) => {
+                context.inner = new_context;
+                *options_changed = true;
+                info!(log, "reinitialized context after errors");
+            }
+            Err(err) => status!(log, "[ERROR] Failed to reinitialize the context: {}", err),
+        },
+        Recovery::GiveUp => {
+            return Err(ExampleError::Backend(
+                format!(
+                    "The context keeps failing after {} rebuilds, giving up.",
+                    MAX_REBUILDS
+                ),
+                None,
+            ))
+        }
+    }
+    Ok(())
+}
+
+// Failure classes reported by the example. Each maps to a stable exit code so
+// that scripts around the one-shot mode can tell them apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    Usage,
+    ModelLoad,
+    PromptTooLong,
+    Backend,
+    // Reading the input failed, e.g. stdin.
+    Io,
+}
+
+impl ErrorKind {
+    fn from_backend_error(err: &Error) -> Self {
+        match err {
+            Error::BackendError(BackendError::PromptTooLong) => ErrorKind::PromptTooLong,
+            _ => ErrorKind::Backend,
+        }
+    }
+
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::Usage => 2,
+            ErrorKind::ModelLoad => 3,
+            ErrorKind::PromptTooLong => 4,
+            ErrorKind::Backend => 5,
+            ErrorKind::Io => 8,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Usage => "usage",
+            ErrorKind::ModelLoad => "model_load",
+            ErrorKind::PromptTooLong => "prompt_too_long",
+            ErrorKind::Backend => "backend",
+            ErrorKind::Io => "io",
+        }
+    }
+}
+
+// The errors that end the example. Each of them belongs to an `ErrorKind`,
+// which decides the exit code.
+#[derive(Debug)]
+pub enum ExampleError {
+    // Invalid command line arguments or input files.
+    Usage(String),
+    // Options that don't fit the model, with the values at odds.
+    Config(String, Value),
+    Io(io::Error),
+    // The model couldn't be loaded or set up.
+    ModelLoad(String, Option<Error>),
+    // The prompt doesn't fit, with its size and the limit.
+    PromptTooLong(String, Value),
+    Backend(String, Option<Error>),
+}
+
+impl ExampleError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ExampleError::Usage(_) | ExampleError::Config(..) => ErrorKind::Usage,
+            ExampleError::Io(_) => ErrorKind::Io,
+            ExampleError::ModelLoad(..) => ErrorKind::ModelLoad,
+            ExampleError::PromptTooLong(..) => ErrorKind::PromptTooLong,
+            ExampleError::Backend(..) => ErrorKind::Backend,
+        }
+    }
+
+    // A failed generation, which may be a prompt that turned out too long.
+    pub fn backend(message: String, err: Error) -> Self {
+        if let Error::IoError(err) = err {
+            return ExampleError::Io(err);
+        }
+        match ErrorKind::from_backend_error(&err) {
+            ErrorKind::PromptTooLong => {
+                ExampleError::PromptTooLong(message, json!(err.to_string()))
+            }
+            _ => ExampleError::Backend(message, Some(err)),
+        }
+    }
+
+    // The `detail` of the error reported with `--json-errors`.
+    pub fn detail(&self) -> Value {
+        match self {
+            ExampleError::Config(_, detail) | ExampleError::PromptTooLong(_, detail) => {
+                detail.clone()
+            }
+            ExampleError::ModelLoad(_, Some(err)) | ExampleError::Backend(_, Some(err)) => {
+                json!(err.to_string())
+            }
+            _ => Value::Null,
+        }
+    }
+}
+
+impl std::fmt::Display for ExampleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExampleError::Usage(message)
+            | ExampleError::Config(message, _)
+            | ExampleError::ModelLoad(message, _)
+            | ExampleError::PromptTooLong(message, _)
+            | ExampleError::Backend(message, _) => write!(f, "{}", message),
+            ExampleError::Io(err) => {
+                write!(f, "Failed to read the input or write the output: {}", err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExampleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExampleError::Io(err) => Some(err),
+            ExampleError::ModelLoad(_, Some(err)) | ExampleError::Backend(_, Some(err)) => {
+                Some(err)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ExampleError {
+    fn from(err: io::Error) -> Self {
+        ExampleError::Io(err)
+    }
+}
+
+// Print the error, either as free-form text or, with `--json-errors`, as a
+// single JSON object on stderr, and return the exit code of its kind.
+pub fn report_error(kind: ErrorKind, message: &str, detail: Value, json_errors: bool) -> ExitCode {
+    if json_errors {
+        eprintln!(
+            "{}",
+            json!({
+                "error": {
+                    "kind": kind.name(),
+                    "message": message,
+                    "detail": detail,
+                }
+            })
+        );
+    } else {
+        eprintln!("[ERROR] {}", message);
+    }
+    ExitCode::from(kind.exit_code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_errors_by_kind() {
+        let error = |err| ExampleError::backend(String::from("failed"), err);
+        assert_eq!(
+            error(Error::BackendError(BackendError::PromptTooLong)).kind(),
+            ErrorKind::PromptTooLong
+        );
+        assert_eq!(
+            error(Error::BackendError(BackendError::ContextFull)).kind(),
+            ErrorKind::Backend
+        );
+        assert_eq!(
+            error(Error::IoError(io::Error::other("closed"))).kind(),
+            ErrorKind::Io
+        );
+        assert_eq!(
+            ExampleError::Config(String::from("bad"), json!({})).kind(),
+            ErrorKind::Usage
+        );
+    }
+
+    #[test]
+    fn display_is_the_message() {
+        let message = || String::from("Failed to load model default");
+        let errors = [
+            ExampleError::Usage(message()),
+            ExampleError::Config(message(), json!({ "ctx_size": 4096 })),
+            ExampleError::ModelLoad(message(), Some(Error::BackendError(BackendError::NotFound))),
+            ExampleError::PromptTooLong(message(), json!({ "limit": 10 })),
+            ExampleError::Backend(message(), None),
+        ];
+        for error in errors {
+            assert_eq!(error.to_string(), message());
+        }
+        assert_eq!(
+            ExampleError::Io(io::Error::other("broken pipe")).to_string(),
+            "Failed to read the input or write the output: broken pipe"
+        );
+    }
+
+    const KINDS: [ErrorKind; 5] = [
+        ErrorKind::Usage,
+        ErrorKind::ModelLoad,
+        ErrorKind::PromptTooLong,
+        ErrorKind::Backend,
+        ErrorKind::Io,
+    ];
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        for (i, a) in KINDS.iter().enumerate() {
+            assert_ne!(a.exit_code(), 0);
+            // 1 is what a panic exits with.
+            assert_ne!(a.exit_code(), 1);
+            for b in &KINDS[i + 1..] {
+                assert_ne!(a.exit_code(), b.exit_code());
+                assert_ne!(a.name(), b.name());
+            }
+        }
+    }
+
+    // Record failed turns until the tracker asks for more than doing nothing.
+    fn fail_until_recovery(tracker: &mut ErrorTracker) -> (u32, Recovery) {
+        let mut failures = 1;
+        loop {
+            match tracker.record(true) {
+                Recovery::None => failures += 1,
+                recovery => return (failures, recovery),
+            }
+        }
+    }
+
+    #[test]
+    fn rebuild_after_repeated_failures() {
+        let mut tracker = ErrorTracker::default();
+        for _ in 0..MAX_REBUILDS {
+            assert_eq!(
+                fail_until_recovery(&mut tracker),
+                (REBUILD_AFTER_ERRORS, Recovery::Rebuild)
+            );
+        }
+        assert_eq!(
+            fail_until_recovery(&mut tracker),
+            (REBUILD_AFTER_ERRORS, Recovery::GiveUp)
+        );
+    }
+
+    #[test]
+    fn successful_turn_resets_the_tracker() {
+        let mut tracker = ErrorTracker::default();
+        fail_until_recovery(&mut tracker);
+        tracker.record(true);
+        assert_eq!(tracker.record(false), Recovery::None);
+        for _ in 0..MAX_REBUILDS {
+            assert_eq!(fail_until_recovery(&mut tracker).1, Recovery::Rebuild);
+        }
+    }
+}
@@ -0,0 +1,1228 @@
+// The generation loop and its configuration.
+use crate::backend::{
+    get_output_from_context, get_single_output_from_context, is_streaming_unsupported,
+    set_data_to_context, set_metadata_to_context, try_get_metadata_from_context,
+    try_get_single_metadata_from_context, Backend, ComputeMode,
+};
+use crate::conversation::{keep_tail_chars, Conversation};
+use crate::error::ErrorKind;
+use crate::output::{
+    check_primer_echo, detect_repetition, extract_json, find_stop_sequence, printable_end, redact,
+    sha256_hex, strip_markdown, tail_lines, BanFilter, PrimerEcho,
+};
+use crate::sink::{StringSink, TokenSink, Utf8Sink};
+use crate::Log;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use wasmedge_wasi_nn::{BackendError, Error};
+
+// The probability of the last token from the `logprobs` entry of the single
+// output metadata. It's either the log probability itself, an object with a
+// `logprob` field, or a list of such objects for the tokens so far.
+fn parse_logprob(metadata: &Value) -> Option<f64> {
+    let entry = match &metadata["logprobs"] {
+        Value::Array(entries) => entries.last()?,
+        entry => entry,
+    };
+    let logprob = entry.as_f64().or_else(|| entry["logprob"].as_f64())?;
+    Some(logprob.exp())
+}
+
+// The most likely alternatives for the last token from the single output
+// metadata, at most `k` of them, each with its probability. They're either in
+// `top_logprobs` of the last `logprobs` entry or at the top level, as
+// `{"token": "...", "logprob": -0.2}` objects.
+fn parse_top_probs(metadata: &Value, k: usize) -> Option<Vec<(String, f64)>> {
+    let entry = match &metadata["logprobs"] {
+        Value::Array(entries) => entries.last().unwrap_or(&Value::Null),
+        entry => entry,
+    };
+    let top = entry["top_logprobs"]
+        .as_array()
+        .or_else(|| metadata["top_logprobs"].as_array())?;
+    top.iter()
+        .take(k)
+        .map(|candidate| {
+            let token = candidate["token"].as_str()?;
+            let logprob = candidate["logprob"].as_f64()?;
+            Some((token.to_string(), logprob.exp()))
+        })
+        .collect()
+}
+
+// Render the alternatives of a token for `--n-probs`, e.g.
+// `[ Paris 0.82 | London 0.10]`, dimmed on a terminal.
+pub fn format_top_probs(top: &[(String, f64)], dim: bool) -> String {
+    let alternatives: Vec<String> = top
+        .iter()
+        .map(|(token, probability)| format!("{} {:.2}", token.escape_debug(), probability))
+        .collect();
+    let text = format!("[{}]", alternatives.join(" |"));
+    if dim {
+        format!("\x1b[2m{}\x1b[0m", text)
+    } else {
+        text
+    }
+}
+
+// Format the number of tokens of the prompt that was just set, as reported by
+// `input_tokens` in the metadata.
+pub fn format_prompt_tokens(metadata: &Value) -> Option<String> {
+    let input_tokens = metadata["input_tokens"].as_u64()?;
+    Some(format!("this prompt: {} tokens", input_tokens))
+}
+
+// Format a number of bytes with a binary unit, e.g. `512.0 MiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+// Format the memory usage reported by the plugins that have it, the size of
+// the model weights as `model_bytes` and of the KV cache as `kv_cache_bytes`.
+// `None` if neither is reported.
+pub fn format_memory_stats(metadata: &Value) -> Option<String> {
+    let fields: Vec<String> = [("model", "model_bytes"), ("KV cache", "kv_cache_bytes")]
+        .iter()
+        .filter_map(|(name, key)| {
+            Some(format!(
+                "{} {}",
+                name,
+                format_bytes(metadata[*key].as_u64()?)
+            ))
+        })
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    Some(format!("memory: {}", fields.join(", ")))
+}
+
+// Why the generation of an answer stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    EndOfSequence,
+    // A stop sequence such as `--eos-token` ended the answer.
+    StopSequence,
+    // The answer reached the `n-predict` limit of tokens.
+    NPredict,
+    ContextFull,
+    PromptTooLong,
+    Timeout,
+    Repetition,
+    Banned,
+    // The answer was complete, but contained no JSON for `--extract-json`.
+    NoJson,
+    Error,
+}
+
+// The exit code for an answer that was cut short.
+const EXIT_TRUNCATED: u8 = 6;
+// The exit code for an answer without JSON with `--extract-json`.
+const EXIT_NO_JSON: u8 = 7;
+
+impl StopReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            StopReason::EndOfSequence => "eos",
+            StopReason::StopSequence => "stop-string",
+            StopReason::NPredict => "n-predict",
+            StopReason::ContextFull => "context_full",
+            StopReason::PromptTooLong => "prompt_too_long",
+            StopReason::Timeout => "timeout",
+            StopReason::Repetition => "repetition",
+            StopReason::Banned => "banned",
+            StopReason::NoJson => "no_json",
+            StopReason::Error => "error",
+        }
+    }
+
+    // The exit code of the one-shot and batch modes for an answer that stopped
+    // for this reason.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            StopReason::EndOfSequence | StopReason::StopSequence => 0,
+            StopReason::PromptTooLong => ErrorKind::PromptTooLong.exit_code(),
+            StopReason::NPredict
+            | StopReason::ContextFull
+            | StopReason::Timeout
+            | StopReason::Repetition
+            | StopReason::Banned => EXIT_TRUNCATED,
+            StopReason::NoJson => EXIT_NO_JSON,
+            StopReason::Error => ErrorKind::Backend.exit_code(),
+        }
+    }
+}
+
+// Thresholds of the client-side repetition loop detection.
+pub struct RepetitionConfig {
+    // The number of trailing characters of the answer that are inspected.
+    pub window: usize,
+    // The minimum length in characters of a repeated substring.
+    pub min_len: usize,
+    // Stop once a substring is repeated more than this many times in a row.
+    pub max_repeats: usize,
+}
+
+// Client-side settings of the generation loop.
+#[derive(Default)]
+pub struct GenerationConfig {
+    // Where the notices about the generation go.
+    pub log: Log,
+    // Stop streaming once a turn has been generating for longer than this.
+    pub turn_timeout: Option<Duration>,
+    // Stop streaming when the model is stuck repeating itself.
+    pub repetition: Option<RepetitionConfig>,
+    // Mask or stop on banned words and patterns.
+    pub ban_filter: Option<BanFilter>,
+    // End the answer at any of these, e.g. set by `--eos-token`.
+    pub stop_sequences: Vec<String>,
+    // The primer of the prompt template, an echo of it at the start of the
+    // answer is suppressed. Set once the template is known.
+    pub primer: &'static str,
+    // Printed right before and after every answer, but never sent to the model.
+    pub answer_prefix: String,
+    pub answer_suffix: String,
+    // Print every token with its probability to stderr, set by `--logprobs`.
+    pub logprobs: bool,
+    // Flush the streamed answer every this many tokens, or once this much
+    // time passed since the last flush, whichever comes first.
+    pub flush_every: usize,
+    pub flush_interval: Option<Duration>,
+    // Print the buffered answer without its markdown, set by `--strip-markdown`.
+    pub strip_markdown: bool,
+    // Print the SHA-256 of every answer, set by `--deterministic`.
+    pub output_hash: bool,
+    // Print only the last lines of the buffered answer, set by `--tail`.
+    pub tail: Option<usize>,
+    // Print the percentiles of the inter-token latency, set by `--token-timings`.
+    pub token_timings: bool,
+    // Replace the matches in the buffered answer, set by `--redact`.
+    pub redact: Option<Regex>,
+    // Print the memory usage after every answer, set by `--memory-stats`.
+    pub memory_stats: bool,
+    // Show this many alternatives of every token, set by `--n-probs`.
+    pub n_probs: Option<usize>,
+    // Print only the JSON in the buffered answer, set by `--extract-json`.
+    pub extract_json: bool,
+    // The backend is asked to shift the context instead of stopping when it's
+    // full, set by `--context-shift`.
+    pub context_shift: bool,
+    // The `n-predict` option, to tell an answer that reached it from one the
+    // model finished.
+    pub n_predict: Option<u64>,
+}
+
+// Whether the streamed output needs to be flushed now, with `pending` tokens
+// written since the last flush `since` ago.
+fn should_flush(
+    pending: usize,
+    since: Duration,
+    flush_every: usize,
+    flush_interval: Option<Duration>,
+) -> bool {
+    pending >= flush_every || flush_interval.is_some_and(|interval| since >= interval)
+}
+
+// The result of generating one answer.
+pub struct Generation {
+    pub output: String,
+    pub reason: StopReason,
+    pub stats: TurnStats,
+    // The backend error when `reason` is `StopReason::Error`.
+    pub error: Option<Error>,
+}
+
+// The token counts and the time of a single turn, the same whether the
+// backend reports the counters per call or accumulated over the context.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TurnStats {
+    // The number of prompt tokens, if the backend reports it.
+    pub prompt_tokens: Option<u64>,
+    // The number of generated tokens and the time spent generating them.
+    pub completion_tokens: u64,
+    pub elapsed: Duration,
+}
+
+impl TurnStats {
+    // The statistics as they're reported in the JSON events and responses.
+    pub fn fields(&self) -> serde_json::Map<String, Value> {
+        let mut fields = serde_json::Map::new();
+        fields.insert(String::from("input_tokens"), json!(self.prompt_tokens));
+        fields.insert(String::from("output_tokens"), json!(self.completion_tokens));
+        fields.insert(String::from("t_ms"), json!(self.elapsed.as_millis() as u64));
+        fields.insert(
+            String::from("tokens_per_second"),
+            json!(tokens_per_second(self.completion_tokens, self.elapsed)),
+        );
+        fields
+    }
+}
+
+// The token counters of the metadata at one point of a turn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TokenCounters {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl TokenCounters {
+    pub fn read(context: &dyn Backend) -> Option<TokenCounters> {
+        let metadata = try_get_metadata_from_context(context)?;
+        Some(TokenCounters {
+            input_tokens: metadata["input_tokens"].as_u64()?,
+            output_tokens: metadata["output_tokens"].as_u64().unwrap_or(0),
+        })
+    }
+
+    // The prompt and completion tokens of a turn, from the counters right
+    // after `set_input` and after the generation. Some plugin versions reset
+    // the counters with every call, others accumulate them over the context.
+    // A counter which grew during the turn is accumulated and the turn's share
+    // is its growth, otherwise it was reset and its final value is the turn's
+    // share. The prompt always has tokens, so an input counter which didn't
+    // grow was set anew by `set_input`.
+    fn delta(start: TokenCounters, end: TokenCounters) -> (u64, u64) {
+        let prompt_tokens = if end.input_tokens > start.input_tokens {
+            end.input_tokens - start.input_tokens
+        } else {
+            end.input_tokens
+        };
+        let completion_tokens = if end.output_tokens >= start.output_tokens {
+            end.output_tokens - start.output_tokens
+        } else {
+            end.output_tokens
+        };
+        (prompt_tokens, completion_tokens)
+    }
+}
+
+// Run the inference on the prompt that is already set to the context.
+//
+// In streaming mode the tokens are computed one by one with `compute_single`
+// and written to `out` as soon as they are available. Otherwise `compute` is
+// called once and the whole output is read with `get_output` and written at
+// the end, which works on backends that don't support the single token API.
+// The mode is updated in place, so a fallback decided here sticks for the
+// whole session. `out` is flushed as set by `--flush-every` and
+// `--flush-every-ms`, after every token by default, so that it shows up on a
+// terminal right away. The `prefill` the prompt ends with is printed as the
+// start of the answer, but isn't part of the returned output. Backend errors
+// end up in the returned `Generation`, a failure to write to `out` is
+// returned as the error.
+pub fn generate(
+    context: &mut dyn Backend,
+    mode: &mut ComputeMode,
+    config: &GenerationConfig,
+    prefill: &str,
+    out: &mut dyn TokenSink,
+) -> Result<Generation, Error> {
+    let log = config.log;
+    let start_counters = TokenCounters::read(context);
+    out.start(context)?;
+    out.write_frame(&config.answer_prefix)?;
+    out.write_str(prefill)?;
+    out.flush()?;
+    let mut output = String::new();
+    let mut error = None;
+    let mut tokens = 0;
+    // The number of characters of the output printed so far by the ban filter.
+    let mut printed = 0;
+    // The number of bytes of the output printed so far with stop sequences.
+    let mut printed_bytes = 0;
+    // An answer continuing a prefill can't start with an echo of the primer.
+    let mut primer_pending = !config.primer.is_empty() && prefill.is_empty();
+    // Cleared once the backend turns out not to report the probabilities.
+    let mut logprobs = config.logprobs;
+    let mut n_probs = config.n_probs;
+    // The tokens written since the last flush.
+    let mut pending = 0;
+    // Decodes the tokens into the text of the output.
+    let mut decoder = Utf8Sink::new(StringSink::default());
+    // Whether the output was cut short, dropping anything the decoder holds back.
+    let mut truncated = false;
+    let start = Instant::now();
+    let mut last_flush = start;
+    // The time between consecutive streamed tokens, for `--token-timings`.
+    let mut token_deltas = Vec::new();
+    let mut last_token = None;
+    let deadline = config.turn_timeout.map(|timeout| start + timeout);
+    let mut reason = loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break StopReason::Timeout;
+        }
+        let result = if *mode == ComputeMode::Batch {
+            context.compute()
+        } else {
+            context.compute_single()
+        };
+        match result {
+            Ok(_) => (),
+            Err(err) if *mode == ComputeMode::Probe && is_streaming_unsupported(&err) => {
+                info!(log,
+                    "Streaming is not supported by the backend ({}), falling back to non-streaming mode.",
+                    err
+                );
+                *mode = ComputeMode::Batch;
+                continue;
+            }
+            Err(Error::BackendError(BackendError::EndOfSequence)) => {
+                break StopReason::EndOfSequence;
+            }
+            Err(Error::BackendError(BackendError::ContextFull)) => {
+                break StopReason::ContextFull;
+            }
+            Err(Error::BackendError(BackendError::PromptTooLong)) => {
+                break StopReason::PromptTooLong;
+            }
+            Err(err) => {
+                error = Some(err);
+                break StopReason::Error;
+            }
+        }
+        if *mode == ComputeMode::Batch {
+            if logprobs {
+                warn!(log, "--logprobs is not supported in non-streaming mode.");
+            }
+            if config.token_timings {
+                warn!(
+                    log,
+                    "--token-timings is not supported in non-streaming mode."
+                );
+            }
+            if n_probs.is_some() {
+                warn!(log, "--n-probs is not supported in non-streaming mode.");
+            }
+            output = match get_output_from_context(context) {
+                Ok(output) => output,
+                Err(err) => {
+                    error = Some(err);
+                    break StopReason::Error;
+                }
+            };
+            tokens = try_get_metadata_from_context(context)
+                .and_then(|metadata| metadata["output_tokens"].as_u64())
+                .unwrap_or(0);
+            if let Some(start) = config
+                .ban_filter
+                .as_ref()
+                .and_then(|f| f.find_stop(&output))
+            {
+                output.truncate(start);
+                break StopReason::Banned;
+            }
+            let stopped = find_stop_sequence(&output, &config.stop_sequences);
+            if let Some(start) = stopped {
+                output.truncate(start);
+            }
+            if let PrimerEcho::Echo(len) = check_primer_echo(&output, config.primer) {
+                output = output[len..].trim_start().to_string();
+            }
+            break match stopped {
+                Some(_) => StopReason::StopSequence,
+                None => StopReason::EndOfSequence,
+            };
+        }
+        *mode = ComputeMode::Stream;
+        // The first token also includes the prompt processing, so it isn't
+        // counted.
+        if config.token_timings {
+            let now = Instant::now();
+            if let Some(last) = last_token.replace(now) {
+                token_deltas.push(now - last);
+            }
+        }
+        // Retrieve the single output token and print it.
+        let token = match get_single_output_from_context(context) {
+            Ok(bytes) => {
+                decoder.write(&bytes)?;
+                decoder.inner.take()
+            }
+            Err(err) => {
+                error = Some(err);
+                break StopReason::Error;
+            }
+        };
+        output += &token;
+        tokens += 1;
+        if logprobs {
+            match try_get_single_metadata_from_context(context)
+                .as_ref()
+                .and_then(parse_logprob)
+            {
+                Some(probability) => eprint!("{}({:.2}) ", token, probability),
+                None => {
+                    warn!(log, "--logprobs is not supported by the backend, no probabilities are reported.");
+                    logprobs = false;
+                }
+            }
+        }
+        if let Some(k) = n_probs {
+            match try_get_single_metadata_from_context(context)
+                .as_ref()
+                .and_then(|metadata| parse_top_probs(metadata, k))
+            {
+                Some(top) => out.top_probs(&top)?,
+                None => {
+                    warn!(
+                        log,
+                        "--n-probs is not supported by the backend, no alternatives are reported."
+                    );
+                    n_probs = None;
+                }
+            }
+        }
+        // Hold the output back while it may be an echo of the primer, which
+        // is dropped. Once that's decided, everything held back is printed.
+        let mut held_back = false;
+        if primer_pending {
+            match check_primer_echo(&output, config.primer) {
+                PrimerEcho::Pending => continue,
+                PrimerEcho::Echo(len) => {
+                    output = output[len..].trim_start().to_string();
+                }
+                PrimerEcho::Content => (),
+            }
+            primer_pending = false;
+            held_back = true;
+        }
+        // A stop sequence ends the answer as if the model had finished it.
+        if let Some(start) = find_stop_sequence(&output, &config.stop_sequences) {
+            output.truncate(start);
+            truncated = true;
+            break StopReason::StopSequence;
+        }
+        // The token completing a loop isn't printed, the answer is stopped first.
+        if let Some(repetition) = &config.repetition {
+            if let Some(end) = detect_repetition(&output, repetition) {
+                output.truncate(end);
+                truncated = true;
+                break StopReason::Repetition;
+            }
+        }
+        let end = printable_end(&output, &config.stop_sequences);
+        if let Some(filter) = &config.ban_filter {
+            if let Some(start) = filter.find_stop(&output) {
+                output.truncate(start);
+                truncated = true;
+                break StopReason::Banned;
+            }
+            out.write_str(&filter.release(&output[..end], &mut printed, false))?;
+        } else if config.stop_sequences.is_empty() {
+            out.write_str(if held_back { &output } else { &token })?;
+        } else {
+            out.write_str(&output[printed_bytes..end])?;
+            printed_bytes = end;
+        }
+        pending += 1;
+        if should_flush(
+            pending,
+            last_flush.elapsed(),
+            config.flush_every,
+            config.flush_interval,
+        ) {
+            out.flush()?;
+            pending = 0;
+            last_flush = Instant::now();
+        }
+    };
+    // The backend ends an answer at the `n-predict` limit like at the end of
+    // the sequence, the number of tokens tells them apart.
+    if reason == StopReason::EndOfSequence && config.n_predict.is_some_and(|n| tokens >= n) {
+        reason = StopReason::NPredict;
+    }
+    // The last token may have ended in the middle of a character.
+    decoder.finish()?;
+    let tail = decoder.inner.take();
+    if !truncated {
+        output += &tail;
+    }
+    // An answer consisting of the primer only is an echo as well.
+    if primer_pending && output.trim() == config.primer.trim() {
+        output.clear();
+    }
+    if *mode == ComputeMode::Batch {
+        if let Some(filter) = &config.ban_filter {
+            output = filter.mask(&output);
+        }
+        let mut shown = if config.strip_markdown {
+            strip_markdown(&output)
+        } else if config.extract_json {
+            match extract_json(&output) {
+                Some(json) => json.to_string(),
+                None => {
+                    if matches!(reason, StopReason::EndOfSequence | StopReason::StopSequence) {
+                        reason = StopReason::NoJson;
+                    }
+                    String::new()
+                }
+            }
+        } else {
+            output.clone()
+        };
+        if let Some(regex) = &config.redact {
+            shown = redact(&shown, regex);
+        }
+        if let Some(lines) = config.tail {
+            shown = tail_lines(&shown, lines).to_string();
+        }
+        out.write_str(&shown)?;
+    } else if let Some(filter) = &config.ban_filter {
+        out.write_str(&filter.release(&output, &mut printed, true))?;
+        output = filter.mask(&output);
+    } else if !config.stop_sequences.is_empty() {
+        out.write_str(&output[printed_bytes.min(output.len())..])?;
+    } else if primer_pending {
+        out.write_str(&output)?;
+    } else if !truncated {
+        out.write_str(&tail)?;
+    }
+    out.write_frame(&config.answer_suffix)?;
+    out.write_frame("\n")?;
+    out.finish()?;
+    if logprobs && *mode != ComputeMode::Batch {
+        eprintln!();
+    }
+    match reason {
+        StopReason::ContextFull if config.context_shift => info!(log,
+            "Context full although --context-shift is set, the backend doesn't support shifting the context. We'll reset the context and continue."
+        ),
+        StopReason::ContextFull => {
+            info!(log, "Context full, we'll reset the context and continue.")
+        }
+        StopReason::PromptTooLong => {
+            info!(log, "Prompt too long, we'll reset the context and continue.")
+        }
+        StopReason::Timeout => info!(log, "Generation timed out, the answer is incomplete."),
+        StopReason::Repetition => {
+            info!(log, "[repetition detected] The answer is stuck in a loop, stopping.")
+        }
+        StopReason::Banned => {
+            info!(log, "The answer contained banned content and was stopped.")
+        }
+        StopReason::NoJson => {
+            status!(log,
+                "[ERROR] The answer contains no JSON object or array:\n{}",
+                output
+            )
+        }
+        StopReason::NPredict => info!(log, "The answer reached the n-predict limit of tokens."),
+        StopReason::EndOfSequence | StopReason::StopSequence | StopReason::Error => (),
+    }
+    if config.output_hash {
+        info!(log, "output sha256: {}", sha256_hex(output.as_bytes()));
+    }
+    if config.memory_stats {
+        if let Some(stats) = try_get_metadata_from_context(context)
+            .as_ref()
+            .and_then(format_memory_stats)
+        {
+            info!(log, "{}", stats);
+        }
+    }
+    if !token_deltas.is_empty() {
+        token_deltas.sort();
+        let ms = |p: f64| percentile(&token_deltas, p).as_secs_f64() * 1000.0;
+        info!(log,
+            "inter-token latency over {} tokens: p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+            token_deltas.len(),
+            ms(50.0),
+            ms(90.0),
+            ms(99.0),
+            ms(100.0)
+        );
+    }
+    let elapsed = start.elapsed();
+    let counters = start_counters.zip(TokenCounters::read(context));
+    let deltas = counters.map(|(start, end)| TokenCounters::delta(start, end));
+    // Streamed tokens are counted as they arrive, which is exact.
+    let completion_tokens = match deltas {
+        Some((_, completion_tokens)) if *mode == ComputeMode::Batch => completion_tokens,
+        _ => tokens,
+    };
+    let generation = Generation {
+        output,
+        reason,
+        stats: TurnStats {
+            prompt_tokens: deltas.map(|(prompt_tokens, _)| prompt_tokens),
+            completion_tokens,
+            elapsed,
+        },
+        error,
+    };
+    out.end(&generation)?;
+    Ok(generation)
+}
+
+// Set the prompt and generate the answer into `out`, leaving the context ready
+// for the next prompt.
+pub fn run_prompt(
+    context: &mut dyn Backend,
+    mode: &mut ComputeMode,
+    config: &GenerationConfig,
+    prompt: &str,
+    prefill: &str,
+    out: &mut dyn TokenSink,
+) -> Result<Generation, Error> {
+    set_data_to_context(context, prompt.as_bytes().to_vec())?;
+    let generation = generate(context, mode, config, prefill, out)?;
+    if *mode != ComputeMode::Batch {
+        context.fini_single()?;
+    }
+    Ok(generation)
+}
+
+// The number of tokens of a generated session title, and the number of
+// characters of the end of the conversation it's generated from.
+const TITLE_N_PREDICT: u64 = 16;
+const TITLE_TRANSCRIPT_CHARS: usize = 2000;
+
+// Ask the model for a title of the conversation for `/save`. The prompt is
+// separate from the conversation, so the history isn't changed, and it's
+// generated without the output of `--logprobs`, `--memory-stats` and the
+// like. The `n-predict` of the options is restored afterwards.
+pub fn generate_title(
+    context: &mut dyn Backend,
+    mode: &mut ComputeMode,
+    config: &GenerationConfig,
+    conversation: &Conversation,
+    options: &Value,
+) -> Option<String> {
+    let log = config.log;
+    let turns = conversation.turns();
+    let transcript = turns
+        .iter()
+        .map(|turn| format!("User: {}\nAssistant: {}", turn.user, turn.assistant))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let prompt = conversation.template.format_question(
+        &conversation.system_prompt,
+        &format!(
+            "{}\n\nSummarize this conversation in 5 words. Reply with the words only.",
+            keep_tail_chars(&transcript, TITLE_TRANSCRIPT_CHARS)
+        ),
+        true,
+    );
+    let mut title_options = options.clone();
+    title_options["n-predict"] = json!(TITLE_N_PREDICT);
+    let title_options = serde_json::to_string(&title_options)
+        .expect("Failed to serialize options")
+        .into_bytes();
+    let title_config = GenerationConfig {
+        turn_timeout: config.turn_timeout,
+        stop_sequences: config.stop_sequences.clone(),
+        primer: config.primer,
+        log: config.log,
+        flush_every: 1,
+        ..GenerationConfig::default()
+    };
+    let result = set_metadata_to_context(context, title_options).and_then(|()| {
+        run_prompt(
+            context,
+            mode,
+            &title_config,
+            &prompt,
+            "",
+            &mut StringSink::default(),
+        )
+    });
+    // The plugin keeps options which aren't sent again, so without an
+    // `n-predict` of its own the user gets the default of no limit, -1, back.
+    let mut restored = options.clone();
+    if restored.get("n-predict").is_none() {
+        restored["n-predict"] = json!(-1);
+    }
+    let restored = serde_json::to_string(&restored)
+        .expect("Failed to serialize options")
+        .into_bytes();
+    if let Err(err) = set_metadata_to_context(context, restored) {
+        warn!(
+            log,
+            "Failed to restore the options after the title: {}", err
+        );
+    }
+    match result {
+        Ok(Generation {
+            error: None,
+            output,
+            ..
+        }) => Some(output),
+        Ok(Generation {
+            error: Some(err), ..
+        })
+        | Err(err) => {
+            warn!(log, "Failed to generate a title for the session: {}", err);
+            None
+        }
+    }
+}
+
+pub fn tokens_per_second(tokens: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    tokens as f64 / elapsed.as_secs_f64()
+}
+
+// The `p`th percentile of the sorted durations by the nearest-rank method, so
+// it's always one of them.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::DEFAULT_MAX_OUTPUT_BYTES;
+    use crate::template::{PromptTemplate, RoleNames};
+    use std::collections::VecDeque;
+
+    // A backend which answers every prompt with the same tokens, and reports
+    // the token counters in its metadata like the GGML plugin.
+    #[derive(Default)]
+    struct FakeBackend {
+        answer: Vec<&'static [u8]>,
+        // Whether `compute_single` is rejected as unsupported.
+        batch_only: bool,
+        prompt: Vec<u8>,
+        // The options last set through the metadata tensor.
+        options: Value,
+        pending: VecDeque<&'static [u8]>,
+        token: Vec<u8>,
+        output: Vec<u8>,
+        output_tokens: u64,
+        finished: bool,
+        // The number of tokens that fill the context, unless the
+        // `context-shift` option is set.
+        ctx_size: Option<u64>,
+    }
+
+    impl FakeBackend {
+        fn new(answer: &[&'static str]) -> Self {
+            FakeBackend {
+                answer: answer.iter().map(|token| token.as_bytes()).collect(),
+                ..FakeBackend::default()
+            }
+        }
+
+        fn metadata(&self) -> Vec<u8> {
+            json!({
+                "input_tokens": self.prompt.len(),
+                "output_tokens": self.output_tokens,
+            })
+            .to_string()
+            .into_bytes()
+        }
+    }
+
+    fn copy(bytes: &[u8], buffer: &mut [u8]) -> Result<usize, Error> {
+        let len = bytes.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&bytes[..len]);
+        Ok(bytes.len())
+    }
+
+    impl Backend for FakeBackend {
+        fn set_input(&mut self, index: usize, data: &[u8]) -> Result<(), Error> {
+            if index == 0 {
+                self.prompt = data.to_vec();
+                self.pending = self.answer.iter().copied().collect();
+                self.output_tokens = 0;
+                self.finished = false;
+            } else {
+                self.options = serde_json::from_slice(data).unwrap();
+            }
+            Ok(())
+        }
+
+        fn compute(&mut self) -> Result<(), Error> {
+            self.output = self.pending.drain(..).flatten().copied().collect();
+            self.output_tokens = self.answer.len() as u64;
+            Ok(())
+        }
+
+        fn compute_single(&mut self) -> Result<(), Error> {
+            if self.batch_only {
+                return Err(Error::BackendError(BackendError::UnsupportedOperation));
+            }
+            if self.options["context-shift"] != json!(true)
+                && self
+                    .ctx_size
+                    .is_some_and(|ctx_size| self.output_tokens >= ctx_size)
+            {
+                return Err(Error::BackendError(BackendError::ContextFull));
+            }
+            let token = self
+                .pending
+                .pop_front()
+                .ok_or(Error::BackendError(BackendError::EndOfSequence))?;
+            self.token = token.to_vec();
+            self.output_tokens += 1;
+            Ok(())
+        }
+
+        fn fini_single(&mut self) -> Result<(), Error> {
+            self.finished = true;
+            Ok(())
+        }
+
+        fn get_output(&self, index: usize, buffer: &mut [u8]) -> Result<usize, Error> {
+            match index {
+                0 => copy(&self.output, buffer),
+                _ => copy(&self.metadata(), buffer),
+            }
+        }
+
+        fn get_output_single(&self, index: usize, buffer: &mut [u8]) -> Result<usize, Error> {
+            match index {
+                0 => copy(&self.token, buffer),
+                _ => Err(Error::BackendError(BackendError::UnsupportedOperation)),
+            }
+        }
+
+        fn max_output_bytes(&self) -> usize {
+            DEFAULT_MAX_OUTPUT_BYTES
+        }
+    }
+
+    fn run(
+        backend: &mut FakeBackend,
+        mode: &mut ComputeMode,
+        config: &GenerationConfig,
+    ) -> (Generation, String) {
+        let mut sink = StringSink::default();
+        let generation = run_prompt(backend, mode, config, "What?", "", &mut sink).unwrap();
+        (generation, sink.into_string())
+    }
+
+    #[test]
+    fn streams_the_answer_into_the_sink() {
+        let mut backend = FakeBackend::new(&["Hel", "lo", " wor", "ld"]);
+        let mut mode = ComputeMode::Probe;
+        let (generation, shown) = run(&mut backend, &mut mode, &GenerationConfig::default());
+        assert_eq!(shown, "Hello world\n");
+        assert_eq!(generation.output, "Hello world");
+        assert_eq!(generation.reason, StopReason::EndOfSequence);
+        assert_eq!(generation.stats.prompt_tokens, Some(5));
+        assert_eq!(generation.stats.completion_tokens, 4);
+        assert_eq!(mode, ComputeMode::Stream);
+        assert_eq!(backend.prompt, b"What?");
+        assert!(backend.finished);
+    }
+
+    #[test]
+    fn prefix_and_suffix_frame_the_answer() {
+        let mut backend = FakeBackend::new(&["Paris", "."]);
+        let config = GenerationConfig {
+            answer_prefix: String::from("<answer>"),
+            answer_suffix: String::from("</answer>"),
+            ..GenerationConfig::default()
+        };
+        let (generation, shown) = run(&mut backend, &mut ComputeMode::Probe, &config);
+        assert_eq!(shown, "<answer>Paris.</answer>\n");
+        assert_eq!(generation.output, "Paris.");
+    }
+
+    #[test]
+    fn title_restores_n_predict() {
+        let mut conversation = Conversation::new(
+            PromptTemplate::ChatMl,
+            RoleNames::default(),
+            String::from("You are helpful."),
+            Vec::new(),
+            Log::default(),
+        );
+        conversation.push(String::from("Capital of Japan?"), String::from("Tokyo."), 2);
+        let mut backend = FakeBackend::new(&["Capital", " of", " Japan"]);
+        let mut mode = ComputeMode::Probe;
+        let config = GenerationConfig::default();
+        let title = |backend: &mut FakeBackend, mode: &mut ComputeMode, options: &Value| {
+            generate_title(backend, mode, &config, &conversation, options)
+        };
+        let options = json!({ "temp": 0.5 });
+        assert_eq!(
+            title(&mut backend, &mut mode, &options).as_deref(),
+            Some("Capital of Japan")
+        );
+        assert_eq!(backend.options, json!({ "temp": 0.5, "n-predict": -1 }));
+        let options = json!({ "n-predict": 100 });
+        title(&mut backend, &mut mode, &options);
+        assert_eq!(backend.options, options);
+    }
+
+    #[test]
+    fn repetition_stops_before_the_loop_is_printed() {
+        let mut backend = FakeBackend::new(&["ab"; 6]);
+        let config = GenerationConfig {
+            repetition: Some(RepetitionConfig {
+                window: 64,
+                min_len: 2,
+                max_repeats: 2,
+            }),
+            ..GenerationConfig::default()
+        };
+        let (generation, shown) = run(&mut backend, &mut ComputeMode::Probe, &config);
+        assert_eq!(generation.reason, StopReason::Repetition);
+        // The third repeat is detected before it's written.
+        assert_eq!(shown, "abab\n");
+        assert_eq!(generation.output, "ab");
+        assert_eq!(generation.stats.completion_tokens, 3);
+    }
+
+    #[test]
+    fn logprob_of_the_last_token() {
+        let probability = |metadata: Value| parse_logprob(&metadata).unwrap();
+        assert_eq!(probability(json!({ "logprobs": 0.0 })), 1.0);
+        assert!(
+            (probability(json!({ "logprobs": { "logprob": -0.5 } })) - (-0.5f64).exp()).abs()
+                < 1e-9
+        );
+        let metadata = json!({ "logprobs": [{ "logprob": -3.0 }, { "logprob": -0.2 }] });
+        assert!((probability(metadata) - (-0.2f64).exp()).abs() < 1e-9);
+        assert_eq!(parse_logprob(&json!({ "logprobs": [] })), None);
+        assert_eq!(parse_logprob(&json!({ "input_tokens": 5 })), None);
+    }
+
+    #[test]
+    fn top_probs_of_the_last_token() {
+        let candidates = json!([
+            { "token": " Paris", "logprob": 0.0 },
+            { "token": " London", "logprob": -2.0 },
+            { "token": " Rome", "logprob": -3.0 },
+        ]);
+        let metadata =
+            json!({ "logprobs": [{ "top_logprobs": [] }, { "top_logprobs": candidates }] });
+        let top = parse_top_probs(&metadata, 2).unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0], (String::from(" Paris"), 1.0));
+        assert_eq!(top[1].0, " London");
+        assert!((top[1].1 - (-2.0f64).exp()).abs() < 1e-9);
+        let metadata = json!({ "top_logprobs": candidates });
+        assert_eq!(parse_top_probs(&metadata, 5).unwrap().len(), 3);
+        assert_eq!(parse_top_probs(&json!({ "logprobs": -0.1 }), 2), None);
+    }
+
+    #[test]
+    fn top_probs_rendering() {
+        let top = vec![
+            (String::from(" Paris"), 0.82),
+            (String::from(" London\n"), 0.1),
+        ];
+        assert_eq!(
+            format_top_probs(&top, false),
+            "[ Paris 0.82 | London\\n 0.10]"
+        );
+        assert_eq!(
+            format_top_probs(&top, true),
+            "\x1b[2m[ Paris 0.82 | London\\n 0.10]\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_batch_mode() {
+        let mut backend = FakeBackend::new(&["Hel", "lo"]);
+        backend.batch_only = true;
+        let mut mode = ComputeMode::Probe;
+        let (generation, shown) = run(&mut backend, &mut mode, &GenerationConfig::default());
+        assert_eq!(mode, ComputeMode::Batch);
+        assert_eq!(shown, "Hello\n");
+        assert_eq!(generation.reason, StopReason::EndOfSequence);
+        assert_eq!(generation.stats.completion_tokens, 2);
+        // There is no single token session to finish.
+        assert!(!backend.finished);
+    }
+
+    #[test]
+    fn backend_errors_end_up_in_the_generation() {
+        let mut backend = FakeBackend::new(&["Hi"]);
+        backend.batch_only = true;
+        let (generation, _) = run(
+            &mut backend,
+            &mut ComputeMode::Stream,
+            &GenerationConfig::default(),
+        );
+        assert_eq!(generation.reason, StopReason::Error);
+        assert!(generation.error.is_some());
+    }
+
+    #[test]
+    fn prompt_tokens_from_the_metadata() {
+        assert_eq!(
+            format_prompt_tokens(&json!({ "input_tokens": 42, "output_tokens": 0 })).as_deref(),
+            Some("this prompt: 42 tokens")
+        );
+        assert_eq!(format_prompt_tokens(&json!({ "output_tokens": 3 })), None);
+        assert_eq!(format_prompt_tokens(&json!({ "input_tokens": "42" })), None);
+        // As read from the context right after the prompt is set.
+        let mut backend = FakeBackend::new(&[]);
+        set_data_to_context(&mut backend, b"What?".to_vec()).unwrap();
+        let metadata = try_get_metadata_from_context(&backend).unwrap();
+        assert_eq!(
+            format_prompt_tokens(&metadata).as_deref(),
+            Some("this prompt: 5 tokens")
+        );
+    }
+
+    #[test]
+    fn exit_codes_of_stop_reasons() {
+        assert_eq!(StopReason::EndOfSequence.exit_code(), 0);
+        assert_eq!(StopReason::StopSequence.exit_code(), 0);
+        assert_eq!(StopReason::NPredict.exit_code(), EXIT_TRUNCATED);
+        assert_eq!(StopReason::Timeout.exit_code(), EXIT_TRUNCATED);
+        assert_eq!(StopReason::NoJson.exit_code(), EXIT_NO_JSON);
+        assert_eq!(
+            StopReason::PromptTooLong.exit_code(),
+            ErrorKind::PromptTooLong.exit_code()
+        );
+        assert_eq!(
+            StopReason::Error.exit_code(),
+            ErrorKind::Backend.exit_code()
+        );
+    }
+
+    #[test]
+    fn stop_sequence_ends_the_answer() {
+        let mut backend = FakeBackend::new(&["Hi", "\nUS", "ER:", " more"]);
+        let config = GenerationConfig {
+            stop_sequences: vec![String::from("\nUSER:")],
+            ..GenerationConfig::default()
+        };
+        let (generation, shown) = run(&mut backend, &mut ComputeMode::Stream, &config);
+        assert_eq!(generation.output, "Hi");
+        assert_eq!(generation.reason, StopReason::StopSequence);
+        assert_eq!(shown, "Hi\n");
+    }
+
+    #[test]
+    fn primer_echo_is_not_shown() {
+        let mut backend = FakeBackend::new(&["<|assis", "tant|>", "\nHi"]);
+        let config = GenerationConfig {
+            primer: "<|assistant|>",
+            ..GenerationConfig::default()
+        };
+        let (generation, shown) = run(&mut backend, &mut ComputeMode::Stream, &config);
+        assert_eq!(generation.output, "Hi");
+        assert_eq!(shown, "Hi\n");
+    }
+
+    #[test]
+    fn flush_after_enough_tokens_or_time() {
+        let ms = Duration::from_millis;
+        assert!(should_flush(1, ms(0), 1, None));
+        assert!(!should_flush(3, ms(500), 4, None));
+        assert!(should_flush(4, ms(0), 4, None));
+        assert!(!should_flush(1, ms(49), 4, Some(ms(50))));
+        assert!(should_flush(1, ms(50), 4, Some(ms(50))));
+    }
+
+    #[test]
+    fn characters_split_across_tokens() {
+        let mut backend = FakeBackend {
+            answer: vec![b"caf", b"\xc3", b"\xa9 \xe2\x82", b"\xac"],
+            ..FakeBackend::default()
+        };
+        let (generation, shown) = run(
+            &mut backend,
+            &mut ComputeMode::Stream,
+            &GenerationConfig::default(),
+        );
+        assert_eq!(generation.output, "café €");
+        assert_eq!(shown, "café €\n");
+    }
+
+    #[test]
+    fn percentile_by_nearest_rank() {
+        let sorted: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(5));
+        assert_eq!(percentile(&sorted, 91.0), Duration::from_millis(10));
+        assert_eq!(percentile(&sorted, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&sorted, 100.0), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn redact_the_buffered_answer() {
+        let mut backend = FakeBackend::new(&["key: ", "abc", "123"]);
+        let config = GenerationConfig {
+            redact: Some(regex::Regex::new("abc1").unwrap()),
+            ..GenerationConfig::default()
+        };
+        let (generation, shown) = run(&mut backend, &mut ComputeMode::Batch, &config);
+        assert_eq!(shown, "key: [REDACTED]23\n");
+        // Only the output is redacted, the history keeps the answer.
+        assert_eq!(generation.output, "key: abc123");
+    }
+
+    #[test]
+    fn token_counters_accumulated_or_reset() {
+        let counters = |input_tokens, output_tokens| TokenCounters {
+            input_tokens,
+            output_tokens,
+        };
+        // Accumulated over the context.
+        assert_eq!(
+            TokenCounters::delta(counters(100, 50), counters(130, 80)),
+            (30, 30)
+        );
+        // Reset with every call.
+        assert_eq!(
+            TokenCounters::delta(counters(20, 0), counters(20, 12)),
+            (20, 12)
+        );
+        assert_eq!(
+            TokenCounters::delta(counters(100, 50), counters(20, 12)),
+            (20, 12)
+        );
+    }
+
+    #[test]
+    fn memory_stats_of_the_metadata() {
+        assert_eq!(
+            format_memory_stats(&json!({"model_bytes": 4_294_967_296u64, "kv_cache_bytes": 1536})),
+            Some(String::from("memory: model 4.0 GiB, KV cache 1.5 KiB"))
+        );
+        assert_eq!(
+            format_memory_stats(&json!({"kv_cache_bytes": 512})),
+            Some(String::from("memory: KV cache 512 B"))
+        );
+        assert_eq!(format_memory_stats(&json!({"input_tokens": 5})), None);
+    }
+
+    #[test]
+    fn n_predict_limit() {
+        let mut backend = FakeBackend::new(&["one", " two"]);
+        let config = GenerationConfig {
+            n_predict: Some(2),
+            ..GenerationConfig::default()
+        };
+        let (generation, _) = run(&mut backend, &mut ComputeMode::Stream, &config);
+        assert_eq!(generation.reason, StopReason::NPredict);
+    }
+
+    #[test]
+    fn context_full_unless_shifted() {
+        let mut backend = FakeBackend::new(&["one", " two", " three"]);
+        backend.ctx_size = Some(2);
+        let config = GenerationConfig {
+            context_shift: true,
+            ..GenerationConfig::default()
+        };
+        let (generation, _) = run(&mut backend, &mut ComputeMode::Stream, &config);
+        assert_eq!(generation.reason, StopReason::ContextFull);
+        assert_eq!(generation.output, "one two");
+        // With the option set, the backend shifts the context and goes on.
+        backend.options = json!({"context-shift": true, "n-keep": 1});
+        let (generation, _) = run(&mut backend, &mut ComputeMode::Stream, &config);
+        assert_eq!(generation.reason, StopReason::EndOfSequence);
+        assert_eq!(generation.output, "one two three");
+    }
+}
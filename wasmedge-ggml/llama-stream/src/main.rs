@@ -1,288 +1,1965 @@
-use serde_json::json;
-use serde_json::Value;
-use std::env;
-use std::io::{self, Write};
-use wasmedge_wasi_nn::{
-    self, BackendError, Error, ExecutionTarget, GraphBuilder, GraphEncoding, GraphExecutionContext,
-    TensorType,
+use crate::backend::{
+    get_metadata_from_context, set_data_to_context, set_metadata_to_context,
+    try_get_metadata_from_context, Backend, ComputeMode, Context,
 };
+use crate::capabilities::Capabilities;
+use crate::cli::{
+    apply_deterministic, check_ctx_size, check_ignore_eos_bounds, dynatemp_without_temp,
+    get_options_from_env, read_batch_file, read_input_file, take_flag, Cli, ModelCandidate,
+    DETERMINISTIC_SEED,
+};
+use crate::conversation::{
+    apply_prompt_prefix, estimate_tokens, keep_tail, keep_tail_chars, language, merge_options,
+    prompt_chars_excess, prompt_token_limit, Conversation, Language, Persona, Turn,
+    BYTES_PER_TOKEN, PERSONAS_DIR, SYSTEM_PROMPT, TIMED_OUT_MARKER,
+};
+use crate::error::{recover, report_error, ErrorTracker, ExampleError};
+use crate::generate::{
+    format_memory_stats, format_prompt_tokens, generate, generate_title, run_prompt,
+    tokens_per_second, Generation, GenerationConfig, StopReason,
+};
+use crate::repl::{parse_repl_command, read_input, set_logit_bias, ReplCommand};
+use crate::rpc::{parse_rpc_request, rpc_emit, rpc_error, RpcMethod, RpcPartialSink};
+use crate::session::{
+    load_session, save_session, session_expired, session_file_name, session_recap, slugify,
+    SessionStats, SessionSummary, SESSION_FILE,
+};
+use crate::sink::{StringSink, TokenSink};
+use crate::template::{show_prompt, template_mismatch, PromptTemplate};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, io};
+use wasmedge_wasi_nn::{self, Error, ExecutionTarget, Graph, GraphBuilder, GraphEncoding};
 
-fn read_input() -> String {
-    loop {
-        let mut answer = String::new();
-        io::stdin()
-            .read_line(&mut answer)
-            .expect("Failed to read line");
-        if !answer.is_empty() && answer != "\n" && answer != "\r\n" {
-            return answer.trim().to_string();
+// Where the lines which aren't model output go.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Log {
+    // Set by `--quiet`, and implied by the one-shot and batch modes, to keep
+    // stdout for the model output only.
+    pub quiet: bool,
+    // Set by `--bare` to print nothing but the generated text, without any
+    // labels or informational lines.
+    pub bare: bool,
+}
+
+// The kinds of lines which aren't model output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineKind {
+    // Errors and the output of commands, always printed.
+    Status,
+    // Labels such as `USER:` or `Response:`.
+    Label,
+    Info,
+    Warn,
+}
+
+impl Log {
+    // The text a line is printed as, `None` if `--bare` drops it.
+    pub fn format(self, kind: LineKind, message: &str) -> Option<String> {
+        match kind {
+            LineKind::Status => Some(message.to_string()),
+            _ if self.bare => None,
+            LineKind::Label => Some(message.to_string()),
+            LineKind::Info => Some(format!("[INFO] {}", message)),
+            LineKind::Warn => Some(format!("[WARN] {}", message)),
         }
     }
 }
 
-fn get_options_from_env() -> Value {
-    let mut options = json!({});
-    if let Ok(val) = env::var("enable_log") {
-        options["enable-log"] = serde_json::from_str(val.as_str())
-            .expect("invalid value for enable-log option (true/false)")
-    } else {
-        options["enable-log"] = serde_json::from_str("false").unwrap()
+// Print a line which isn't model output, such as a label or an error. It goes
+// to stderr in quiet mode.
+macro_rules! status {
+    ($log:expr, $($arg:tt)*) => {
+        if $log.quiet {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Print a line of the given kind, unless `--bare` drops it.
+macro_rules! print_line {
+    ($log:expr, $kind:expr, $($arg:tt)*) => {
+        if let Some(line) = $log.format($kind, &format!($($arg)*)) {
+            status!($log, "{}", line);
+        }
+    };
+}
+
+// Print a label such as `USER:`, unless `--bare` is set.
+macro_rules! label {
+    ($log:expr, $($arg:tt)*) => {
+        print_line!($log, $crate::LineKind::Label, $($arg)*)
+    };
+}
+
+// Print an informational line, unless `--bare` is set.
+macro_rules! info {
+    ($log:expr, $($arg:tt)*) => {
+        print_line!($log, $crate::LineKind::Info, $($arg)*)
+    };
+}
+
+// Print a warning line, unless `--bare` is set.
+macro_rules! warn {
+    ($log:expr, $($arg:tt)*) => {
+        print_line!($log, $crate::LineKind::Warn, $($arg)*)
+    };
+}
+
+mod backend;
+mod capabilities;
+mod cli;
+mod conversation;
+mod error;
+mod generate;
+mod output;
+mod repl;
+mod rpc;
+mod session;
+mod sink;
+mod template;
+
+// Whether the model argument is a file path rather than the name of a
+// preloaded model.
+fn looks_like_path(model_name: &str) -> bool {
+    model_name.contains('/') || model_name.contains('\\') || model_name.ends_with(".gguf")
+}
+
+// Check that a model file can be accessed before loading it. A WebAssembly
+// program can only access the directories the runtime preopened with `--dir`,
+// otherwise the backend fails with an inscrutable error. Relative paths are
+// resolved where possible, so the path actually used can be reported.
+fn resolve_model_path(path: &str) -> Result<PathBuf, String> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => {
+            Ok(std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path)))
+        }
+        Ok(_) => Err(format!("The model path {} is not a file", path)),
+        Err(err) => {
+            let dir = Path::new(path)
+                .parent()
+                .map(|dir| dir.display().to_string())
+                .filter(|dir| !dir.is_empty())
+                .unwrap_or_else(|| String::from("."));
+            Err(format!(
+                "Failed to access the model file {}: {}. Make sure the file exists and its directory is preopened, WebAssembly programs can only access the directories the runtime preopened, e.g. with `wasmedge --dir {}:{} ...`",
+                path, err, dir, dir
+            ))
+        }
     }
-    if let Ok(val) = env::var("llama3") {
-        options["llama3"] = serde_json::from_str(val.as_str())
-            .expect("invalid value for llama3 option (true/false)");
-    } else {
-        options["llama3"] = serde_json::from_str("false").unwrap()
+}
+
+// A GBNF grammar which only accepts one of the choices, literally.
+fn choices_grammar(choices: &[String]) -> String {
+    let alternatives: Vec<String> = choices
+        .iter()
+        .map(|choice| {
+            let escaped = choice
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("\"{}\"", escaped)
+        })
+        .collect();
+    format!("root ::= {}", alternatives.join(" | "))
+}
+
+#[derive(Debug, PartialEq)]
+enum ChoiceMatch {
+    Choice(usize),
+    Tie(Vec<usize>),
+    NoMatch,
+}
+
+// Map the answer to one of the choices, compared case-insensitively. The
+// longest choice the answer starts with wins. A model that stopped early may
+// also have given a prefix of a choice, which counts if it's unambiguous.
+fn match_choice(output: &str, choices: &[String]) -> ChoiceMatch {
+    let answer = output.trim().to_lowercase();
+    let lowered: Vec<String> = choices.iter().map(|c| c.to_lowercase()).collect();
+    let mut candidates: Vec<usize> = (0..choices.len())
+        .filter(|&i| answer.starts_with(&lowered[i]))
+        .collect();
+    if let Some(longest) = candidates.iter().map(|&i| lowered[i].len()).max() {
+        candidates.retain(|&i| lowered[i].len() == longest);
+    } else if !answer.is_empty() {
+        candidates = (0..choices.len())
+            .filter(|&i| lowered[i].starts_with(&answer))
+            .collect();
     }
-    if let Ok(val) = env::var("ctx_size") {
-        options["ctx-size"] =
-            serde_json::from_str(val.as_str()).expect("invalid ctx-size value (unsigned integer")
-    } else {
-        options["ctx-size"] = serde_json::from_str("1024").unwrap()
+    match candidates.len() {
+        0 => ChoiceMatch::NoMatch,
+        1 => ChoiceMatch::Choice(candidates[0]),
+        _ => ChoiceMatch::Tie(candidates),
     }
+}
 
-    if let Ok(val) = env::var("n_gpu_layers") {
-        options["n-gpu-layers"] =
-            serde_json::from_str(val.as_str()).expect("invalid ngl value (unsigned integer")
-    } else {
-        options["n-gpu-layers"] = serde_json::from_str("0").unwrap()
+// Ask the model to pick one of the choices and print the picked one. Ties and
+// answers matching no choice are printed as `[tie]` and `[no match]`, so that
+// a batch keeps one line per prompt.
+fn answer_choice(
+    context: &mut dyn Backend,
+    mode: &mut ComputeMode,
+    config: &GenerationConfig,
+    prompt: &str,
+    choices: &[String],
+) -> Result<(ChoiceMatch, Generation), Error> {
+    let log = config.log;
+    let mut generation = run_prompt(
+        context,
+        mode,
+        config,
+        prompt,
+        "",
+        &mut StringSink::default(),
+    )?;
+    if let Some(err) = generation.error.take() {
+        return Err(err);
+    }
+    let choice = match_choice(&generation.output, choices);
+    match &choice {
+        ChoiceMatch::Choice(i) => println!("{}", choices[*i]),
+        ChoiceMatch::Tie(candidates) => {
+            let names: Vec<&str> = candidates.iter().map(|&i| choices[i].as_str()).collect();
+            println!("[tie]");
+            info!(
+                log,
+                "The answer matches {}: {:?}",
+                names.join(", "),
+                generation.output
+            );
+        }
+        ChoiceMatch::NoMatch => {
+            println!("[no match]");
+            info!(log, "The answer matches no choice: {:?}", generation.output);
+        }
+    }
+    Ok((choice, generation))
+}
+
+// How often `--watch` checks the modification time of the prompt file.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// What happened to a watched file between two polls.
+#[derive(Debug, PartialEq)]
+enum WatchEvent {
+    Unchanged,
+    Changed,
+    Deleted,
+}
+
+// Compare the modification times of two polls, `None` if the file didn't
+// exist. A file appearing counts as a change, so the first poll of an
+// existing file runs it right away.
+fn watch_event(previous: Option<SystemTime>, current: Option<SystemTime>) -> WatchEvent {
+    match (previous, current) {
+        (_, Some(current)) if previous != Some(current) => WatchEvent::Changed,
+        (Some(_), None) => WatchEvent::Deleted,
+        _ => WatchEvent::Unchanged,
     }
+}
 
-    options
+// Score a `--best-of` candidate: the number of words, minus the words starting
+// a word trigram seen before in the same answer, twice. Longer answers win,
+// unless they get there by repeating themselves.
+fn score_answer(text: &str) -> i64 {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let mut seen = std::collections::HashSet::new();
+    let repeated = words
+        .windows(3)
+        .filter(|trigram| !seen.insert(*trigram))
+        .count();
+    words.len() as i64 - 2 * repeated as i64
 }
 
-fn set_data_to_context(context: &mut GraphExecutionContext, data: Vec<u8>) -> Result<(), Error> {
-    context.set_input(0, TensorType::U8, &[1], &data)
+// The index of the best scoring answer, the first one on a tie.
+fn best_answer(answers: &[String]) -> usize {
+    let mut best = 0;
+    for (i, answer) in answers.iter().enumerate().skip(1) {
+        if score_answer(answer) > score_answer(&answers[best]) {
+            best = i;
+        }
+    }
+    best
 }
 
-#[allow(dead_code)]
-fn set_metadata_to_context(
-    context: &mut GraphExecutionContext,
-    data: Vec<u8>,
-) -> Result<(), Error> {
-    context.set_input(1, TensorType::U8, &[1], &data)
+// Ask the model which of two answers to the question is better. Returns `None`
+// when the verdict can't be told from the judge's answer.
+fn judge_answers(
+    context: &mut dyn Backend,
+    mode: &mut ComputeMode,
+    config: &GenerationConfig,
+    template: PromptTemplate,
+    question: &str,
+    answers: [&str; 2],
+) -> Result<Option<usize>, Error> {
+    let prompt = template.format_question(
+        SYSTEM_PROMPT,
+        &format!(
+            "Question: {}\n\nAnswer 1: {}\n\nAnswer 2: {}\n\nWhich answer is better? Reply with 1 or 2 only.",
+            question, answers[0], answers[1]
+        ),
+        true,
+    );
+    let generation = run_prompt(
+        context,
+        mode,
+        config,
+        &prompt,
+        "",
+        &mut StringSink::default(),
+    )?;
+    if let Some(err) = generation.error {
+        return Err(err);
+    }
+    let verdict = [String::from("1"), String::from("2")];
+    match match_choice(&generation.output, &verdict) {
+        ChoiceMatch::Choice(i) => Ok(Some(i)),
+        ChoiceMatch::Tie(_) | ChoiceMatch::NoMatch => Ok(None),
+    }
 }
 
-fn get_data_from_context(context: &GraphExecutionContext, index: usize, is_single: bool) -> String {
-    // Preserve for 4096 tokens with average token length 6
-    const MAX_OUTPUT_BUFFER_SIZE: usize = 4096 * 6;
-    let mut output_buffer = vec![0u8; MAX_OUTPUT_BUFFER_SIZE];
-    let mut output_size = if is_single {
-        context
-            .get_output_single(index, &mut output_buffer)
-            .expect("Failed to get single output")
+// Build the graph of a preloaded model, or of a model file. `build_from_cache`
+// expects the name of a preloaded model, a file path is loaded from the file
+// instead.
+fn load_graph(model_name: &str, options: &Value, log: Log) -> Result<Graph, ExampleError> {
+    let from_file = looks_like_path(model_name);
+    let builder = GraphBuilder::new(GraphEncoding::Ggml, ExecutionTarget::AUTO)
+        .config(serde_json::to_string(options).expect("Failed to serialize options"));
+    let result = if from_file {
+        info!(log,
+            "{} looks like a file path, loading the model from the file instead of a preloaded model.",
+            model_name
+        );
+        let path = resolve_model_path(model_name)
+            .map_err(|message| ExampleError::ModelLoad(message, None))?;
+        info!(log, "Loading the model from {}", path.display());
+        builder.build_from_files([path])
     } else {
-        context
-            .get_output(index, &mut output_buffer)
-            .expect("Failed to get output")
+        builder.build_from_cache(model_name)
     };
-    output_size = std::cmp::min(MAX_OUTPUT_BUFFER_SIZE, output_size);
-
-    return String::from_utf8_lossy(&output_buffer[..output_size]).to_string();
-}
-
-#[allow(dead_code)]
-fn get_output_from_context(context: &GraphExecutionContext) -> String {
-    get_data_from_context(context, 0, false)
-}
-
-fn get_single_output_from_context(context: &GraphExecutionContext) -> String {
-    get_data_from_context(context, 0, true)
-}
-
-#[allow(dead_code)]
-fn get_metadata_from_context(context: &GraphExecutionContext) -> Value {
-    serde_json::from_str(&get_data_from_context(context, 1, false)).expect("Failed to get metadata")
-}
-
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let model_name: &str = &args[1];
-
-    // Set options for the graph. Check our README for more details:
-    // https://github.com/second-state/WasmEdge-WASINN-examples/tree/master/wasmedge-ggml#parameters
-    let options = get_options_from_env();
-
-    // Create graph and initialize context.
-    let graph = GraphBuilder::new(GraphEncoding::Ggml, ExecutionTarget::AUTO)
-        .config(serde_json::to_string(&options).expect("Failed to serialize options"))
-        .build_from_cache(model_name)
-        .expect("Failed to build graph");
-    let mut context = graph
-        .init_execution_context()
-        .expect("Failed to init context");
-
-    // We also support setting the options via input tensor with index 1.
-    // Uncomment the line below to run the example, Check our README for more details.
-    // set_metadata_to_context(
-    //     &mut context,
-    //     serde_json::to_string(&options)
-    //         .expect("Failed to serialize options")
-    //         .as_bytes()
-    //         .to_vec(),
-    // )
-    // .expect("Failed to set metadata");
-
-    // If there is a third argument, use it as the prompt and enter non-interactive mode.
-    // This is mainly for the CI workflow.
-    if args.len() >= 3 {
-        let prompt = &args[2];
-        // Set the prompt.
-        println!("Prompt:\n{}", prompt);
-        let tensor_data = prompt.as_bytes().to_vec();
-        context
-            .set_input(0, TensorType::U8, &[1], &tensor_data)
-            .expect("Failed to set input");
-        println!("Response:");
-
-        // Get the number of input tokens and llama.cpp versions.
-        let input_metadata = get_metadata_from_context(&context);
-        println!("[INFO] llama_commit: {}", input_metadata["llama_commit"]);
-        println!(
-            "[INFO] llama_build_number: {}",
-            input_metadata["llama_build_number"]
-        );
-        println!(
-            "[INFO] Number of input tokens: {}",
-            input_metadata["input_tokens"]
-        );
+    result.map_err(|err| {
+        let hint = if from_file {
+            "the directory of the file needs to be preopened with --dir, or preload the model with --nn-preload default:GGML:AUTO:<path> and pass its name, default"
+        } else {
+            "the model name must be the name given to --nn-preload, e.g. default for --nn-preload default:GGML:AUTO:<path>"
+        };
+        ExampleError::ModelLoad(
+            format!("Failed to load model {}: {} ({})", model_name, err, hint),
+            Some(err),
+        )
+    })
+}
 
-        // Get the output.
-        let mut output = String::new();
-        loop {
-            match context.compute_single() {
-                Ok(_) => (),
-                Err(Error::BackendError(BackendError::EndOfSequence)) => {
-                    break;
+// Totals over the prompts of a `--batch-file`, for the summary at the end.
+#[derive(Default)]
+struct BatchStats {
+    input_tokens: u64,
+    output_tokens: u64,
+    elapsed: Duration,
+}
+
+impl BatchStats {
+    fn add(&mut self, generation: &Generation) {
+        self.input_tokens += generation.stats.prompt_tokens.unwrap_or(0);
+        self.output_tokens += generation.stats.completion_tokens;
+        self.elapsed += generation.stats.elapsed;
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} input tokens, {} output tokens, {:.2}s, {:.2} tokens/s",
+            self.input_tokens,
+            self.output_tokens,
+            self.elapsed.as_secs_f64(),
+            tokens_per_second(self.output_tokens, self.elapsed)
+        )
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().collect();
+    let json_errors = take_flag(&mut args, "--json-errors");
+    match run(args) {
+        Ok(code) => code,
+        Err(err) => report_error(err.kind(), &err.to_string(), err.detail(), json_errors),
+    }
+}
+
+// Run the mode picked by the flags, and unload the model whatever the outcome.
+// The one-shot and batch modes exit with the code of their answers.
+fn run(args: Vec<String>) -> Result<ExitCode, ExampleError> {
+    let mut setup = Setup::new(Cli::parse(args)?)?;
+    let result = if setup.cli.dry_run {
+        dry_run(&setup)
+    } else if setup.cli.stdio_jsonrpc {
+        serve_jsonrpc(&mut setup)
+    } else if let Some(path) = setup.cli.watch.clone() {
+        watch(&mut setup, &path)
+    } else if let Some(path) = setup.cli.batch_file.clone() {
+        run_batch(&mut setup, &path)
+    } else if setup.cli.args.len() >= 3 {
+        run_one_shot(&mut setup)
+    } else {
+        run_interactive(&mut setup)
+    };
+    if let Err(err) = setup.graph.unload() {
+        warn!(setup.cli.log, "Failed to unload the model: {}", err);
+    }
+    result
+}
+
+// The loaded model and what all the modes share, set up from the flags.
+struct Setup {
+    cli: Cli,
+    model_name: String,
+    graph: Graph,
+    context: Context,
+    mode: ComputeMode,
+    options: Value,
+    // The options given by the flags, which take precedence over a persona.
+    overrides: serde_json::Map<String, Value>,
+    default_template: PromptTemplate,
+    template: PromptTemplate,
+    language: &'static Language,
+    system_prompt: String,
+    // The few-shot examples of the persona, if any.
+    examples: Vec<Turn>,
+    // The estimated number of tokens a prompt may take, with `--reserve-tokens`.
+    prompt_limit: Option<u64>,
+    summary: SessionSummary,
+}
+
+impl Setup {
+    // Work out the options, the template and the system prompt from the flags,
+    // then load the model and check the options against it.
+    fn new(mut cli: Cli) -> Result<Setup, ExampleError> {
+        let log = cli.log;
+
+        // Set options for the graph. Check our README for more details:
+        // https://github.com/second-state/WasmEdge-WASINN-examples/tree/master/wasmedge-ggml#parameters
+        let mut options = get_options_from_env()?;
+        if let Some(choices) = &cli.choices {
+            options["grammar"] = json!(choices_grammar(choices));
+        }
+        for (key, value) in &cli.samplers {
+            options[*key] = json!(value);
+        }
+        let default_template = if options["llama3"] == json!(true) {
+            PromptTemplate::Llama3
+        } else {
+            PromptTemplate::Llama2
+        };
+        let persona = match cli.persona_name.take() {
+            Some(name) => {
+                let persona = Persona::load(&name).map_err(ExampleError::Usage)?;
+                persona.apply_options(&mut options);
+                Some(persona)
+            }
+            None => None,
+        };
+        // The sampling flags take precedence over the options of the persona, and
+        // `--options-json` over everything else.
+        let mut overrides = json!({});
+        if cli.deterministic {
+            apply_deterministic(&mut overrides, cli.seed.unwrap_or(DETERMINISTIC_SEED));
+        } else if let Some(seed) = cli.seed {
+            overrides["seed"] = json!(seed);
+        }
+        if let Some(n_predict) = cli.n_predict {
+            overrides["n-predict"] = json!(n_predict);
+        }
+        if cli.ignore_eos {
+            overrides["ignore-eos"] = json!(true);
+        }
+        if let Some(n_probs) = cli.n_probs {
+            overrides["n-probs"] = json!(n_probs);
+        }
+        if cli.config.context_shift {
+            overrides["context-shift"] = json!(true);
+        }
+        if let Some(n_keep) = cli.n_keep {
+            overrides["n-keep"] = json!(n_keep);
+        }
+        if !cli.logit_biases.is_empty() {
+            set_logit_bias(&mut overrides, &cli.logit_biases);
+        }
+        for (key, value) in cli.options_json.take().into_iter().flatten() {
+            overrides[key.as_str()] = value;
+        }
+        let overrides = overrides.as_object().cloned().unwrap_or_default();
+        merge_options(&mut options, &overrides);
+        cli.config.n_predict = options["n-predict"].as_u64();
+        if cli.ignore_eos {
+            check_ignore_eos_bounds(
+                options["n-predict"].as_u64(),
+                !cli.config.stop_sequences.is_empty(),
+                cli.config.turn_timeout.is_some(),
+            )
+            .map_err(ExampleError::Usage)?;
+        }
+        if dynatemp_without_temp(&options) {
+            warn!(
+                log,
+                "--dynatemp-range has no effect with a temperature of 0, e.g. with --deterministic."
+            );
+        }
+        // The model family takes precedence over the template of the persona.
+        let template = if cli.raw {
+            PromptTemplate::Raw
+        } else {
+            cli.model_family
+                .or_else(|| persona.as_ref().and_then(|persona| persona.template))
+                .unwrap_or(default_template)
+        };
+        if let Some(family) = cli.model_family {
+            for token in family.stop_tokens() {
+                if !cli.config.stop_sequences.iter().any(|stop| stop == token) {
+                    cli.config.stop_sequences.push(token.to_string());
                 }
-                Err(Error::BackendError(BackendError::ContextFull)) => {
-                    println!("\n[INFO] Context full, we'll reset the context and continue.");
+            }
+        }
+        cli.config.primer = template.primer();
+        let language = language(cli.lang.as_deref().unwrap_or("en"), log);
+        // `--system-prompt` takes precedence over a persona, and a persona over `--lang`.
+        let system_prompt = cli.system_prompt_override.clone().unwrap_or_else(|| {
+            persona
+                .as_ref()
+                .map_or(language.system_prompt, |persona| &persona.system_prompt)
+                .to_string()
+        });
+        // The system prompt has to survive shifting the context, so by default the
+        // tokens it takes up are kept.
+        if cli.config.context_shift && cli.n_keep.is_none() {
+            let n_keep = estimate_tokens(&template.format_question(&system_prompt, "", true));
+            options["n-keep"] = json!(n_keep);
+            info!(log,
+                "Keeping the first {} tokens when the context is shifted, the estimated size of the system prompt.",
+                n_keep
+            );
+        }
+        if !cli.roles.is_empty() && !template.has_named_turns() {
+            info!(log,
+                "The {:?} template has fixed roles, --user-name and --assistant-name only change the labels.",
+                template
+            );
+        }
+        let mut mode = if cli.no_stream {
+            ComputeMode::Batch
+        } else {
+            ComputeMode::Probe
+        };
+
+        // Create graph and initialize context.
+        // This happens before any input is read, so a model that fails to load is
+        // reported immediately instead of after the user typed the first question.
+        // The `--model` candidates are tried in order until one loads.
+        let candidates = if cli.model_candidates.is_empty() {
+            vec![ModelCandidate {
+                name: cli.args[1].clone(),
+                options: serde_json::Map::new(),
+            }]
+        } else {
+            std::mem::take(&mut cli.model_candidates)
+        };
+        let mut failures = Vec::new();
+        let mut loaded = None;
+        for candidate in &candidates {
+            let mut candidate_options = options.clone();
+            merge_options(&mut candidate_options, &candidate.options);
+            match load_graph(&candidate.name, &candidate_options, log) {
+                Ok(graph) => {
+                    loaded = Some((candidate, candidate_options, graph));
                     break;
                 }
-                Err(Error::BackendError(BackendError::PromptTooLong)) => {
-                    println!("\n[INFO] Prompt too long, we'll reset the context and continue.");
-                    break;
+                Err(err) if candidates.len() > 1 => {
+                    warn!(log, "{}", err);
+                    failures.push(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let Some((candidate, candidate_options, graph)) = loaded else {
+            return Err(ExampleError::ModelLoad(
+                format!(
+                    "None of the {} models could be loaded:\n{}",
+                    candidates.len(),
+                    failures
+                        .iter()
+                        .map(|err| format!("  {}", err))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ),
+                None,
+            ));
+        };
+        if candidates.len() > 1 {
+            info!(log, "Using the model {}", candidate.name);
+        }
+        let model_name = candidate.name.clone();
+        let from_file = looks_like_path(&model_name);
+        options = candidate_options;
+        // The estimate is rough, so prompts are only checked against the context
+        // size with `--reserve-tokens`. Otherwise the backend has the final say.
+        let prompt_limit = cli
+            .reserve_tokens
+            .map(|reserve| prompt_token_limit(options["ctx-size"].as_u64().unwrap_or(0), reserve));
+        let mut context = match graph.init_execution_context() {
+            Ok(inner) => Context {
+                inner,
+                max_output_bytes: cli.max_output_bytes,
+            },
+            Err(err) => {
+                return Err(ExampleError::ModelLoad(
+                    format!("Failed to init context for model {}: {}", model_name, err),
+                    Some(err),
+                ))
+            }
+        };
+        // A model loaded from a file doesn't get the options with the graph, they
+        // are set through the metadata tensor instead.
+        if from_file {
+            if let Err(err) = set_metadata_to_context(
+                &mut context,
+                serde_json::to_string(&options)
+                    .expect("Failed to serialize options")
+                    .as_bytes()
+                    .to_vec(),
+            ) {
+                return Err(ExampleError::ModelLoad(
+                    format!(
+                        "Failed to set the options for model {}: {}",
+                        model_name, err
+                    ),
+                    Some(err),
+                ));
+            }
+        }
+
+        // Validate ctx-size against the native context size of the model.
+        // Older plugins don't report `n_ctx_train`, skip the check silently then.
+        let n_ctx_train = try_get_metadata_from_context(&context)
+            .and_then(|metadata| metadata["n_ctx_train"].as_u64());
+        if let Some(message) =
+            check_ctx_size(options["ctx-size"].as_u64().unwrap_or(0), n_ctx_train)
+        {
+            if cli.strict_ctx {
+                return Err(ExampleError::Config(
+                    message,
+                    json!({ "ctx_size": options["ctx-size"], "n_ctx_train": n_ctx_train }),
+                ));
+            }
+            warn!(log, "{}", message);
+        }
+
+        if cli.config.memory_stats {
+            match try_get_metadata_from_context(&context)
+                .as_ref()
+                .and_then(format_memory_stats)
+            {
+                Some(stats) => info!(log, "{}", stats),
+                None => {
+                    warn!(log, "--memory-stats is not supported by the backend, it doesn't report the memory usage in the metadata.");
+                    cli.config.memory_stats = false;
+                }
+            }
+        }
+
+        let plugin_metadata = try_get_metadata_from_context(&context);
+        let capabilities = Capabilities::from_metadata(plugin_metadata.as_ref());
+        let summary = SessionSummary::new(plugin_metadata.as_ref());
+        if cli.strict_template {
+            match plugin_metadata
+                .as_ref()
+                .and_then(|metadata| metadata["chat_template"].as_str())
+            {
+                Some(chat_template) => {
+                    if let Some(declared) = template_mismatch(template, chat_template) {
+                        let declared = declared.name().unwrap_or_default();
+                        return Err(ExampleError::Usage(format!(
+                            "The {} template doesn't match the chat template of the model, which is {}, use --model-family {}",
+                            template.name().unwrap_or("raw"),
+                            declared,
+                            declared
+                        )));
+                    }
+                }
+                None => warn!(log,
+                    "--strict-template can't check the template, the backend doesn't report the chat template of the model."
+                ),
+            }
+        }
+        info!(log, "Plugin capabilities: {}", capabilities.describe());
+        let unsupported = |feature: &str| {
+            ExampleError::Config(
+                capabilities.unsupported(feature),
+                json!({ "llama_build_number": capabilities.build_number }),
+            )
+        };
+        if let Some((key, _)) = cli
+            .samplers
+            .iter()
+            .find(|(key, _)| !capabilities.supports_sampler(key))
+        {
+            return Err(unsupported(&format!("--{}", key)));
+        }
+        if !capabilities.supports_grammar && options["grammar"].is_string() {
+            return Err(unsupported(
+                "The grammar of --choices or the grammar option",
+            ));
+        }
+        if !capabilities.supports_embeddings && options["embedding"] == json!(true) {
+            return Err(unsupported("The embedding option"));
+        }
+        if !capabilities.supports_streaming {
+            let config = &cli.config;
+            if config.logprobs || config.n_probs.is_some() || config.token_timings {
+                return Err(unsupported(
+                    "Streaming, which --logprobs, --n-probs and --token-timings need,",
+                ));
+            }
+            if mode == ComputeMode::Probe {
+                info!(
+                    log,
+                    "{}, using non-streaming mode.",
+                    capabilities.unsupported("Streaming")
+                );
+                mode = ComputeMode::Batch;
+            }
+        }
+
+        let mut setup = Setup {
+            cli,
+            model_name,
+            graph,
+            context,
+            mode,
+            options,
+            overrides,
+            default_template,
+            template,
+            language,
+            system_prompt,
+            examples: persona.map_or_else(Vec::new, |persona| persona.examples),
+            prompt_limit,
+            summary,
+        };
+        // Unlike the prompt argument, the prompt of an input file is plain text,
+        // which is formatted with the template. `--choices` formats it anyway.
+        if setup.cli.input_file.is_some()
+            && setup.cli.choices.is_none()
+            && setup.cli.args.len() >= 3
+        {
+            setup.cli.args[2] = setup.question_prompt(&setup.cli.args[2]);
+        }
+        Ok(setup)
+    }
+
+    // The prompt of a new conversation, with the `--prompt-prefix` applied to the question.
+    fn question_prompt(&self, question: &str) -> String {
+        self.template.rename_roles(
+            self.template.format_question(
+                &self.system_prompt,
+                &apply_prompt_prefix(self.cli.prompt_prefix.as_deref(), question),
+                true,
+            ),
+            &self.cli.roles,
+        )
+    }
+
+    // Set the options on the context, the plugin applies them to the next generation.
+    fn set_options(&mut self) -> Result<(), Error> {
+        set_metadata_to_context(
+            &mut self.context,
+            serde_json::to_string(&self.options)
+                .expect("Failed to serialize options")
+                .into_bytes(),
+        )
+    }
+}
+
+// With `--dry-run`, show how the example is set up without generating anything.
+fn dry_run(setup: &Setup) -> Result<ExitCode, ExampleError> {
+    let metadata = try_get_metadata_from_context(&setup.context).unwrap_or(Value::Null);
+    println!("Metadata:\n{:#}", metadata);
+    println!("Options:\n{:#}", setup.options);
+    println!("Template: {:?}", setup.template);
+    println!(
+        "First prompt:\n{}",
+        setup.template.rename_roles(
+            setup
+                .template
+                .format_question(&setup.system_prompt, "<question>", true),
+            &setup.cli.roles
+        )
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+// With `--stdio-jsonrpc`, serve requests from stdin until it's closed.
+fn serve_jsonrpc(setup: &mut Setup) -> Result<ExitCode, ExampleError> {
+    let mut conversation = Conversation::new(
+        setup.template,
+        setup.cli.roles.clone(),
+        setup.system_prompt.clone(),
+        setup.examples.clone(),
+        setup.cli.log,
+    );
+    let mut session_stats = SessionStats::default();
+    for line in io::stdin().lines() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match parse_rpc_request(&line) {
+            Ok(request) => request,
+            Err(response) => {
+                rpc_emit(&response)?;
+                continue;
+            }
+        };
+        let id = request.id;
+        let response = match request.method {
+            RpcMethod::Commands(commands) => {
+                for command in commands {
+                    match command {
+                        ReplCommand::Reset => {
+                            conversation.clear();
+                            session_stats = SessionStats::default();
+                        }
+                        ReplCommand::LogitBias(biases) => {
+                            set_logit_bias(&mut setup.options, &biases)
+                        }
+                        ReplCommand::ClearLogitBias => setup.options["logit-bias"] = json!({}),
+                        command => {
+                            if let Some((key, value)) = command.option() {
+                                setup.options[key] = value;
+                            }
+                        }
+                    }
+                }
+                match setup.set_options() {
+                    Ok(()) => json!({ "id": id, "result": "ok" }),
+                    Err(err) => rpc_error(&id, &err.to_string(), &line),
+                }
+            }
+            RpcMethod::Stats => json!({
+                "id": id,
+                "result": {
+                    "turns": conversation.turns().len(),
+                    "input_tokens": session_stats.input_tokens,
+                    "output_tokens": session_stats.tokens,
+                    "tokens_per_second": session_stats.tokens_per_second(),
+                }
+            }),
+            RpcMethod::Chat { message, stream } => {
+                let message = apply_prompt_prefix(setup.cli.prompt_prefix.as_deref(), &message);
+                // The same guards and prefill as an interactive question.
+                let Some((message, mut prompt)) = fit_prompt(setup, &mut conversation, message)
+                else {
+                    rpc_emit(&rpc_error(
+                        &id,
+                        "The prompt is too long, please shorten it.",
+                        &line,
+                    ))?;
+                    continue;
+                };
+                let prefill = setup.cli.prefill.clone().unwrap_or_default();
+                if !prefill.is_empty() {
+                    prompt += &setup.template.format_prefill(&prefill);
+                }
+                let mut partials = RpcPartialSink { id: id.clone() };
+                let mut collected = StringSink::default();
+                let out: &mut dyn TokenSink = if stream {
+                    &mut partials
+                } else {
+                    &mut collected
+                };
+                match run_prompt(
+                    &mut setup.context,
+                    &mut setup.mode,
+                    &setup.cli.config,
+                    &prompt,
+                    &prefill,
+                    out,
+                ) {
+                    Ok(Generation {
+                        error: Some(err), ..
+                    })
+                    | Err(err) => rpc_error(&id, &err.to_string(), &line),
+                    Ok(generation) => {
+                        session_stats.add(&generation);
+                        setup.summary.add(&generation);
+                        let answer = prefill + &generation.output;
+                        if setup.cli.stateless
+                            || matches!(
+                                generation.reason,
+                                StopReason::ContextFull | StopReason::PromptTooLong
+                            )
+                        {
+                            conversation.clear();
+                        } else {
+                            conversation.push(
+                                message,
+                                setup.template.history_answer(&answer),
+                                generation.stats.completion_tokens,
+                            );
+                        }
+                        let mut result = generation.stats.fields();
+                        result.insert(String::from("answer"), json!(answer));
+                        result.insert(String::from("reason"), json!(generation.reason.name()));
+                        json!({ "id": id, "result": result })
+                    }
+                }
+            }
+        };
+        rpc_emit(&response)?;
+    }
+    if setup.cli.final_metadata {
+        println!("{}", setup.summary.to_json(&setup.options));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// With `--watch`, the file is run as a prompt again every time it's saved,
+// each run as a new conversation.
+fn watch(setup: &mut Setup, path: &str) -> Result<ExitCode, ExampleError> {
+    let log = setup.cli.log;
+    if setup.cli.choices.is_some() {
+        return Err(ExampleError::Usage(String::from(
+            "--watch can't be combined with --choices",
+        )));
+    }
+    let mut last_modified = None;
+    let mut runs = 0;
+    let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    if modified(path).is_none() {
+        info!(log, "Waiting for {} to be created.", path);
+    }
+    loop {
+        let current = modified(path);
+        match watch_event(last_modified, current) {
+            WatchEvent::Unchanged => (),
+            WatchEvent::Deleted => {
+                info!(
+                    log,
+                    "{} was deleted, waiting for it to be created again.", path
+                )
+            }
+            WatchEvent::Changed => {
+                runs += 1;
+                status!(log, "===== run {}: {} =====", runs, path);
+                // Every run is a new conversation with just the file as the question.
+                match read_input_file(path, setup.cli.input_encoding) {
+                    Ok(question) => {
+                        let mut prompt = setup.question_prompt(&question);
+                        if let Some(prefill) = &setup.cli.prefill {
+                            prompt += &setup.template.format_prefill(prefill);
+                        }
+                        match run_prompt(
+                            &mut setup.context,
+                            &mut setup.mode,
+                            &setup.cli.config,
+                            &prompt,
+                            setup.cli.prefill.as_deref().unwrap_or_default(),
+                            setup.cli.sink.as_mut(),
+                        ) {
+                            Ok(Generation {
+                                error: Some(err), ..
+                            })
+                            | Err(err) => status!(log, "[ERROR] {}", err),
+                            Ok(_) => (),
+                        }
+                    }
+                    Err(message) => status!(log, "[ERROR] {}", message),
+                }
+            }
+        }
+        last_modified = current;
+        std::thread::sleep(WATCH_POLL_INTERVAL);
+    }
+}
+
+// With `--batch-file`, every prompt of the file is run as a separate one-shot
+// prompt without any history. The exit code is the one of the first prompt
+// that didn't finish normally.
+fn run_batch(setup: &mut Setup, path: &str) -> Result<ExitCode, ExampleError> {
+    let log = setup.cli.log;
+    let prompts = read_batch_file(path, setup.cli.input_encoding).map_err(ExampleError::Usage)?;
+    let mut counts = vec![0; setup.cli.choices.as_ref().map_or(0, Vec::len)];
+    let (mut ties, mut no_matches, mut failures) = (0, 0, 0);
+    let mut stats = BatchStats::default();
+    let mut exit_code = 0;
+    for prompt in &prompts {
+        if let Some(choices) = &setup.cli.choices {
+            let prompt = setup.question_prompt(prompt);
+            match answer_choice(
+                &mut setup.context,
+                &mut setup.mode,
+                &setup.cli.config,
+                &prompt,
+                choices,
+            ) {
+                Ok((choice, generation)) => {
+                    stats.add(&generation);
+                    match choice {
+                        ChoiceMatch::Choice(i) => counts[i] += 1,
+                        ChoiceMatch::Tie(_) => ties += 1,
+                        ChoiceMatch::NoMatch => no_matches += 1,
+                    }
                 }
                 Err(err) => {
-                    println!("\n[ERROR] {}", err);
-                    std::process::exit(1);
+                    status!(log, "[ERROR] {}", err);
+                    failures += 1;
+                    if exit_code == 0 {
+                        exit_code = StopReason::Error.exit_code();
+                    }
                 }
             }
-            // Retrieve the single output token and print it.
-            let token = get_single_output_from_context(&context);
-            print!("{}", token);
-            io::stdout().flush().unwrap();
-            output += &token;
-        }
-        println!();
-
-        // Retrieve the output metadata.
-        let metadata = get_metadata_from_context(&context);
-        println!(
-            "[INFO] Number of input tokens: {}",
-            metadata["input_tokens"]
+            continue;
+        }
+        if !setup.cli.count_only {
+            label!(log, "Prompt:\n{}", prompt);
+            label!(log, "Response:");
+        }
+        let result = if setup.cli.count_only {
+            run_prompt(
+                &mut setup.context,
+                &mut setup.mode,
+                &setup.cli.config,
+                prompt,
+                "",
+                &mut StringSink::default(),
+            )
+        } else {
+            run_prompt(
+                &mut setup.context,
+                &mut setup.mode,
+                &setup.cli.config,
+                prompt,
+                "",
+                setup.cli.sink.as_mut(),
+            )
+        };
+        if let Ok(generation) = &result {
+            stats.add(generation);
+        }
+        let reason = match result {
+            Ok(Generation {
+                error: None,
+                reason,
+                ..
+            }) => reason,
+            Ok(Generation {
+                error: Some(err), ..
+            })
+            | Err(err) => {
+                status!(log, "[ERROR] {}", err);
+                failures += 1;
+                StopReason::Error
+            }
+        };
+        if exit_code == 0 {
+            exit_code = reason.exit_code();
+        }
+    }
+    // The summary goes to stderr, so stdout keeps exactly one answer per prompt.
+    eprintln!(
+        "[INFO] {} prompts, {} failed, {}",
+        prompts.len(),
+        failures,
+        stats.summary()
+    );
+    if let Some(choices) = &setup.cli.choices {
+        for (choice, count) in choices.iter().zip(&counts) {
+            eprintln!("[INFO] {}: {}", choice, count);
+        }
+        eprintln!("[INFO] [tie]: {}", ties);
+        eprintln!("[INFO] [no match]: {}", no_matches);
+    }
+    Ok(ExitCode::from(exit_code))
+}
+
+// With a prompt argument, answer it without any history and exit with the
+// code of the answer. This is mainly for the CI workflow.
+fn run_one_shot(setup: &mut Setup) -> Result<ExitCode, ExampleError> {
+    let log = setup.cli.log;
+    let mut prompt = setup.cli.args[2].clone();
+    let estimate = estimate_tokens(&prompt);
+    if let Some(prompt_limit) = setup.prompt_limit.filter(|&limit| estimate > limit) {
+        if !setup.cli.truncate_long_prompts {
+            return Err(ExampleError::PromptTooLong(
+                format!(
+                    "The prompt is estimated at {} tokens, the limit is {} tokens",
+                    estimate, prompt_limit
+                ),
+                json!({ "estimated_tokens": estimate, "limit": prompt_limit }),
+            ));
+        }
+        info!(
+            log,
+            "The prompt is estimated at {} tokens, the limit is {} tokens, truncating it.",
+            estimate,
+            prompt_limit
+        );
+        prompt = keep_tail(&prompt, prompt_limit as usize * BYTES_PER_TOKEN).to_string();
+    }
+    if let Some(excess) = prompt_chars_excess(&prompt, setup.cli.max_prompt_chars) {
+        let chars = prompt.chars().count();
+        if !setup.cli.truncate_long_prompts {
+            return Err(ExampleError::PromptTooLong(
+                format!(
+                    "The prompt has {} characters, the limit is {} characters",
+                    chars,
+                    chars - excess
+                ),
+                json!({ "chars": chars, "limit": chars - excess }),
+            ));
+        }
+        info!(
+            log,
+            "The prompt has {} characters, the limit is {} characters, truncating it.",
+            chars,
+            chars - excess
         );
-        println!(
-            "[INFO] Number of output tokens: {}",
-            metadata["output_tokens"]
+        prompt = keep_tail_chars(&prompt, chars - excess).to_string();
+    }
+    if let Some(choices) = &setup.cli.choices {
+        let prompt = setup.template.rename_roles(
+            setup
+                .template
+                .format_question(&setup.system_prompt, &prompt, true),
+            &setup.cli.roles,
         );
-        std::process::exit(0);
+        answer_choice(
+            &mut setup.context,
+            &mut setup.mode,
+            &setup.cli.config,
+            &prompt,
+            choices,
+        )
+        .map_err(|err| {
+            ExampleError::backend(format!("Failed to generate the answer: {}", err), err)
+        })?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let question = prompt.clone();
+    if let Some(forced) = setup
+        .cli
+        .continue_answer
+        .as_ref()
+        .or(setup.cli.prefill.as_ref())
+    {
+        prompt += &setup.template.format_prefill(forced);
+    }
+    if setup.cli.print_prompt {
+        println!("{}", show_prompt(&prompt, setup.template));
+        return Ok(ExitCode::SUCCESS);
+    }
+    // Set the prompt.
+    label!(log, "Prompt:\n{}", prompt);
+    if setup.cli.best_of > 1 {
+        return best_of(setup, &question, &prompt);
+    }
+    if let Err(err) = set_data_to_context(&mut setup.context, prompt.as_bytes().to_vec()) {
+        return Err(ExampleError::backend(
+            format!("Failed to set input: {}", err),
+            err,
+        ));
     }
+    label!(log, "Response:");
 
-    let mut saved_prompt = String::new();
-    let system_prompt = String::from("You are a helpful, respectful and honest assistant. Always answer as short as possible, while being safe." );
+    // Get the number of input tokens and llama.cpp versions.
+    let input_metadata = get_metadata_from_context(&setup.context)?;
+    info!(log, "llama_commit: {}", input_metadata["llama_commit"]);
+    info!(
+        log,
+        "llama_build_number: {}", input_metadata["llama_build_number"]
+    );
+    info!(
+        log,
+        "Number of input tokens: {}", input_metadata["input_tokens"]
+    );
 
+    // Get the output. Only the continuation of a continued answer is printed.
+    let shown_prefill = match setup.cli.continue_answer {
+        Some(_) => "",
+        None => setup.cli.prefill.as_deref().unwrap_or_default(),
+    };
+    let generation = generate(
+        &mut setup.context,
+        &mut setup.mode,
+        &setup.cli.config,
+        shown_prefill,
+        setup.cli.sink.as_mut(),
+    )
+    .map_err(|err| ExampleError::backend(format!("Failed to write the answer: {}", err), err))?;
+    if let Some(err) = generation.error {
+        return Err(ExampleError::backend(
+            format!("Failed to generate the answer: {}", err),
+            err,
+        ));
+    }
+    if generation.reason == StopReason::PromptTooLong {
+        return Err(ExampleError::PromptTooLong(
+            String::from("The prompt is too long for the context"),
+            json!({ "ctx_size": setup.options["ctx-size"] }),
+        ));
+    }
+
+    // Retrieve the output metadata.
+    let metadata = get_metadata_from_context(&setup.context)?;
+    info!(log, "Number of input tokens: {}", metadata["input_tokens"]);
+    info!(
+        log,
+        "Number of output tokens: {}", metadata["output_tokens"]
+    );
+    Ok(ExitCode::from(generation.reason.exit_code()))
+}
+
+// With `--best-of`, generate the candidates with different seeds and print
+// the best one.
+fn best_of(setup: &mut Setup, question: &str, prompt: &str) -> Result<ExitCode, ExampleError> {
+    let log = setup.cli.log;
+    let prefill = setup.cli.prefill.clone().unwrap_or_default();
+    let seed = setup.options["seed"].as_u64().unwrap_or(0);
+    let mut answers = Vec::new();
+    // The candidates as they would have been streamed.
+    let mut rendered = Vec::new();
+    for i in 0..setup.cli.best_of {
+        setup.options["seed"] = json!(seed + i);
+        let result = setup
+            .set_options()
+            .and_then(|_| {
+                let mut collected = StringSink::default();
+                let generation = run_prompt(
+                    &mut setup.context,
+                    &mut setup.mode,
+                    &setup.cli.config,
+                    prompt,
+                    &prefill,
+                    &mut collected,
+                )?;
+                rendered.push(collected.into_string());
+                Ok(generation)
+            })
+            .and_then(|generation| match generation.error {
+                Some(err) => Err(err),
+                None => Ok(generation),
+            });
+        let generation = result.map_err(|err| {
+            ExampleError::backend(format!("Failed to generate the answer: {}", err), err)
+        })?;
+        let answer = prefill.clone() + &generation.output;
+        info!(
+            log,
+            "Candidate {}: {} tokens in {:.2}s ({:.1} tok/s), score {}",
+            i + 1,
+            generation.stats.completion_tokens,
+            generation.stats.elapsed.as_secs_f64(),
+            tokens_per_second(generation.stats.completion_tokens, generation.stats.elapsed),
+            score_answer(&answer)
+        );
+        answers.push(answer);
+    }
+    let mut winner = best_answer(&answers);
+    if setup.cli.judge {
+        // Knockout: the current winner meets every other candidate.
+        winner = 0;
+        for i in 1..answers.len() {
+            let pair = [answers[winner].as_str(), answers[i].as_str()];
+            let verdict = judge_answers(
+                &mut setup.context,
+                &mut setup.mode,
+                &setup.cli.config,
+                setup.template,
+                question,
+                pair,
+            )
+            .map_err(|err| {
+                ExampleError::backend(format!("Failed to judge the answers: {}", err), err)
+            })?;
+            winner = match verdict {
+                Some(0) => winner,
+                Some(_) => i,
+                // Fall back to the heuristic when the verdict is unclear.
+                None if score_answer(&answers[i]) > score_answer(&answers[winner]) => i,
+                None => winner,
+            };
+        }
+    }
+    label!(log, "Response:");
+    // The candidates are rendered with the answer prefix and suffix already.
+    let sink = setup.cli.sink.as_mut();
+    for (i, answer) in rendered.iter().enumerate() {
+        if setup.cli.show_all {
+            let mark = if i == winner { " (winner)" } else { "" };
+            sink.write_frame(&format!("[candidate {}{}]\n", i + 1, mark))?;
+        } else if i != winner {
+            continue;
+        }
+        sink.write_str(answer)?;
+    }
+    sink.finish()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+// The state of the interactive mode which is carried from turn to turn.
+struct Repl {
+    conversation: Conversation,
+    session_stats: SessionStats,
+    error_tracker: ErrorTracker,
+    // Whether the options have to be set again before the next generation.
+    options_changed: bool,
+    // The prefill of the next answer only, set with `/prefill`.
+    next_prefill: Option<String>,
+    // Whether the conversation is saved to the session file when the session
+    // ends. Nothing is carried over in stateless mode, so there is nothing to save.
+    autosave: bool,
+}
+
+impl Repl {
+    fn save(&self, log: Log) {
+        if self.autosave {
+            match save_session(SESSION_FILE, self.conversation.turns()) {
+                Ok(()) => info!(log, "Saved the conversation to {}.", SESSION_FILE),
+                Err(message) => warn!(log, "{}", message),
+            }
+        }
+    }
+}
+
+// Without a prompt argument, chat with the model until the input is closed.
+fn run_interactive(setup: &mut Setup) -> Result<ExitCode, ExampleError> {
+    let log = setup.cli.log;
+    info!(
+        log,
+        "model {} loaded (ctx-size {})", setup.model_name, setup.options["ctx-size"]
+    );
+
+    let mut repl = Repl {
+        conversation: Conversation::new(
+            setup.template,
+            setup.cli.roles.clone(),
+            setup.system_prompt.clone(),
+            std::mem::take(&mut setup.examples),
+            log,
+        ),
+        session_stats: SessionStats::default(),
+        error_tracker: ErrorTracker::default(),
+        options_changed: false,
+        next_prefill: None,
+        autosave: !setup.cli.no_autosave && !setup.cli.stateless,
+    };
+    if setup.cli.resume {
+        match load_session(SESSION_FILE) {
+            Ok(Some(turns)) => {
+                info!(log, "Resumed {} turns from {}.", turns.len(), SESSION_FILE);
+                if let Some(recap) = session_recap(&turns) {
+                    for line in recap.lines() {
+                        info!(log, "{}", line);
+                    }
+                }
+                repl.conversation.restore(turns);
+            }
+            Ok(None) => info!(log, "There is no session to resume, starting a new one."),
+            Err(message) => warn!(log, "{}, starting a new session.", message),
+        }
+    }
+    if repl.autosave {
+        info!(
+            log,
+            "The conversation will be saved to {} when the session ends, --no-autosave turns this off.",
+            SESSION_FILE
+        );
+    }
+    // The conversation is saved however the session ends, also with an error.
+    let result = chat(setup, &mut repl);
+    repl.save(log);
+    if setup.cli.final_metadata {
+        println!("{}", setup.summary.to_json(&setup.options));
+    }
+    result.map(|()| ExitCode::SUCCESS)
+}
+
+// Read the turns of the interactive mode and answer them, until the input is
+// closed or the session time limit is reached.
+fn chat(setup: &mut Setup, repl: &mut Repl) -> Result<(), ExampleError> {
+    let log = setup.cli.log;
+    let session_start = Instant::now();
     loop {
-        println!("USER:");
-        let input = read_input();
-        if saved_prompt.is_empty() {
-            if options["llama3"].as_bool().unwrap() {
-                saved_prompt = format!(
-                    "<|start_header_id|>system<|end_header_id|>\n\n{}<|eot_id|>\n<|start_header_id|>user<|end_header_id|>\n\n{}<|eot_id|>\n<|start_header_id|>assistant<|end_header_id|>\n\n",
-                    system_prompt, input
-                );
-            } else {
-                saved_prompt = format!(
-                    "[INST] <<SYS>> {} <</SYS>> {} [/INST]",
-                    system_prompt, input
-                );
+        // The session only ends between turns, never in the middle of an answer.
+        if session_expired(session_start.elapsed(), setup.cli.max_session_duration) {
+            info!(
+                log,
+                "The session time limit is reached, thanks for chatting. Goodbye!"
+            );
+            break;
+        }
+        label!(log, "{}", setup.cli.roles.user_label(setup.language));
+        // The message given with `--first` is handled exactly as if it was typed.
+        let mut input = match setup.cli.first_message.take() {
+            Some(message) => {
+                label!(log, "{}", message);
+                message
+            }
+            None => match read_input(setup.cli.allow_empty_turn)? {
+                Some(input) => input,
+                None => break,
+            },
+        };
+        // The answer `/continue` resumes.
+        let mut continued = None;
+        if let Some(command) = parse_repl_command(&input) {
+            let Some((question, answer)) = run_command(setup, repl, command) else {
+                continue;
+            };
+            input = question;
+            continued = Some(answer);
+        }
+        // An empty turn continues the conversation, so it needs one.
+        if input.is_empty() && repl.conversation.history.len() <= repl.conversation.examples.len() {
+            info!(
+                log,
+                "There is no conversation to continue yet, please type a message."
+            );
+            continue;
+        }
+        // A continued turn already has the prefix, an empty turn gets none.
+        let question = match continued {
+            Some(_) => input,
+            None if input.is_empty() => input,
+            None => apply_prompt_prefix(setup.cli.prompt_prefix.as_deref(), &input),
+        };
+        let Some((question, mut prompt)) = fit_prompt(setup, &mut repl.conversation, question)
+        else {
+            continue;
+        };
+
+        // Force the answer to start with the prefill text, if any. A continued
+        // answer is re-submitted the same way, but isn't printed again.
+        let prefill = repl
+            .next_prefill
+            .take()
+            .or_else(|| setup.cli.prefill.clone());
+        if let Some(forced) = continued.as_ref().or(prefill.as_ref()) {
+            prompt += &setup.template.format_prefill(forced);
+        }
+
+        // Apply the options changed by REPL commands before the next generation.
+        if repl.options_changed {
+            setup.set_options().map_err(|err| {
+                ExampleError::backend(format!("Failed to set the options: {}", err), err)
+            })?;
+            repl.options_changed = false;
+        }
+
+        // Set prompt to the input tensor. A failure counts as a failed turn, the
+        // turn taken back by `/continue` is put back as it was.
+        if let Err(err) = set_data_to_context(&mut setup.context, prompt.as_bytes().to_vec()) {
+            status!(log, "[ERROR] Failed to set input: {}", err);
+            if let Some(answer) = continued {
+                repl.conversation.push(question, answer, 0);
+            }
+            let recovery = repl.error_tracker.record(true);
+            recover(
+                recovery,
+                &setup.graph,
+                &mut setup.context,
+                &mut repl.options_changed,
+                log,
+            )?;
+            continue;
+        }
+
+        // Print how many tokens the prompt of this turn costs.
+        if setup.cli.show_prompt_tokens {
+            match try_get_metadata_from_context(&setup.context)
+                .as_ref()
+                .and_then(format_prompt_tokens)
+            {
+                Some(message) => info!(log, "{}", message),
+                None => info!(log, "this prompt: unknown number of tokens"),
+            }
+        }
+
+        // Execute the inference.
+        label!(log, "{}", setup.cli.roles.assistant_label(setup.language));
+        let shown_prefill = match continued {
+            Some(_) => "",
+            None => prefill.as_deref().unwrap_or_default(),
+        };
+        let generation = generate(
+            &mut setup.context,
+            &mut setup.mode,
+            &setup.cli.config,
+            shown_prefill,
+            setup.cli.sink.as_mut(),
+        )
+        .map_err(|err| {
+            ExampleError::backend(format!("Failed to write the answer: {}", err), err)
+        })?;
+        if let Some(err) = &generation.error {
+            status!(log, "[ERROR] {}", err);
+        }
+        // Delete the context in compute_single mode. A context that can't be
+        // finished is as broken as one that failed to generate.
+        let mut failed = generation.error.is_some();
+        if setup.mode != ComputeMode::Batch {
+            if let Err(err) = setup.context.fini_single() {
+                status!(log, "[ERROR] Failed to finish the generation: {}", err);
+                failed = true;
             }
+        }
+        let recovery = repl.error_tracker.record(failed);
+        recover(
+            recovery,
+            &setup.graph,
+            &mut setup.context,
+            &mut repl.options_changed,
+            log,
+        )?;
+        repl.session_stats.add(&generation);
+        setup.summary.add(&generation);
+        info!(
+            log,
+            "{:.1} tok/s, session avg {:.1} tok/s",
+            tokens_per_second(generation.stats.completion_tokens, generation.stats.elapsed),
+            repl.session_stats.tokens_per_second()
+        );
+        let mut output = continued.or(prefill).unwrap_or_default() + &generation.output;
+        let reset_prompt = matches!(
+            generation.reason,
+            StopReason::ContextFull | StopReason::PromptTooLong
+        );
+
+        // Update the conversation. In stateless mode every question is answered
+        // against the system prompt only, so nothing is carried over.
+        if reset_prompt || setup.cli.stateless {
+            repl.conversation.clear();
         } else {
-            if options["llama3"].as_bool().unwrap() {
-                saved_prompt = format!("{}<|start_header_id|>user<|end_header_id|>\n\n{}<|eot_id|>\n<|start_header_id|>assistant<|end_header_id|>\n\n", saved_prompt, input);
-            } else {
-                saved_prompt = format!("{} [INST] {} [/INST]", saved_prompt, input);
+            output = setup.template.history_answer(&output);
+            if generation.reason == StopReason::Timeout {
+                output += TIMED_OUT_MARKER;
             }
+            repl.conversation
+                .push(question, output, generation.stats.completion_tokens);
         }
+    }
+    Ok(())
+}
 
-        // Set prompt to the input tensor.
-        set_data_to_context(&mut context, saved_prompt.as_bytes().to_vec())
-            .expect("Failed to set input");
-
-        // Get the number of input tokens and llama.cpp versions.
-        // let input_metadata = get_metadata_from_context(&context);
-        // println!("[INFO] llama_commit: {}", input_metadata["llama_commit"]);
-        // println!(
-        //     "[INFO] llama_build_number: {}",
-        //     input_metadata["llama_build_number"]
-        // );
-        // println!(
-        //     "[INFO] Number of input tokens: {}",
-        //     input_metadata["input_tokens"]
-        // );
-
-        // Execute the inference (streaming mode).
-        let mut output = String::new();
-        let mut reset_prompt = false;
-        println!("ASSISTANT:");
-        loop {
-            match context.compute_single() {
-                Ok(_) => (),
-                Err(Error::BackendError(BackendError::EndOfSequence)) => {
-                    break;
-                }
-                Err(Error::BackendError(BackendError::ContextFull)) => {
-                    println!("\n[INFO] Context full, we'll reset the context and continue.");
-                    reset_prompt = true;
-                    break;
-                }
-                Err(Error::BackendError(BackendError::PromptTooLong)) => {
-                    println!("\n[INFO] Prompt too long, we'll reset the context and continue.");
-                    reset_prompt = true;
-                    break;
-                }
-                Err(err) => {
-                    println!("\n[ERROR] {}", err);
-                    break;
+// Fit the prompt of the question into the history window, `--reserve-tokens`
+// and `--max-prompt-chars`, first by dropping the oldest turns and then, with
+// `--truncate-long-prompts`, by truncating the question. Returns the question
+// and its prompt, or `None` if it doesn't fit.
+fn fit_prompt(
+    setup: &Setup,
+    conversation: &mut Conversation,
+    mut question: String,
+) -> Option<(String, String)> {
+    let log = setup.cli.log;
+    if let Some(budget) = setup.cli.history_window_tokens {
+        let dropped = conversation.trim_to_tokens(budget, estimate_tokens(&question));
+        if dropped > 0 {
+            info!(
+                log,
+                "Dropped the {} oldest turns to fit the history into {} tokens.", dropped, budget
+            );
+        }
+    }
+    let mut prompt = conversation.prompt(&question);
+
+    // Check the size of the prompt before handing it to the backend, which
+    // may take a long time to reject an enormous input.
+    let max_prompt_chars = setup.cli.max_prompt_chars;
+    let too_long = |prompt: &str| {
+        setup
+            .prompt_limit
+            .is_some_and(|limit| estimate_tokens(prompt) > limit)
+            || prompt_chars_excess(prompt, max_prompt_chars).is_some()
+    };
+    let mut dropped = 0;
+    while too_long(&prompt) && conversation.drop_oldest() {
+        dropped += 1;
+        prompt = conversation.prompt(&question);
+    }
+    if dropped > 0 {
+        info!(
+            log,
+            "Prompt too long, dropped the {} oldest turns to make room.", dropped
+        );
+    }
+    let estimate = estimate_tokens(&prompt);
+    if let Some(prompt_limit) = setup.prompt_limit.filter(|&limit| estimate > limit) {
+        info!(
+            log,
+            "The prompt is estimated at {} tokens, the limit is {} tokens.", estimate, prompt_limit
+        );
+        if !setup.cli.truncate_long_prompts {
+            status!(log, "[ERROR] The prompt is too long, please shorten it.");
+            return None;
+        }
+        let excess_bytes = (estimate - prompt_limit) as usize * BYTES_PER_TOKEN;
+        question = keep_tail(&question, question.len().saturating_sub(excess_bytes)).to_string();
+        info!(
+            log,
+            "The input has been truncated to its last {} bytes.",
+            question.len()
+        );
+        prompt = conversation.prompt(&question);
+    }
+    if let Some(excess) = prompt_chars_excess(&prompt, max_prompt_chars) {
+        info!(
+            log,
+            "The prompt has {} characters, the limit is {} characters.",
+            prompt.chars().count(),
+            max_prompt_chars.unwrap_or(0)
+        );
+        if !setup.cli.truncate_long_prompts {
+            status!(log, "[ERROR] The prompt is too long, please shorten it.");
+            return None;
+        }
+        let keep = question.chars().count().saturating_sub(excess);
+        question = keep_tail_chars(&question, keep).to_string();
+        info!(
+            log,
+            "The input has been truncated to its last {} characters.", keep
+        );
+        prompt = conversation.prompt(&question);
+    }
+    Some((question, prompt))
+}
+
+// Run a REPL command. `/continue` returns the turn it takes back, as the
+// question and the answer to continue; every other command returns `None`.
+fn run_command(
+    setup: &mut Setup,
+    repl: &mut Repl,
+    command: Result<ReplCommand, String>,
+) -> Option<(String, String)> {
+    let log = setup.cli.log;
+    match command {
+        Ok(ReplCommand::Preview(question)) => {
+            let mut prompt = repl.conversation.prompt(&apply_prompt_prefix(
+                setup.cli.prompt_prefix.as_deref(),
+                &question,
+            ));
+            if let Some(prefill) = repl.next_prefill.as_ref().or(setup.cli.prefill.as_ref()) {
+                prompt += &setup.template.format_prefill(prefill);
+            }
+            status!(log, "{}", show_prompt(&prompt, setup.template));
+        }
+        Ok(ReplCommand::Continue) => match repl.conversation.pop() {
+            Some(turn) => {
+                let answer = turn.assistant;
+                let answer = answer
+                    .strip_suffix(TIMED_OUT_MARKER)
+                    .map_or(answer.clone(), String::from);
+                return Some((turn.user, answer));
+            }
+            None => status!(log, "[ERROR] There is no answer to continue."),
+        },
+        Ok(ReplCommand::Prefill(text)) => {
+            info!(log, "The next answer will start with: {}", text);
+            repl.next_prefill = Some(text);
+        }
+        Ok(ReplCommand::ListPersonas) => match Persona::list() {
+            Ok(names) if names.is_empty() => info!(log, "No personas in {}.", PERSONAS_DIR),
+            Ok(names) => info!(log, "Personas: {}", names.join(", ")),
+            Err(message) => status!(log, "[ERROR] {}", message),
+        },
+        Ok(ReplCommand::Persona(_)) if setup.cli.raw => {
+            status!(log, "[ERROR] /persona is not available with --raw.")
+        }
+        Ok(ReplCommand::Persona(name)) => match Persona::load(&name) {
+            Ok(persona) => {
+                persona.apply_options(&mut setup.options);
+                merge_options(&mut setup.options, &setup.overrides);
+                setup.cli.config.n_predict = setup.options["n-predict"].as_u64();
+                repl.options_changed = true;
+                setup.template = setup
+                    .cli
+                    .model_family
+                    .or(persona.template)
+                    .unwrap_or(setup.default_template);
+                setup.cli.config.primer = setup.template.primer();
+                repl.conversation = Conversation::new(
+                    setup.template,
+                    setup.cli.roles.clone(),
+                    setup
+                        .cli
+                        .system_prompt_override
+                        .clone()
+                        .unwrap_or(persona.system_prompt),
+                    persona.examples,
+                    log,
+                );
+                info!(
+                    log,
+                    "Switched to the persona {}, the conversation has been reset.", name
+                );
+            }
+            Err(message) => status!(log, "[ERROR] {}", message),
+        },
+        Ok(ReplCommand::Reset) => {
+            repl.conversation.clear();
+            if !setup.cli.cumulative_stats {
+                repl.session_stats = SessionStats::default();
+            }
+            info!(log, "The conversation has been reset.");
+        }
+        Ok(ReplCommand::Temp(temp)) => {
+            setup.options["temp"] = json!(temp);
+            repl.options_changed = true;
+            info!(log, "temp set to {}", temp);
+            if dynatemp_without_temp(&setup.options) {
+                warn!(
+                    log,
+                    "The dynamic temperature has no effect with a temperature of 0."
+                );
+            }
+        }
+        Ok(ReplCommand::TopP(top_p)) => {
+            setup.options["top-p"] = json!(top_p);
+            repl.options_changed = true;
+            info!(log, "top-p set to {}", top_p);
+        }
+        Ok(ReplCommand::MinP(min_p)) => {
+            setup.options["min-p"] = json!(min_p);
+            repl.options_changed = true;
+            info!(log, "min-p set to {}", min_p);
+        }
+        Ok(ReplCommand::TypicalP(typical_p)) => {
+            setup.options["typical-p"] = json!(typical_p);
+            repl.options_changed = true;
+            info!(log, "typical-p set to {}", typical_p);
+        }
+        Ok(ReplCommand::TfsZ(tfs_z)) => {
+            setup.options["tfs-z"] = json!(tfs_z);
+            repl.options_changed = true;
+            info!(log, "tfs-z set to {}", tfs_z);
+        }
+        Ok(ReplCommand::DynatempRange(range)) => {
+            setup.options["dynatemp-range"] = json!(range);
+            repl.options_changed = true;
+            info!(log, "dynatemp-range set to {}", range);
+            if dynatemp_without_temp(&setup.options) {
+                warn!(
+                    log,
+                    "The dynamic temperature has no effect with a temperature of 0."
+                );
+            }
+        }
+        Ok(ReplCommand::DynatempExponent(exponent)) => {
+            setup.options["dynatemp-exponent"] = json!(exponent);
+            repl.options_changed = true;
+            info!(log, "dynatemp-exponent set to {}", exponent);
+        }
+        Ok(ReplCommand::TopK(top_k)) => {
+            setup.options["top-k"] = json!(top_k);
+            repl.options_changed = true;
+            info!(log, "top-k set to {}", top_k);
+        }
+        Ok(ReplCommand::LogitBias(biases)) => {
+            set_logit_bias(&mut setup.options, &biases);
+            repl.options_changed = true;
+            info!(log, "logit-bias set to {}", setup.options["logit-bias"]);
+        }
+        Ok(ReplCommand::ClearLogitBias) => {
+            setup.options["logit-bias"] = json!({});
+            repl.options_changed = true;
+            info!(log, "logit-bias cleared");
+        }
+        Ok(ReplCommand::Save(_)) if repl.conversation.turns().is_empty() => {
+            info!(log, "There is no conversation to save yet.");
+        }
+        Ok(ReplCommand::Save(name)) => {
+            let path = match name {
+                Some(slug) => format!("{}.json", slug),
+                None => {
+                    let title = generate_title(
+                        &mut setup.context,
+                        &mut setup.mode,
+                        &setup.cli.config,
+                        &repl.conversation,
+                        &setup.options,
+                    );
+                    let timestamp = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |since| since.as_secs());
+                    session_file_name(&slugify(&title.unwrap_or_default()), timestamp)
                 }
+            };
+            match save_session(&path, repl.conversation.turns()) {
+                Ok(()) => info!(log, "Saved the session to {}.", path),
+                Err(message) => status!(log, "[ERROR] {}", message),
             }
-            // Retrieve the single output token and print it.
-            let token = get_single_output_from_context(&context);
-            print!("{}", token);
-            io::stdout().flush().unwrap();
-            output += &token;
         }
-        println!();
+        Err(message) => status!(log, "[ERROR] {}", message),
+    }
+    None
+}
 
-        // Update the saved prompt.
-        if reset_prompt {
-            saved_prompt.clear();
-        } else {
-            output = output.trim().to_string();
-            saved_prompt = format!("{} {}", saved_prompt, output);
-        }
-
-        // Retrieve the output metadata.
-        // let metadata = get_metadata_from_context(&context);
-        // println!(
-        //     "[INFO] Number of input tokens: {}",
-        //     metadata["input_tokens"]
-        // );
-        // println!(
-        //     "[INFO] Number of output tokens: {}",
-        //     metadata["output_tokens"]
-        // );
-
-        // Delete the context in compute_single mode.
-        context.fini_single().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::TurnStats;
+    use crate::template::RoleNames;
+
+    #[test]
+    fn bare_mode_prints_no_labels() {
+        let bare = Log {
+            quiet: true,
+            bare: true,
+        };
+        let english = language("en", bare);
+        let labels = [
+            String::from("Prompt:"),
+            String::from("Response:"),
+            RoleNames::default().user_label(english),
+            RoleNames::default().assistant_label(english),
+        ];
+        for label in &labels {
+            assert_eq!(bare.format(LineKind::Label, label), None);
+            assert_eq!(
+                Log::default().format(LineKind::Label, label).as_deref(),
+                Some(label.as_str())
+            );
+        }
+        assert_eq!(bare.format(LineKind::Info, "loaded"), None);
+        assert_eq!(bare.format(LineKind::Warn, "slow"), None);
+        // Errors are still reported.
+        assert_eq!(
+            bare.format(LineKind::Status, "[ERROR] failed").as_deref(),
+            Some("[ERROR] failed")
+        );
+        assert_eq!(
+            Log::default().format(LineKind::Info, "loaded").as_deref(),
+            Some("[INFO] loaded")
+        );
+    }
+
+    fn choices(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn choices_grammar_escapes_the_choices() {
+        assert_eq!(
+            choices_grammar(&choices(&["yes", "say \"no\""])),
+            r#"root ::= "yes" | "say \"no\"""#
+        );
+    }
+
+    #[test]
+    fn match_choice_prefers_the_longest_choice() {
+        let choices = choices(&["No", "Not sure", "Yes"]);
+        assert_eq!(match_choice(" yes.\n", &choices), ChoiceMatch::Choice(2));
+        assert_eq!(match_choice("Not sure", &choices), ChoiceMatch::Choice(1));
+        assert_eq!(match_choice("no", &choices), ChoiceMatch::Choice(0));
+        // A model that stopped early.
+        assert_eq!(match_choice("Ye", &choices), ChoiceMatch::Choice(2));
+        assert_eq!(match_choice("maybe", &choices), ChoiceMatch::NoMatch);
+        assert_eq!(match_choice("", &choices), ChoiceMatch::NoMatch);
+    }
+
+    #[test]
+    fn match_choice_ties() {
+        let choices = choices(&["Red", "red", "Blue"]);
+        assert_eq!(match_choice("RED", &choices), ChoiceMatch::Tie(vec![0, 1]));
+    }
+
+    #[test]
+    fn score_answer_penalizes_repetition() {
+        assert_eq!(score_answer("the cat sat on the mat"), 6);
+        // The trigram "a b c" comes back once.
+        assert_eq!(score_answer("a b c a b c"), 4);
+        assert_eq!(score_answer("go go go go go go go"), -1);
+        assert_eq!(score_answer(""), 0);
+    }
+
+    #[test]
+    fn best_answer_takes_the_first_on_a_tie() {
+        let answers = vec![
+            String::from("one two"),
+            String::from("one two three four"),
+            String::from("four three two one"),
+            String::from("go go go go go go go"),
+        ];
+        assert_eq!(best_answer(&answers), 1);
+    }
+
+    #[test]
+    fn model_paths_and_names() {
+        assert!(looks_like_path("models/llama.gguf"));
+        assert!(looks_like_path("llama.gguf"));
+        assert!(looks_like_path("C:\\models\\llama"));
+        assert!(!looks_like_path("default"));
+        assert!(!looks_like_path("llama-3-8b"));
+    }
+
+    #[test]
+    fn missing_model_file_names_the_directory_to_preopen() {
+        let err = resolve_model_path("no-such-dir/model.gguf").unwrap_err();
+        assert!(err.starts_with("Failed to access the model file no-such-dir/model.gguf"));
+        assert!(
+            err.contains("wasmedge --dir no-such-dir:no-such-dir"),
+            "{}",
+            err
+        );
+        let err = resolve_model_path("no-such-model.gguf").unwrap_err();
+        assert!(err.contains("wasmedge --dir .:."), "{}", err);
+        let dir = std::env::temp_dir();
+        assert_eq!(
+            resolve_model_path(dir.to_str().unwrap()),
+            Err(format!("The model path {} is not a file", dir.display()))
+        );
+    }
+
+    fn generation(prompt_tokens: Option<u64>, completion_tokens: u64, ms: u64) -> Generation {
+        Generation {
+            output: String::new(),
+            reason: StopReason::EndOfSequence,
+            stats: TurnStats {
+                prompt_tokens,
+                completion_tokens,
+                elapsed: Duration::from_millis(ms),
+            },
+            error: None,
+        }
+    }
+
+    #[test]
+    fn batch_stats_add_up_the_prompts() {
+        let mut stats = BatchStats::default();
+        stats.add(&generation(Some(12), 30, 1500));
+        // A backend which doesn't report the prompt tokens counts as 0.
+        stats.add(&generation(None, 20, 1000));
+        assert_eq!(
+            stats.summary(),
+            "12 input tokens, 50 output tokens, 2.50s, 20.00 tokens/s"
+        );
+        assert_eq!(
+            BatchStats::default().summary(),
+            "0 input tokens, 0 output tokens, 0.00s, 0.00 tokens/s"
+        );
+    }
+
+    #[test]
+    fn watch_events_of_the_mtimes() {
+        let mtime = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        // The first poll of an existing file runs it.
+        assert_eq!(watch_event(None, mtime(10)), WatchEvent::Changed);
+        assert_eq!(watch_event(mtime(10), mtime(10)), WatchEvent::Unchanged);
+        assert_eq!(watch_event(mtime(10), mtime(12)), WatchEvent::Changed);
+        // An editor saving by rename may leave the mtime older.
+        assert_eq!(watch_event(mtime(12), mtime(11)), WatchEvent::Changed);
+        assert_eq!(watch_event(mtime(12), None), WatchEvent::Deleted);
+        assert_eq!(watch_event(None, None), WatchEvent::Unchanged);
     }
 }
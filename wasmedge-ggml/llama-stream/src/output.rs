@@ -0,0 +1,379 @@
+// Filters and checks applied to the generated text.
+use crate::generate::RepetitionConfig;
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+// The SHA-256 of the bytes as a lowercase hex string, to compare the answers
+// of `--deterministic` runs.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// What to do when the answer contains banned content.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BanAction {
+    // Replace every character of the match with an asterisk.
+    Mask,
+    // Cut the answer off right before the match.
+    Stop,
+}
+
+// Client-side filter for banned words and patterns in the answer, e.g. for
+// demos in front of an audience. The matching is case-insensitive by default.
+pub struct BanFilter {
+    pub regex: Regex,
+    pub action: BanAction,
+}
+
+impl BanFilter {
+    pub fn new(
+        words: &[String],
+        patterns: &[String],
+        action: BanAction,
+        case_sensitive: bool,
+    ) -> Result<Self, String> {
+        let alternatives: Vec<String> = words
+            .iter()
+            .map(|word| format!(r"\b{}\b", regex::escape(word)))
+            .chain(patterns.iter().map(|pattern| format!("(?:{})", pattern)))
+            .collect();
+        let regex = RegexBuilder::new(&alternatives.join("|"))
+            .case_insensitive(!case_sensitive)
+            .build()
+            .map_err(|err| format!("invalid value for --ban-pattern: {}", err))?;
+        Ok(BanFilter { regex, action })
+    }
+
+    // The byte offset where the answer has to be cut off in `Stop` mode.
+    pub fn find_stop(&self, text: &str) -> Option<usize> {
+        if self.action != BanAction::Stop {
+            return None;
+        }
+        self.regex.find(text).map(|m| m.start())
+    }
+
+    // Replace every character of each match with an asterisk. The matches are
+    // on character boundaries and the number of characters doesn't change, so
+    // the masked text stays valid UTF-8 and aligned with the original one.
+    pub fn mask(&self, text: &str) -> String {
+        self.regex
+            .replace_all(text, |caps: &regex::Captures| {
+                "*".repeat(caps[0].chars().count())
+            })
+            .into_owned()
+    }
+
+    // Return the part of the masked answer that hasn't been printed yet.
+    // Until the answer is finished, the trailing incomplete word is held back,
+    // so a banned word split across several tokens is still masked before it's
+    // printed. Patterns spanning whitespace can only be caught while their
+    // beginning is still held back.
+    pub fn release(&self, text: &str, printed: &mut usize, finished: bool) -> String {
+        let releasable = if finished {
+            text.chars().count()
+        } else {
+            match text.rfind(char::is_whitespace) {
+                Some(pos) => text[..pos].chars().count() + 1,
+                None => 0,
+            }
+        };
+        if releasable <= *printed {
+            return String::new();
+        }
+        let released = self
+            .mask(text)
+            .chars()
+            .skip(*printed)
+            .take(releasable - *printed)
+            .collect();
+        *printed = releasable;
+        released
+    }
+}
+
+// What the start of an answer is with regard to the primer of the template.
+#[derive(Debug, PartialEq)]
+pub enum PrimerEcho {
+    // The answer so far may still turn out to be an echo of the primer.
+    Pending,
+    // The answer starts with an echo of the primer, this many bytes long.
+    Echo(usize),
+    Content,
+}
+
+// Check whether `text` starts with an echo of `primer`, ignoring whitespace
+// around it. The echo must be followed by whitespace, so that genuine content
+// which merely starts like the primer is kept.
+pub fn check_primer_echo(text: &str, primer: &str) -> PrimerEcho {
+    let trimmed = text.trim_start();
+    let primer = primer.trim();
+    match trimmed.strip_prefix(primer) {
+        Some("") => PrimerEcho::Pending,
+        Some(rest) if rest.starts_with(char::is_whitespace) => {
+            PrimerEcho::Echo(text.len() - rest.len())
+        }
+        Some(_) => PrimerEcho::Content,
+        None if primer.starts_with(trimmed) => PrimerEcho::Pending,
+        None => PrimerEcho::Content,
+    }
+}
+
+// The start of the first stop sequence in `text`.
+pub fn find_stop_sequence(text: &str, stop_sequences: &[String]) -> Option<usize> {
+    stop_sequences
+        .iter()
+        .filter_map(|stop| text.find(stop.as_str()))
+        .min()
+}
+
+// The end of the part of `text` that is safe to print: a trailing partial
+// stop sequence is held back until it's known whether it completes.
+pub fn printable_end(text: &str, stop_sequences: &[String]) -> usize {
+    let held = stop_sequences
+        .iter()
+        .flat_map(|stop| stop.char_indices().skip(1).map(move |(i, _)| &stop[..i]))
+        .filter(|prefix| text.ends_with(prefix))
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    text.len() - held
+}
+
+// Detect a degenerate loop at the end of `text`: a substring of at least
+// `min_len` characters that is repeated back to back more than `max_repeats`
+// times within the last `window` characters. The text is inspected as a whole,
+// so repeats straddling token boundaries are found as well.
+//
+// Returns the byte offset where the first occurrence of the repeated substring
+// ends, so the text can be truncated to keep only that occurrence.
+pub fn detect_repetition(text: &str, config: &RepetitionConfig) -> Option<usize> {
+    let mut tail: Vec<(usize, char)> = text.char_indices().rev().take(config.window).collect();
+    tail.reverse();
+    let n = tail.len();
+    let min_len = config.min_len.max(1);
+    for period in min_len..=n / (config.max_repeats + 1) {
+        let same =
+            |i: usize, repeats: usize| tail[n - 1 - i].1 == tail[n - 1 - i - repeats * period].1;
+        let mut repeats = 1;
+        while (repeats + 1) * period <= n && (0..period).all(|i| same(i, repeats)) {
+            repeats += 1;
+        }
+        if repeats > config.max_repeats {
+            let end = n - (repeats - 1) * period;
+            return Some(tail.get(end).map_or(text.len(), |&(offset, _)| offset));
+        }
+    }
+    None
+}
+
+// The first JSON object or array in the text, e.g. in an answer which wraps
+// it in prose or a code fence. Every `{` and `[` is tried as the start of a
+// value, the parser finds its balanced end, also with brackets in strings.
+pub fn extract_json(text: &str) -> Option<&str> {
+    text.match_indices(['{', '[']).find_map(|(start, _)| {
+        let mut values = serde_json::Deserializer::from_str(&text[start..]).into_iter::<Value>();
+        match values.next() {
+            Some(Ok(_)) => Some(&text[start..start + values.byte_offset()]),
+            _ => None,
+        }
+    })
+}
+
+// The text shown for a match of `--redact`.
+const REDACTED: &str = "[REDACTED]";
+
+// Build a single regex matching any of the `--redact` patterns.
+pub fn redact_regex(patterns: &[String]) -> Result<Option<Regex>, String> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let alternatives: Vec<String> = patterns
+        .iter()
+        .map(|pattern| format!("(?:{})", pattern))
+        .collect();
+    Regex::new(&alternatives.join("|"))
+        .map(Some)
+        .map_err(|err| format!("invalid value for --redact: {}", err))
+}
+
+// Replace every match of the regex in the text with `REDACTED`.
+pub fn redact(text: &str, regex: &Regex) -> String {
+    regex.replace_all(text, REDACTED).into_owned()
+}
+
+// The last `lines` lines of the text, ignoring trailing blank lines, or the
+// whole text if it's shorter.
+pub fn tail_lines(text: &str, lines: usize) -> &str {
+    let text = text.trim_end();
+    if lines == 0 {
+        return "";
+    }
+    match text.rmatch_indices('\n').nth(lines - 1) {
+        Some((index, _)) => &text[index + 1..],
+        None => text,
+    }
+}
+
+// Remove the markdown formatting from an answer for plain text consumers:
+// code fences, header markers, bold and italic markers, and the syntax of
+// links and images, keeping their text. The content of code blocks is kept as
+// is, and markers need to hug their text, so `2 * 3 * 4` or `snake_case` are
+// left alone.
+pub fn strip_markdown(text: &str) -> String {
+    let header = Regex::new(r"^(\s{0,3})#{1,6}\s+").unwrap();
+    let link = Regex::new(r"!?\[([^\]\n]*)\]\([^)\s]*(?:\s+\x22[^\x22]*\x22)?\)").unwrap();
+    let bold = Regex::new(r"\*\*(\S(?:.*?\S)?)\*\*|__(\S(?:.*?\S)?)__").unwrap();
+    let italic =
+        Regex::new(r"(^|[^\w*])\*(\S(?:[^*]*?\S)?)\*|(^|[^\w_])_(\S(?:[^_]*?\S)?)_").unwrap();
+    let mut lines = Vec::new();
+    let mut in_code = false;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            lines.push(line.to_string());
+            continue;
+        }
+        let line = header.replace(line, "$1");
+        let line = link.replace_all(&line, "$1");
+        let line = bold.replace_all(&line, |caps: &regex::Captures| {
+            caps.get(1).or(caps.get(2)).unwrap().as_str().to_string()
+        });
+        let line = italic.replace_all(&line, |caps: &regex::Captures| {
+            // The character before the marker is part of the match.
+            match (caps.get(1), caps.get(2)) {
+                (Some(before), Some(inner)) => format!("{}{}", before.as_str(), inner.as_str()),
+                _ => format!("{}{}", &caps[3], &caps[4]),
+            }
+        });
+        lines.push(line.into_owned());
+    }
+    let mut stripped = lines.join("\n");
+    if text.ends_with('\n') {
+        stripped.push('\n');
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPETITION: RepetitionConfig = RepetitionConfig {
+        window: 200,
+        min_len: 4,
+        max_repeats: 3,
+    };
+
+    #[test]
+    fn repetition_keeps_the_first_occurrence() {
+        let text = "Intro. la la la! la la la! la la la! la la la!";
+        let end = detect_repetition(text, &REPETITION).unwrap();
+        assert_eq!(&text[..end], "Intro. la la la!");
+    }
+
+    #[test]
+    fn repetition_below_the_thresholds() {
+        // Repeated only `max_repeats` times.
+        assert_eq!(detect_repetition("abcd abcd abcd ", &REPETITION), None);
+        // Shorter than `min_len`.
+        assert_eq!(detect_repetition("ababababababab", &REPETITION), None);
+        assert_eq!(detect_repetition("", &REPETITION), None);
+    }
+
+    #[test]
+    fn repetition_of_multi_byte_characters() {
+        let text = "答案：好的。好的。好的。好的。";
+        let end = detect_repetition(
+            text,
+            &RepetitionConfig {
+                min_len: 2,
+                ..REPETITION
+            },
+        )
+        .unwrap();
+        assert_eq!(&text[..end], "答案：好的。");
+    }
+
+    #[test]
+    fn primer_echo() {
+        let primer = "<|assistant|>";
+        assert_eq!(check_primer_echo(" <|assis", primer), PrimerEcho::Pending);
+        assert_eq!(
+            check_primer_echo("<|assistant|>", primer),
+            PrimerEcho::Pending
+        );
+        assert_eq!(
+            check_primer_echo(" <|assistant|>\nHi", primer),
+            PrimerEcho::Echo(" <|assistant|>".len())
+        );
+        // Content merely starting like the primer is kept.
+        assert_eq!(
+            check_primer_echo("<|assistant|>s", primer),
+            PrimerEcho::Content
+        );
+        assert_eq!(check_primer_echo("Hi", primer), PrimerEcho::Content);
+    }
+
+    #[test]
+    fn strip_markdown_formatting() {
+        assert_eq!(
+            strip_markdown("## Title\n**bold**, *italic* and __also bold__"),
+            "Title\nbold, italic and also bold"
+        );
+        assert_eq!(
+            strip_markdown("See [the docs](https://example.com \"Docs\") and ![logo](logo.png)."),
+            "See the docs and logo."
+        );
+    }
+
+    #[test]
+    fn strip_markdown_leaves_code_and_arithmetic_alone() {
+        assert_eq!(
+            strip_markdown("```rust\nlet **x** = 1;\n```\n2 * 3 * 4 is snake_case\n"),
+            "let **x** = 1;\n2 * 3 * 4 is snake_case\n"
+        );
+    }
+
+    #[test]
+    fn tail_lines_ignores_trailing_blank_lines() {
+        let text = "one\ntwo\nthree\n\n";
+        assert_eq!(tail_lines(text, 2), "two\nthree");
+        assert_eq!(tail_lines(text, 3), "one\ntwo\nthree");
+        assert_eq!(tail_lines(text, 10), "one\ntwo\nthree");
+        assert_eq!(tail_lines(text, 0), "");
+    }
+
+    #[test]
+    fn redact_any_of_the_patterns() {
+        let patterns = [String::from(r"\d{3}-\d{4}"), String::from("secret")];
+        let regex = redact_regex(&patterns).unwrap().unwrap();
+        assert_eq!(
+            redact("Call 555-1234, the secret is out", &regex),
+            "Call [REDACTED], the [REDACTED] is out"
+        );
+        assert!(redact_regex(&[]).unwrap().is_none());
+        assert!(redact_regex(&[String::from("(")])
+            .unwrap_err()
+            .starts_with("invalid value for --redact"));
+    }
+
+    #[test]
+    fn extract_json_from_prose() {
+        assert_eq!(
+            extract_json("Sure:\n```json\n{\"a\": [1, \"]\"]}\n```"),
+            Some("{\"a\": [1, \"]\"]}")
+        );
+        assert_eq!(extract_json("[1, 2] and [3]"), Some("[1, 2]"));
+        // An unbalanced brace is skipped for the next candidate.
+        assert_eq!(extract_json("{oops [true]"), Some("[true]"));
+        assert_eq!(extract_json("no json here"), None);
+        assert_eq!(extract_json("{\"truncated\": "), None);
+    }
+}
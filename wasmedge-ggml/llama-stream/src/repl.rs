@@ -0,0 +1,316 @@
+// The commands of the interactive mode, e.g. `/temp 0.7`.
+use crate::cli::{check_non_negative, check_tfs_z, parse_value};
+use crate::session::slugify;
+use serde_json::{json, Value};
+use std::io;
+
+// Read the next message from stdin, or `None` once it's closed. Empty lines are
+// skipped unless `allow_empty` is set, then they are returned as an empty turn.
+pub fn read_input(allow_empty: bool) -> io::Result<Option<String>> {
+    loop {
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(None);
+        }
+        if allow_empty || (answer != "\n" && answer != "\r\n") {
+            return Ok(Some(answer.trim().to_string()));
+        }
+    }
+}
+
+// Commands that can be typed at the `USER:` prompt, e.g. to change the
+// sampling options without restarting the session.
+pub enum ReplCommand {
+    Reset,
+    Preview(String),
+    Continue,
+    Persona(String),
+    ListPersonas,
+    Prefill(String),
+    Temp(f64),
+    TopP(f64),
+    MinP(f64),
+    TypicalP(f64),
+    TfsZ(f64),
+    DynatempRange(f64),
+    DynatempExponent(f64),
+    TopK(u64),
+    LogitBias(Vec<(u64, f64)>),
+    ClearLogitBias,
+    // `/save` with the slug of the given name, or `None` for a generated title.
+    Save(Option<String>),
+}
+
+pub fn parse_repl_command(input: &str) -> Option<Result<ReplCommand, String>> {
+    let mut parts = input.split_whitespace();
+    let command = parts.next()?;
+    if command == "/prefill" {
+        let text = input.trim_start()["/prefill".len()..].trim_start();
+        if text.is_empty() {
+            return Some(Err(String::from("usage: /prefill <text>")));
+        }
+        return Some(Ok(ReplCommand::Prefill(text.to_string())));
+    }
+    if command == "/personas" {
+        return match parts.next() {
+            None => Some(Ok(ReplCommand::ListPersonas)),
+            Some(_) => Some(Err(String::from("usage: /personas"))),
+        };
+    }
+    if command == "/persona" {
+        return match (parts.next(), parts.next()) {
+            (Some(name), None) => Some(Ok(ReplCommand::Persona(name.to_string()))),
+            _ => Some(Err(String::from("usage: /persona <name>"))),
+        };
+    }
+    if command == "/continue" {
+        return match parts.next() {
+            None => Some(Ok(ReplCommand::Continue)),
+            Some(_) => Some(Err(String::from("usage: /continue"))),
+        };
+    }
+    if command == "/preview" {
+        let question = input.trim_start()["/preview".len()..].trim_start();
+        return Some(Ok(ReplCommand::Preview(question.to_string())));
+    }
+    if command == "/reset" {
+        return match parts.next() {
+            None => Some(Ok(ReplCommand::Reset)),
+            Some(_) => Some(Err(String::from("usage: /reset"))),
+        };
+    }
+    if command == "/save" {
+        let name = input.trim_start()["/save".len()..].trim();
+        if name.is_empty() {
+            return Some(Ok(ReplCommand::Save(None)));
+        }
+        return match slugify(name) {
+            slug if slug.is_empty() => Some(Err(format!(
+                "invalid session name '{}', it needs a letter or a digit",
+                name
+            ))),
+            slug => Some(Ok(ReplCommand::Save(Some(slug)))),
+        };
+    }
+    if command == "/logit-bias" {
+        let values: Vec<&str> = parts.collect();
+        return Some(match values.as_slice() {
+            [] => Err(String::from(
+                "usage: /logit-bias <token_id:bias>... or /logit-bias clear",
+            )),
+            ["clear"] => Ok(ReplCommand::ClearLogitBias),
+            values => values
+                .iter()
+                .map(|value| parse_logit_bias("/logit-bias", value))
+                .collect::<Result<Vec<_>, _>>()
+                .map(ReplCommand::LogitBias),
+        });
+    }
+    if !matches!(
+        command,
+        "/temp"
+            | "/top-p"
+            | "/min-p"
+            | "/typical-p"
+            | "/tfs-z"
+            | "/dynatemp-range"
+            | "/dynatemp-exponent"
+            | "/top-k"
+    ) {
+        return None;
+    }
+    let value = match (parts.next(), parts.next()) {
+        (Some(value), None) => value,
+        _ => return Some(Err(format!("usage: {} <value>", command))),
+    };
+    Some(parse_option_command(command, value))
+}
+
+// Parse the value of a command setting a sampling option, `/temp`, `/top-p`,
+// `/min-p`, `/typical-p`, `/tfs-z`, `/dynatemp-range`, `/dynatemp-exponent` or
+// `/top-k`.
+pub fn parse_option_command(command: &str, value: &str) -> Result<ReplCommand, String> {
+    match command {
+        "/temp" => match value.parse::<f64>() {
+            Ok(temp) if temp.is_finite() && temp >= 0.0 => Ok(ReplCommand::Temp(temp)),
+            _ => Err(format!(
+                "invalid value '{}' for /temp, expected a non-negative number",
+                value
+            )),
+        },
+        "/top-p" => match value.parse::<f64>() {
+            Ok(top_p) if top_p > 0.0 && top_p <= 1.0 => Ok(ReplCommand::TopP(top_p)),
+            _ => Err(format!(
+                "invalid value '{}' for /top-p, expected a number in (0, 1]",
+                value
+            )),
+        },
+        "/min-p" => match value.parse::<f64>() {
+            Ok(min_p) if min_p > 0.0 && min_p <= 1.0 => Ok(ReplCommand::MinP(min_p)),
+            _ => Err(format!(
+                "invalid value '{}' for /min-p, expected a number in (0, 1]",
+                value
+            )),
+        },
+        "/typical-p" => match value.parse::<f64>() {
+            Ok(typical_p) if typical_p > 0.0 && typical_p <= 1.0 => {
+                Ok(ReplCommand::TypicalP(typical_p))
+            }
+            _ => Err(format!(
+                "invalid value '{}' for /typical-p, expected a number in (0, 1]",
+                value
+            )),
+        },
+        "/tfs-z" => parse_value("/tfs-z", value)
+            .and_then(|value| check_tfs_z("/tfs-z", value))
+            .map(ReplCommand::TfsZ),
+        "/dynatemp-range" => parse_value("/dynatemp-range", value)
+            .and_then(|value| check_non_negative("/dynatemp-range", value))
+            .map(ReplCommand::DynatempRange),
+        "/dynatemp-exponent" => parse_value("/dynatemp-exponent", value)
+            .and_then(|value| check_non_negative("/dynatemp-exponent", value))
+            .map(ReplCommand::DynatempExponent),
+        _ => parse_value("/top-k", value).map(ReplCommand::TopK),
+    }
+}
+
+impl ReplCommand {
+    // The option set by a sampling option command.
+    pub fn option(&self) -> Option<(&'static str, Value)> {
+        match self {
+            ReplCommand::Temp(temp) => Some(("temp", json!(temp))),
+            ReplCommand::TopP(top_p) => Some(("top-p", json!(top_p))),
+            ReplCommand::MinP(min_p) => Some(("min-p", json!(min_p))),
+            ReplCommand::TypicalP(typical_p) => Some(("typical-p", json!(typical_p))),
+            ReplCommand::TfsZ(tfs_z) => Some(("tfs-z", json!(tfs_z))),
+            ReplCommand::DynatempRange(range) => Some(("dynatemp-range", json!(range))),
+            ReplCommand::DynatempExponent(exponent) => Some(("dynatemp-exponent", json!(exponent))),
+            ReplCommand::TopK(top_k) => Some(("top-k", json!(top_k))),
+            _ => None,
+        }
+    }
+}
+
+// The bias of `--ban-token-id`, low enough that the token is never sampled.
+pub const BAN_LOGIT_BIAS: f64 = -100.0;
+
+// Parse a `token_id:bias` pair of `--logit-bias`. The bias applies to a token
+// id, the text of a token is rejected since the same text can be several
+// tokens.
+pub fn parse_logit_bias(flag: &str, value: &str) -> Result<(u64, f64), String> {
+    let (id, bias) = value.rsplit_once(':').ok_or_else(|| {
+        format!(
+            "invalid value '{}' for {}, expected token_id:bias",
+            value, flag
+        )
+    })?;
+    let id = parse_token_id(flag, id)?;
+    match bias.trim().parse::<f64>() {
+        Ok(bias) if bias.is_finite() => Ok((id, bias)),
+        _ => Err(format!(
+            "invalid bias '{}' for {}, expected a finite number",
+            bias, flag
+        )),
+    }
+}
+
+pub fn parse_token_id(flag: &str, id: &str) -> Result<u64, String> {
+    id.trim().parse::<u64>().map_err(|_| {
+        format!(
+            "invalid token id '{}' for {}, expected a non-negative integer, the ids of a text are shown by the llama-tokenize tool of llama.cpp",
+            id, flag
+        )
+    })
+}
+
+// Set the biases in the `logit-bias` option, a map of token id to bias. A
+// bias replaces an earlier one of the same token.
+pub fn set_logit_bias(options: &mut Value, biases: &[(u64, f64)]) {
+    if !options["logit-bias"].is_object() {
+        options["logit-bias"] = json!({});
+    }
+    for (id, bias) in biases {
+        options["logit-bias"][id.to_string()] = json!(bias);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> ReplCommand {
+        parse_repl_command(input).unwrap().unwrap()
+    }
+
+    fn error(input: &str) -> String {
+        match parse_repl_command(input) {
+            Some(Err(message)) => message,
+            _ => panic!("'{}' should be an invalid command", input),
+        }
+    }
+
+    #[test]
+    fn ordinary_input_is_not_a_command() {
+        assert!(parse_repl_command("What is the capital of France?").is_none());
+        assert!(parse_repl_command("/unknown 1").is_none());
+        assert!(parse_repl_command("").is_none());
+    }
+
+    #[test]
+    fn sampling_commands() {
+        assert!(matches!(parse("/temp 0.7"), ReplCommand::Temp(temp) if temp == 0.7));
+        assert!(matches!(parse("/top-k 40"), ReplCommand::TopK(40)));
+        assert_eq!(error("/temp"), "usage: /temp <value>");
+        assert_eq!(error("/temp 1 2"), "usage: /temp <value>");
+        assert!(error("/temp -1").starts_with("invalid value '-1' for /temp"));
+        assert!(error("/top-p 0").starts_with("invalid value '0' for /top-p"));
+    }
+
+    #[test]
+    fn text_commands_keep_the_whole_text() {
+        assert!(
+            matches!(parse("/prefill  Sure, here  is"), ReplCommand::Prefill(text) if text == "Sure, here  is")
+        );
+        assert_eq!(error("/prefill"), "usage: /prefill <text>");
+        assert!(matches!(parse("/preview"), ReplCommand::Preview(text) if text.is_empty()));
+    }
+
+    #[test]
+    fn continue_takes_no_arguments() {
+        assert!(matches!(parse("/continue"), ReplCommand::Continue));
+        assert!(matches!(parse("  /continue  "), ReplCommand::Continue));
+        assert_eq!(error("/continue please"), "usage: /continue");
+    }
+
+    #[test]
+    fn logit_bias() {
+        assert!(matches!(
+            parse("/logit-bias clear"),
+            ReplCommand::ClearLogitBias
+        ));
+        assert!(
+            matches!(parse("/logit-bias 15043:-1.5 2:3"), ReplCommand::LogitBias(biases) if biases == [(15043, -1.5), (2, 3.0)])
+        );
+        assert!(error("/logit-bias").starts_with("usage: /logit-bias"));
+    }
+
+    #[test]
+    fn later_biases_replace_earlier_ones() {
+        let mut options = json!({"temp": 0.8});
+        set_logit_bias(&mut options, &[(15043, -1.5), (2, 3.0)]);
+        set_logit_bias(&mut options, &[(15043, BAN_LOGIT_BIAS)]);
+        assert_eq!(
+            options,
+            json!({"temp": 0.8, "logit-bias": {"15043": -100.0, "2": 3.0}})
+        );
+    }
+
+    #[test]
+    fn save_slugifies_the_name() {
+        assert!(matches!(parse("/save"), ReplCommand::Save(None)));
+        assert!(
+            matches!(parse("/save My Notes!"), ReplCommand::Save(Some(slug)) if slug == "my-notes")
+        );
+        assert!(error("/save ...").starts_with("invalid session name"));
+    }
+}
@@ -0,0 +1,184 @@
+// The requests and responses of `--stdio-jsonrpc`, one JSON object per line.
+use crate::repl::{parse_logit_bias, parse_option_command, ReplCommand};
+use crate::sink::TokenSink;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+
+// A request of the `--stdio-jsonrpc` mode, one JSON object per line of stdin:
+// `{"id":1,"method":"chat","params":{"message":"...","stream":true}}`.
+pub struct RpcRequest {
+    pub id: Value,
+    pub method: RpcMethod,
+}
+
+pub enum RpcMethod {
+    Chat { message: String, stream: bool },
+    Stats,
+    // `reset` and `set-options`, run like the interactive commands.
+    Commands(Vec<ReplCommand>),
+}
+
+// The text of an option value in a request, as it would be typed after the
+// command. A number may also be given as a string, e.g. `"0.7"`, which is
+// taken without its quotes.
+fn rpc_value_text(value: &Value) -> String {
+    match value.as_str() {
+        Some(text) => text.to_string(),
+        None => value.to_string(),
+    }
+}
+
+// Parse a request line. A malformed request is returned as the error response.
+pub fn parse_rpc_request(line: &str) -> Result<RpcRequest, Value> {
+    let request: Value = serde_json::from_str(line)
+        .map_err(|err| rpc_error(&Value::Null, &format!("invalid JSON: {}", err), line))?;
+    let id = request["id"].clone();
+    let params = &request["params"];
+    let method = match request["method"].as_str() {
+        Some("chat") => match params["message"].as_str() {
+            Some(message) => RpcMethod::Chat {
+                message: message.to_string(),
+                stream: params["stream"].as_bool().unwrap_or(false),
+            },
+            None => return Err(rpc_error(&id, "params.message must be a string", line)),
+        },
+        Some("stats") => RpcMethod::Stats,
+        Some("reset") => RpcMethod::Commands(vec![ReplCommand::Reset]),
+        Some("set-options") => {
+            let options = params
+                .as_object()
+                .ok_or_else(|| rpc_error(&id, "params must be an object", line))?;
+            let commands = options
+                .iter()
+                .map(|(key, value)| match key.as_str() {
+                    "temp" | "top-p" | "min-p" | "typical-p" | "tfs-z" | "dynatemp-range"
+                    | "dynatemp-exponent" | "top-k" => {
+                        parse_option_command(&format!("/{}", key), &rpc_value_text(value))
+                    }
+                    // A map of token id to bias, e.g. `{"logit-bias":{"15043":-100}}`.
+                    // An empty map clears the biases.
+                    "logit-bias" => match value.as_object() {
+                        Some(biases) if biases.is_empty() => Ok(ReplCommand::ClearLogitBias),
+                        Some(biases) => biases
+                            .iter()
+                            .map(|(id, bias)| {
+                                parse_logit_bias(
+                                    "logit-bias",
+                                    &format!("{}:{}", id, rpc_value_text(bias)),
+                                )
+                            })
+                            .collect::<Result<Vec<_>, _>>()
+                            .map(ReplCommand::LogitBias),
+                        None => Err(String::from(
+                            "logit-bias must be an object of token id to bias",
+                        )),
+                    },
+                    _ => Err(format!("unknown option '{}'", key)),
+                })
+                .collect::<Result<_, String>>()
+                .map_err(|message| rpc_error(&id, &message, line))?;
+            RpcMethod::Commands(commands)
+        }
+        _ => {
+            return Err(rpc_error(
+                &id,
+                "method must be one of chat, reset, set-options and stats",
+                line,
+            ))
+        }
+    };
+    Ok(RpcRequest { id, method })
+}
+
+pub fn rpc_error(id: &Value, message: &str, line: &str) -> Value {
+    json!({ "id": id, "error": { "message": message, "line": line } })
+}
+
+pub fn rpc_emit(message: &Value) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", message)?;
+    stdout.flush()
+}
+
+// Streams the answer of a `--stdio-jsonrpc` chat request as partial messages.
+pub struct RpcPartialSink {
+    pub id: Value,
+}
+
+impl TokenSink for RpcPartialSink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if !bytes.is_empty() {
+            rpc_emit(&json!({ "id": self.id, "partial": String::from_utf8_lossy(bytes) }))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_frame(&mut self, _text: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_message(line: &str) -> String {
+        match parse_rpc_request(line) {
+            Ok(_) => panic!("{} was accepted", line),
+            Err(response) => response["error"]["message"].as_str().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn chat_request() {
+        let request = parse_rpc_request(
+            r#"{"id":7,"method":"chat","params":{"message":"Hi","stream":true}}"#,
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(request.id, json!(7));
+        assert!(
+            matches!(request.method, RpcMethod::Chat { message, stream } if message == "Hi" && stream)
+        );
+        assert_eq!(
+            error_message(r#"{"id":7,"method":"chat","params":{}}"#),
+            "params.message must be a string"
+        );
+    }
+
+    #[test]
+    fn set_options_request() {
+        let request = parse_rpc_request(
+            r#"{"id":"a","method":"set-options","params":{"temp":"0.5","logit-bias":{"15043":-100}}}"#,
+        )
+        .ok()
+        .unwrap();
+        let RpcMethod::Commands(commands) = request.method else {
+            panic!("not a set-options request");
+        };
+        assert!(
+            matches!(&commands[..], [ReplCommand::LogitBias(biases), ReplCommand::Temp(temp)] if biases == &[(15043, -100.0)] && *temp == 0.5)
+        );
+        assert_eq!(
+            error_message(r#"{"id":1,"method":"set-options","params":{"seed":1}}"#),
+            "unknown option 'seed'"
+        );
+    }
+
+    #[test]
+    fn malformed_requests_keep_the_line() {
+        let line = r#"{"id":3,"method":"sing"}"#;
+        let response = parse_rpc_request(line).err().unwrap();
+        assert_eq!(response["id"], json!(3));
+        assert_eq!(response["error"]["line"], json!(line));
+        assert_eq!(
+            error_message(line),
+            "method must be one of chat, reset, set-options and stats"
+        );
+        assert!(error_message("{").starts_with("invalid JSON"));
+    }
+}
@@ -0,0 +1,299 @@
+// Saving and resuming sessions, and the statistics of a whole session.
+use crate::conversation::{estimate_tokens, Turn};
+use crate::generate::{tokens_per_second, Generation};
+use serde_json::{json, Value};
+use std::io;
+use std::time::Duration;
+
+// The file the interactive conversation is saved to when the session ends, and
+// continued from with `--resume`.
+pub const SESSION_FILE: &str = "llama-stream-session.json";
+
+pub fn save_session(path: &str, turns: &[Turn]) -> Result<(), String> {
+    let turns: Vec<Value> = turns
+        .iter()
+        .map(|turn| {
+            json!({
+                "user": turn.user,
+                "assistant": turn.assistant,
+                "tokens": turn.tokens,
+            })
+        })
+        .collect();
+    std::fs::write(path, json!({ "turns": turns }).to_string())
+        .map_err(|err| format!("Failed to save the session {}: {}", path, err))
+}
+
+// Load the turns saved by `save_session`, or `None` if there is no session
+// file yet.
+pub fn load_session(path: &str) -> Result<Option<Vec<Turn>>, String> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("Failed to read the session {}: {}", path, err)),
+    };
+    let value: Value = serde_json::from_str(&content)
+        .map_err(|err| format!("Failed to parse the session {}: {}", path, err))?;
+    let turns = value["turns"]
+        .as_array()
+        .ok_or_else(|| format!("Invalid session {}: field 'turns' must be an array", path))?;
+    turns
+        .iter()
+        .enumerate()
+        .map(|(i, turn)| {
+            let field = |name: &str| {
+                turn[name].as_str().map(String::from).ok_or_else(|| {
+                    format!(
+                        "Invalid session {}: field 'turns[{}].{}' must be a string",
+                        path, i, name
+                    )
+                })
+            };
+            let user = field("user")?;
+            let assistant = field("assistant")?;
+            Ok(Turn {
+                tokens: turn["tokens"]
+                    .as_u64()
+                    .unwrap_or_else(|| estimate_tokens(&user) + estimate_tokens(&assistant)),
+                user,
+                assistant,
+            })
+        })
+        .collect::<Result<_, String>>()
+        .map(Some)
+}
+
+// A short reminder of where a resumed conversation left off: the last
+// question and the first line of its answer.
+pub fn session_recap(turns: &[Turn]) -> Option<String> {
+    let last = turns.last()?;
+    let answer = last.assistant.trim().lines().next().unwrap_or_default();
+    Some(format!(
+        "Last question: {}\nLast answer: {}",
+        last.user.trim(),
+        answer
+    ))
+}
+
+// The longest slug of a session file name, in characters.
+const SLUG_MAX_CHARS: usize = 40;
+
+// Turn a session title into a name that is safe in any file system: lowercase
+// letters and digits, including non-ASCII ones, with a single `-` for every
+// run of other characters such as spaces, dots and slashes.
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug: String = slug.chars().take(SLUG_MAX_CHARS).collect();
+    slug.trim_end_matches('-').to_string()
+}
+
+// The file `/save` without a name saves to, the slug of the generated title
+// with the time in seconds since the epoch, or only the time without a title.
+pub fn session_file_name(slug: &str, timestamp: u64) -> String {
+    match slug {
+        "" => format!("session-{}.json", timestamp),
+        slug => format!("{}-{}.json", slug, timestamp),
+    }
+}
+
+// Whether the interactive session has run for longer than `--max-session-duration`.
+pub fn session_expired(elapsed: Duration, max_duration: Option<Duration>) -> bool {
+    max_duration.is_some_and(|max_duration| elapsed >= max_duration)
+}
+
+// Generation speed accumulated over the turns of the session, useful to spot
+// the throughput dropping over long sessions, e.g. because of thermal throttling.
+#[derive(Default)]
+pub struct SessionStats {
+    pub input_tokens: u64,
+    pub tokens: u64,
+    pub elapsed: Duration,
+}
+
+impl SessionStats {
+    pub fn add(&mut self, generation: &Generation) {
+        self.input_tokens += generation.stats.prompt_tokens.unwrap_or(0);
+        self.tokens += generation.stats.completion_tokens;
+        self.elapsed += generation.stats.elapsed;
+    }
+
+    pub fn tokens_per_second(&self) -> f64 {
+        tokens_per_second(self.tokens, self.elapsed)
+    }
+}
+
+// The summary of `--final-metadata`, printed as JSON when the session ends,
+// e.g. for reproducibility logs. Unlike `SessionStats` it's never reset.
+#[derive(Default)]
+pub struct SessionSummary {
+    pub turns: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    // The plugin version, read once after the model is loaded.
+    pub llama_build_number: Value,
+    pub llama_commit: Value,
+}
+
+impl SessionSummary {
+    pub fn new(metadata: Option<&Value>) -> Self {
+        SessionSummary {
+            llama_build_number: metadata.map_or(Value::Null, |m| m["llama_build_number"].clone()),
+            llama_commit: metadata.map_or(Value::Null, |m| m["llama_commit"].clone()),
+            ..SessionSummary::default()
+        }
+    }
+
+    pub fn add(&mut self, generation: &Generation) {
+        self.turns += 1;
+        self.input_tokens += generation.stats.prompt_tokens.unwrap_or(0);
+        self.output_tokens += generation.stats.completion_tokens;
+    }
+
+    // The summary with the options in effect at the end of the session.
+    pub fn to_json(&self, options: &Value) -> Value {
+        json!({
+            "turns": self.turns,
+            "input_tokens": self.input_tokens,
+            "output_tokens": self.output_tokens,
+            "llama_build_number": self.llama_build_number,
+            "llama_commit": self.llama_commit,
+            "options": options,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::{StopReason, TurnStats};
+
+    // A session file of its own for every test, in the temporary directory.
+    fn session_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("llama-stream-{}-{}.json", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let path = session_path("round-trip");
+        let turns = vec![Turn {
+            user: String::from("Capital of Japan?"),
+            assistant: String::from("Tokyo."),
+            tokens: 7,
+        }];
+        save_session(&path, &turns).unwrap();
+        let loaded = load_session(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].user, "Capital of Japan?");
+        assert_eq!(loaded[0].assistant, "Tokyo.");
+        assert_eq!(loaded[0].tokens, 7);
+        assert!(load_session(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_session_is_an_error() {
+        let path = session_path("corrupt");
+        let load = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            load_session(&path).map(|_| ()).unwrap_err()
+        };
+        let truncated = load(r#"{"turns": [{"user": "Hi", "assis"#);
+        assert!(truncated.starts_with(&format!("Failed to parse the session {}", path)));
+        assert_eq!(
+            load(r#"{"turns": {}}"#),
+            format!("Invalid session {}: field 'turns' must be an array", path)
+        );
+        assert_eq!(
+            load(r#"{"turns": [{"user": "Hi", "assistant": 42}]}"#),
+            format!(
+                "Invalid session {}: field 'turns[0].assistant' must be a string",
+                path
+            )
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn turn(completion_tokens: u64, secs: u64) -> Generation {
+        Generation {
+            output: String::new(),
+            reason: StopReason::EndOfSequence,
+            stats: TurnStats {
+                prompt_tokens: Some(10),
+                completion_tokens,
+                elapsed: Duration::from_secs(secs),
+            },
+            error: None,
+        }
+    }
+
+    #[test]
+    fn session_average_over_the_turns() {
+        let mut stats = SessionStats::default();
+        assert_eq!(stats.tokens_per_second(), 0.0);
+        stats.add(&turn(100, 4));
+        assert_eq!(stats.tokens_per_second(), 25.0);
+        // A slower turn pulls the average down by its share of the time.
+        stats.add(&turn(20, 4));
+        assert_eq!(stats.tokens_per_second(), 15.0);
+        assert_eq!(stats.input_tokens, 20);
+        assert_eq!(stats.tokens, 120);
+        assert_eq!(stats.elapsed, Duration::from_secs(8));
+    }
+
+    #[test]
+    fn session_expiry() {
+        let minute = Duration::from_secs(60);
+        assert!(!session_expired(minute, None));
+        assert!(!session_expired(minute, Some(2 * minute)));
+        assert!(session_expired(minute, Some(minute)));
+    }
+
+    #[test]
+    fn final_metadata_summary() {
+        let metadata =
+            json!({"llama_build_number": 3000, "llama_commit": "abc1234", "input_tokens": 7});
+        let mut summary = SessionSummary::new(Some(&metadata));
+        summary.add(&turn(20, 1));
+        summary.add(&turn(30, 2));
+        assert_eq!(
+            summary.to_json(&json!({"temp": 0.2})),
+            json!({
+                "turns": 2,
+                "input_tokens": 20,
+                "output_tokens": 50,
+                "llama_build_number": 3000,
+                "llama_commit": "abc1234",
+                "options": {"temp": 0.2},
+            })
+        );
+        // A plugin without the version metadata.
+        let summary = SessionSummary::new(None).to_json(&json!({}));
+        assert_eq!(summary["llama_build_number"], Value::Null);
+        assert_eq!(summary["turns"], 0);
+    }
+
+    #[test]
+    fn slugify_titles() {
+        assert_eq!(slugify("Trip to Paris, 2024!"), "trip-to-paris-2024");
+        assert_eq!(slugify("  ../etc/passwd "), "etc-passwd");
+        assert_eq!(slugify("Größe Ärger"), "größe-ärger");
+        assert_eq!(slugify("?!"), "");
+    }
+
+    #[test]
+    fn slugify_limits_the_length() {
+        let slug = slugify(&"word ".repeat(100));
+        assert!(slug.chars().count() <= SLUG_MAX_CHARS);
+        assert!(!slug.ends_with('-'));
+    }
+}
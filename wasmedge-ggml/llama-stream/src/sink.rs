@@ -0,0 +1,488 @@
+// The sinks the answer is streamed into: stdout, a file, a string or JSON
+// events.
+use crate::backend::{try_get_metadata_from_context, Backend};
+use crate::generate::{format_top_probs, Generation};
+use serde_json::{json, Value};
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+// Where the streamed answer is written to.
+pub trait TokenSink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+
+    fn write_str(&mut self, text: &str) -> io::Result<()> {
+        self.write(text.as_bytes())
+    }
+
+    // Text around the answer which isn't generated, such as `--answer-prefix`
+    // and the final newline.
+    fn write_frame(&mut self, text: &str) -> io::Result<()> {
+        self.write_str(text)
+    }
+
+    // Called when the generation of an answer starts, with the prompt set.
+    fn start(&mut self, _context: &dyn Backend) -> io::Result<()> {
+        Ok(())
+    }
+
+    // The most likely alternatives of the token written next, with
+    // `--n-probs`. Shown on stderr right before the token by default.
+    fn top_probs(&mut self, top: &[(String, f64)]) -> io::Result<()> {
+        let dim = io::stderr().is_terminal();
+        write!(io::stderr(), "{}", format_top_probs(top, dim))
+    }
+
+    // Called once the whole answer is written, the output is always flushed.
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    // Called when the generation of an answer is done.
+    fn end(&mut self, _generation: &Generation) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// When the streamed answer is flushed, set by `--flush`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlushPolicy {
+    Token,
+    Word,
+    Line,
+    Interval(Duration),
+}
+
+impl FlushPolicy {
+    pub fn parse(value: &str) -> Result<FlushPolicy, String> {
+        match value {
+            "token" => Ok(FlushPolicy::Token),
+            "word" => Ok(FlushPolicy::Word),
+            "line" => Ok(FlushPolicy::Line),
+            _ => value
+                .strip_prefix("interval:")
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .filter(|&ms| ms > 0)
+                .map(|ms| FlushPolicy::Interval(Duration::from_millis(ms)))
+                .ok_or_else(|| {
+                    format!(
+                        "invalid value '{}' for --flush, expected token, word, line or interval:<ms>",
+                        value
+                    )
+                }),
+        }
+    }
+
+    // Whether the written bytes end a unit of output the policy flushes after.
+    fn is_boundary(self, bytes: &[u8]) -> bool {
+        match self {
+            FlushPolicy::Token => true,
+            FlushPolicy::Word => bytes.iter().any(u8::is_ascii_whitespace),
+            FlushPolicy::Line => bytes.contains(&b'\n'),
+            FlushPolicy::Interval(_) => false,
+        }
+    }
+}
+
+// Wraps another sink and only passes its flushes on as the policy allows, so
+// e.g. a slow terminal is written to once per line. The flush at the end of
+// an answer is always passed on.
+pub struct FlushPolicySink {
+    pub inner: Box<dyn TokenSink>,
+    pub policy: FlushPolicy,
+    // Whether a boundary was written since the last flush.
+    pub boundary: bool,
+    pub last_flush: Instant,
+}
+
+impl FlushPolicySink {
+    pub fn new(inner: Box<dyn TokenSink>, policy: FlushPolicy) -> Self {
+        FlushPolicySink {
+            inner,
+            policy,
+            boundary: false,
+            last_flush: Instant::now(),
+        }
+    }
+
+    fn flush_now(&mut self) -> io::Result<()> {
+        self.boundary = false;
+        self.last_flush = Instant::now();
+        self.inner.flush()
+    }
+}
+
+impl TokenSink for FlushPolicySink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.boundary |= self.policy.is_boundary(bytes);
+        self.inner.write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let due = match self.policy {
+            FlushPolicy::Interval(interval) => self.last_flush.elapsed() >= interval,
+            _ => self.boundary,
+        };
+        if due {
+            self.flush_now()?;
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, text: &str) -> io::Result<()> {
+        self.boundary |= self.policy.is_boundary(text.as_bytes());
+        self.inner.write_frame(text)
+    }
+
+    fn start(&mut self, context: &dyn Backend) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.inner.start(context)
+    }
+
+    fn top_probs(&mut self, top: &[(String, f64)]) -> io::Result<()> {
+        self.inner.top_probs(top)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush_now()
+    }
+
+    fn end(&mut self, generation: &Generation) -> io::Result<()> {
+        self.inner.end(generation)
+    }
+}
+
+// Wraps another sink and only passes complete characters on, holding back the
+// bytes of a character a token ended in the middle of until the next token
+// completes it. Invalid bytes are replaced with U+FFFD, and whatever is still
+// held back when the answer ends is written lossily rather than dropped.
+pub struct Utf8Sink<S: TokenSink> {
+    pub inner: S,
+    pending: Vec<u8>,
+}
+
+impl<S: TokenSink> Utf8Sink<S> {
+    pub fn new(inner: S) -> Self {
+        Utf8Sink {
+            inner,
+            pending: Vec::new(),
+        }
+    }
+
+    // Write the bytes held back, they can't be completed anymore.
+    fn write_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        self.inner.write_str(&text)
+    }
+}
+
+impl<S: TokenSink> TokenSink for Utf8Sink<S> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(bytes);
+        let mut text = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    self.pending.clear();
+                    break;
+                }
+                Err(err) => {
+                    let valid_up_to = err.valid_up_to();
+                    text.push_str(std::str::from_utf8(&self.pending[..valid_up_to]).unwrap());
+                    match err.error_len() {
+                        // Invalid bytes can't become valid later on.
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid_up_to + len);
+                        }
+                        // An incomplete character at the end, wait for the rest of it.
+                        None => {
+                            self.pending.drain(..valid_up_to);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.inner.write_str(&text)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_frame(&mut self, text: &str) -> io::Result<()> {
+        self.write_pending()?;
+        self.inner.write_frame(text)
+    }
+
+    fn start(&mut self, context: &dyn Backend) -> io::Result<()> {
+        self.pending.clear();
+        self.inner.start(context)
+    }
+
+    fn top_probs(&mut self, top: &[(String, f64)]) -> io::Result<()> {
+        self.inner.top_probs(top)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.inner.finish()
+    }
+
+    fn end(&mut self, generation: &Generation) -> io::Result<()> {
+        self.inner.end(generation)
+    }
+}
+
+pub struct StdoutSink;
+
+impl TokenSink for StdoutSink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        io::stdout().write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+// Streams to stdout and appends everything to a file as well, set by `--tee`.
+pub struct TeeSink {
+    pub file: std::fs::File,
+}
+
+impl TeeSink {
+    pub fn open(path: &str) -> Result<TeeSink, String> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("Failed to open {}: {}", path, err))?;
+        Ok(TeeSink { file })
+    }
+}
+
+impl TokenSink for TeeSink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        StdoutSink.write(bytes)?;
+        self.file.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        StdoutSink.flush()?;
+        self.file.flush()
+    }
+}
+
+// Collects the answer instead of streaming it, for the modes which only print
+// a result once the generation is done.
+#[derive(Default)]
+pub struct StringSink {
+    pub bytes: Vec<u8>,
+}
+
+impl StringSink {
+    pub fn into_string(self) -> String {
+        String::from_utf8_lossy(&self.bytes).into_owned()
+    }
+
+    // The text collected so far, leaving the sink empty.
+    pub fn take(&mut self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut self.bytes)).into_owned()
+    }
+}
+
+impl TokenSink for StringSink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.bytes.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Prints newline-delimited JSON events instead of the text, set by
+// `--emit-json-events`: a `start` event with the number of prompt tokens, a
+// `token` event per streamed piece of the answer and an `end` event with the
+// stop reason and the statistics.
+pub struct JsonEventSink {
+    pub start: Instant,
+    // The alternatives of `--n-probs` for the next token event.
+    pub top_probs: Option<Value>,
+}
+
+impl JsonEventSink {
+    pub fn new() -> Self {
+        JsonEventSink {
+            start: Instant::now(),
+            top_probs: None,
+        }
+    }
+
+    pub fn emit(&self, event: Value) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        writeln!(stdout, "{}", event)?;
+        stdout.flush()
+    }
+}
+
+impl TokenSink for JsonEventSink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let mut event = json!({
+            "type": "token",
+            "text": String::from_utf8_lossy(bytes),
+            "t_ms": self.start.elapsed().as_millis() as u64,
+        });
+        if let Some(top_probs) = self.top_probs.take() {
+            event["top_probs"] = top_probs;
+        }
+        self.emit(event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_frame(&mut self, _text: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn top_probs(&mut self, top: &[(String, f64)]) -> io::Result<()> {
+        self.top_probs = Some(json!(top
+            .iter()
+            .map(|(token, probability)| json!({ "token": token, "p": probability }))
+            .collect::<Vec<_>>()));
+        Ok(())
+    }
+
+    fn start(&mut self, context: &dyn Backend) -> io::Result<()> {
+        self.start = Instant::now();
+        let input_tokens = try_get_metadata_from_context(context)
+            .and_then(|metadata| metadata["input_tokens"].as_u64());
+        self.emit(json!({ "type": "start", "input_tokens": input_tokens }))
+    }
+
+    fn end(&mut self, generation: &Generation) -> io::Result<()> {
+        let mut event = generation.stats.fields();
+        event.insert(String::from("type"), json!("end"));
+        event.insert(String::from("reason"), json!(generation.reason.name()));
+        event.insert(
+            String::from("error"),
+            json!(generation.error.as_ref().map(|err| err.to_string())),
+        );
+        self.emit(Value::Object(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Counts the flushes passed on to it.
+    struct FlushCounter(Rc<Cell<usize>>);
+
+    impl TokenSink for FlushCounter {
+        fn write(&mut self, _bytes: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn utf8_sink_holds_back_incomplete_characters() {
+        let mut sink = Utf8Sink::new(StringSink::default());
+        sink.write(b"a\xe2\x82").unwrap();
+        assert_eq!(sink.inner.take(), "a");
+        sink.write(b"\xac b").unwrap();
+        assert_eq!(sink.inner.take(), "\u{20ac} b");
+        sink.write(b"\xff!").unwrap();
+        assert_eq!(sink.inner.take(), "\u{fffd}!");
+        sink.finish().unwrap();
+        assert_eq!(sink.inner.take(), "");
+    }
+
+    #[test]
+    fn utf8_sink_writes_a_truncated_character_at_the_end() {
+        let mut sink = Utf8Sink::new(StringSink::default());
+        sink.write(b"x\xe2\x82").unwrap();
+        sink.write_frame("\n").unwrap();
+        assert_eq!(sink.inner.take(), "x\u{fffd}\n");
+        sink.finish().unwrap();
+        assert_eq!(sink.inner.take(), "");
+    }
+
+    #[test]
+    fn flush_policy_sink_flushes_at_boundaries() {
+        let flushes = Rc::new(Cell::new(0));
+        let inner = Box::new(FlushCounter(flushes.clone()));
+        let mut sink = FlushPolicySink::new(inner, FlushPolicy::Word);
+        sink.write(b"Hel").unwrap();
+        sink.flush().unwrap();
+        assert_eq!(flushes.get(), 0);
+        sink.write(b"lo ").unwrap();
+        sink.flush().unwrap();
+        assert_eq!(flushes.get(), 1);
+        sink.write(b"world").unwrap();
+        sink.flush().unwrap();
+        assert_eq!(flushes.get(), 1);
+        sink.finish().unwrap();
+        assert_eq!(flushes.get(), 2);
+    }
+
+    #[test]
+    fn top_probs_go_into_the_next_token_event() {
+        let mut sink = JsonEventSink::new();
+        sink.top_probs(&[
+            (String::from(" Paris"), 0.82),
+            (String::from(" London"), 0.1),
+        ])
+        .unwrap();
+        assert_eq!(
+            sink.top_probs,
+            Some(json!([
+                { "token": " Paris", "p": 0.82 },
+                { "token": " London", "p": 0.1 },
+            ]))
+        );
+        // An empty piece isn't an event and keeps them for the next one.
+        sink.write(b"").unwrap();
+        assert!(sink.top_probs.is_some());
+        sink.write(b" Paris").unwrap();
+        assert_eq!(sink.top_probs, None);
+    }
+
+    #[test]
+    fn flush_policies() {
+        assert_eq!(FlushPolicy::parse("word"), Ok(FlushPolicy::Word));
+        assert_eq!(
+            FlushPolicy::parse("interval:250"),
+            Ok(FlushPolicy::Interval(Duration::from_millis(250)))
+        );
+        for invalid in ["interval:0", "interval:", "words"] {
+            assert!(FlushPolicy::parse(invalid).is_err(), "{}", invalid);
+        }
+        assert!(FlushPolicy::Token.is_boundary(b"ab"));
+        assert!(!FlushPolicy::Word.is_boundary(b"ab"));
+        assert!(FlushPolicy::Word.is_boundary(b"a b"));
+        assert!(!FlushPolicy::Line.is_boundary(b"a b"));
+        assert!(FlushPolicy::Line.is_boundary(b"a\n"));
+        assert!(!FlushPolicy::Interval(Duration::from_millis(1)).is_boundary(b"\n"));
+    }
+}
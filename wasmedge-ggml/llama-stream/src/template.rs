@@ -0,0 +1,487 @@
+// The prompt templates of the model families and the names of the roles.
+use crate::conversation::{Language, Turn};
+
+// The prompt templates supported by the example.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PromptTemplate {
+    Llama2,
+    Llama3,
+    Mistral,
+    ChatMl,
+    Gemma,
+    Phi3,
+    // No template at all, set by `--raw`: the input is sent verbatim and the
+    // turns are simply concatenated.
+    Raw,
+}
+
+// The model families known to `--model-family`, by the template they use.
+const MODEL_FAMILIES: [(&str, PromptTemplate); 6] = [
+    ("llama2", PromptTemplate::Llama2),
+    ("llama3", PromptTemplate::Llama3),
+    ("mistral", PromptTemplate::Mistral),
+    ("chatml", PromptTemplate::ChatMl),
+    ("gemma", PromptTemplate::Gemma),
+    ("phi3", PromptTemplate::Phi3),
+];
+
+impl PromptTemplate {
+    pub fn from_name(name: &str) -> Option<Self> {
+        MODEL_FAMILIES
+            .iter()
+            .find(|(family, _)| *family == name)
+            .map(|&(_, template)| template)
+    }
+
+    // The family name of the template, `None` for the raw template.
+    pub fn name(self) -> Option<&'static str> {
+        MODEL_FAMILIES
+            .iter()
+            .find(|(_, template)| *template == self)
+            .map(|&(family, _)| family)
+    }
+
+    // The template family of the `chat_template` the model declares in its
+    // metadata, either a family name or a Jinja template told apart by its
+    // markers. The checks go from the most to the least specific marker, as
+    // Llama 2 and Mistral share `[INST]`.
+    fn from_chat_template(chat_template: &str) -> Option<Self> {
+        if let Some(template) = PromptTemplate::from_name(chat_template.trim()) {
+            return Some(template);
+        }
+        let markers = [
+            ("<|start_header_id|>", PromptTemplate::Llama3),
+            ("<|im_start|>", PromptTemplate::ChatMl),
+            ("<start_of_turn>", PromptTemplate::Gemma),
+            ("<|assistant|>", PromptTemplate::Phi3),
+            ("<<SYS>>", PromptTemplate::Llama2),
+            ("[INST]", PromptTemplate::Mistral),
+        ];
+        markers
+            .iter()
+            .find(|(marker, _)| chat_template.contains(marker))
+            .map(|&(_, template)| template)
+    }
+
+    // The names of the templates for error messages, e.g. "llama2, llama3".
+    pub fn names() -> String {
+        MODEL_FAMILIES
+            .iter()
+            .map(|(family, _)| *family)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    // Wrap a question into the template. The first question of the
+    // conversation also carries the system prompt.
+    pub fn format_question(self, system_prompt: &str, input: &str, first: bool) -> String {
+        // An empty turn of `--allow-empty-turn` only opens a new answer, which
+        // continues the conversation so far.
+        if input.is_empty() && !first {
+            return self.format_empty_turn().to_string();
+        }
+        match (self, first) {
+            (PromptTemplate::Llama2, true) => format!(
+                "[INST] <<SYS>> {} <</SYS>> {} [/INST]",
+                system_prompt, input
+            ),
+            (PromptTemplate::Llama2, false) => format!(" [INST] {} [/INST]", input),
+            (PromptTemplate::Llama3, true) => format!(
+                "<|start_header_id|>system<|end_header_id|>\n\n{}<|eot_id|>\n<|start_header_id|>user<|end_header_id|>\n\n{}<|eot_id|>\n<|start_header_id|>assistant<|end_header_id|>\n\n",
+                system_prompt, input
+            ),
+            (PromptTemplate::Llama3, false) => format!(
+                "<|start_header_id|>user<|end_header_id|>\n\n{}<|eot_id|>\n<|start_header_id|>assistant<|end_header_id|>\n\n",
+                input
+            ),
+            // Mistral and Gemma have no system role, the system prompt goes
+            // in front of the first question.
+            (PromptTemplate::Mistral, true) => {
+                format!("[INST] {}\n\n{} [/INST]", system_prompt, input)
+            }
+            (PromptTemplate::Mistral, false) => format!("[INST] {} [/INST]", input),
+            (PromptTemplate::ChatMl, true) => format!(
+                "<|im_start|>system\n{}<|im_end|>\n<|im_start|>user\n{}<|im_end|>\n<|im_start|>assistant\n",
+                system_prompt, input
+            ),
+            (PromptTemplate::ChatMl, false) => format!(
+                "<|im_start|>user\n{}<|im_end|>\n<|im_start|>assistant\n",
+                input
+            ),
+            (PromptTemplate::Gemma, true) => format!(
+                "<start_of_turn>user\n{}\n\n{}<end_of_turn>\n<start_of_turn>model\n",
+                system_prompt, input
+            ),
+            (PromptTemplate::Gemma, false) => format!(
+                "<start_of_turn>user\n{}<end_of_turn>\n<start_of_turn>model\n",
+                input
+            ),
+            (PromptTemplate::Phi3, true) => format!(
+                "<|system|>\n{}<|end|>\n<|user|>\n{}<|end|>\n<|assistant|>\n",
+                system_prompt, input
+            ),
+            (PromptTemplate::Phi3, false) => {
+                format!("<|user|>\n{}<|end|>\n<|assistant|>\n", input)
+            }
+            (PromptTemplate::Raw, _) => input.to_string(),
+        }
+    }
+
+    // The assistant marker that starts a new answer without a question,
+    // with the whitespace the template puts after it.
+    fn format_empty_turn(self) -> &'static str {
+        match self {
+            PromptTemplate::Llama2 => " [/INST]",
+            PromptTemplate::Llama3 => "<|start_header_id|>assistant<|end_header_id|>\n\n",
+            PromptTemplate::Mistral => "[/INST]",
+            PromptTemplate::ChatMl => "<|im_start|>assistant\n",
+            PromptTemplate::Gemma => "<start_of_turn>model\n",
+            PromptTemplate::Phi3 => "<|assistant|>\n",
+            PromptTemplate::Raw => "",
+        }
+    }
+
+    // The text that forces the answer to start with `prefill`, placed right
+    // after the assistant marker the rendered prompt ends with.
+    pub fn format_prefill(self, prefill: &str) -> String {
+        match self {
+            PromptTemplate::Llama2 | PromptTemplate::Mistral => format!(" {}", prefill),
+            _ => prefill.to_string(),
+        }
+    }
+
+    // Strip template markup the model echoed at the start of its answer, such
+    // as a stray `[/INST]` or assistant header. Kept in the history, it would
+    // be sent again with every following prompt and compound.
+    fn strip_leading_markup(self, answer: &str) -> &str {
+        let markers: &[&str] = match self {
+            PromptTemplate::Llama2 => &["[/INST]", "[INST]", "<s>", "</s>"],
+            PromptTemplate::Llama3 => &[
+                "<|start_header_id|>assistant<|end_header_id|>",
+                "assistant<|end_header_id|>",
+                "<|eot_id|>",
+            ],
+            PromptTemplate::Mistral => &["[/INST]", "[INST]", "<s>", "</s>"],
+            PromptTemplate::ChatMl => &["<|im_start|>assistant", "<|im_end|>"],
+            PromptTemplate::Gemma => &["<start_of_turn>model", "<end_of_turn>"],
+            PromptTemplate::Phi3 => &["<|assistant|>", "<|end|>"],
+            PromptTemplate::Raw => &[],
+        };
+        let mut answer = answer.trim_start();
+        while let Some(rest) = markers
+            .iter()
+            .find_map(|marker| answer.strip_prefix(marker))
+        {
+            answer = rest.trim_start();
+        }
+        answer
+    }
+
+    // The answer as it's kept in the history, without echoed markup and
+    // trailing whitespace. A raw answer is kept verbatim, since the next
+    // input is simply appended to it.
+    pub fn history_answer(self, answer: &str) -> String {
+        match self {
+            PromptTemplate::Raw => answer.to_string(),
+            _ => self.strip_leading_markup(answer).trim_end().to_string(),
+        }
+    }
+
+    // The assistant marker the prompt ends with, which some models echo as
+    // the start of their answer.
+    pub fn primer(self) -> &'static str {
+        match self {
+            PromptTemplate::Llama2 => "[/INST]",
+            PromptTemplate::Llama3 => "<|start_header_id|>assistant<|end_header_id|>",
+            PromptTemplate::Mistral => "[/INST]",
+            PromptTemplate::ChatMl => "<|im_start|>assistant",
+            PromptTemplate::Gemma => "<start_of_turn>model",
+            PromptTemplate::Phi3 => "<|assistant|>",
+            PromptTemplate::Raw => "",
+        }
+    }
+
+    // The end-of-turn markers of the template. Models with broken EOS
+    // metadata generate them as text instead of stopping, so they're used as
+    // stop sequences with `--model-family`.
+    pub fn stop_tokens(self) -> &'static [&'static str] {
+        match self {
+            PromptTemplate::Llama2 | PromptTemplate::Mistral => &["</s>"],
+            PromptTemplate::Llama3 => &["<|eot_id|>"],
+            PromptTemplate::ChatMl => &["<|im_end|>"],
+            PromptTemplate::Gemma => &["<end_of_turn>"],
+            PromptTemplate::Phi3 => &["<|end|>", "<|endoftext|>"],
+            PromptTemplate::Raw => &[],
+        }
+    }
+
+    // The special markers of the template, highlighted by `show_prompt`.
+    pub fn markers(self) -> &'static [&'static str] {
+        match self {
+            PromptTemplate::Llama2 => &["[INST]", "[/INST]", "<<SYS>>", "<</SYS>>", "<s>", "</s>"],
+            PromptTemplate::Llama3 => &[
+                "<|begin_of_text|>",
+                "<|start_header_id|>",
+                "<|end_header_id|>",
+                "<|eot_id|>",
+            ],
+            PromptTemplate::Mistral => &["[INST]", "[/INST]", "<s>", "</s>"],
+            PromptTemplate::ChatMl => &["<|im_start|>", "<|im_end|>"],
+            PromptTemplate::Gemma => &["<start_of_turn>", "<end_of_turn>"],
+            PromptTemplate::Phi3 => &["<|system|>", "<|user|>", "<|assistant|>", "<|end|>"],
+            PromptTemplate::Raw => &[],
+        }
+    }
+
+    pub fn format_answer(self, answer: &str) -> String {
+        match self {
+            PromptTemplate::Llama2 | PromptTemplate::Llama3 => format!(" {}", answer),
+            PromptTemplate::Mistral => format!(" {}</s>", answer),
+            PromptTemplate::ChatMl => format!("{}<|im_end|>\n", answer),
+            PromptTemplate::Gemma => format!("{}<end_of_turn>\n", answer),
+            PromptTemplate::Phi3 => format!("{}<|end|>\n", answer),
+            PromptTemplate::Raw => answer.to_string(),
+        }
+    }
+
+    // Rename the user and assistant roles of a rendered prompt to the names
+    // of `--user-name` and `--assistant-name`. Only ChatML and Llama 3 have
+    // named turns, the other templates keep their fixed markers.
+    pub fn rename_roles(self, prompt: String, roles: &RoleNames) -> String {
+        let header = |role: &str| match self {
+            PromptTemplate::ChatMl => Some(format!("<|im_start|>{}\n", role)),
+            PromptTemplate::Llama3 => Some(format!("<|start_header_id|>{}<|end_header_id|>", role)),
+            _ => None,
+        };
+        let mut prompt = prompt;
+        for (role, name) in [("user", &roles.user), ("assistant", &roles.assistant)] {
+            let Some(name) = name else { continue };
+            if let (Some(marker), Some(renamed)) = (header(role), header(name)) {
+                prompt = prompt.replace(&marker, &renamed);
+            }
+        }
+        prompt
+    }
+
+    pub fn has_named_turns(self) -> bool {
+        matches!(self, PromptTemplate::ChatMl | PromptTemplate::Llama3)
+    }
+
+    // Render the whole conversation followed by the new question.
+    pub fn format(self, system_prompt: &str, history: &[Turn], input: &str) -> String {
+        let mut prompt = String::new();
+        for (i, turn) in history.iter().enumerate() {
+            prompt += &self.format_question(system_prompt, &turn.user, i == 0);
+            prompt += &self.format_answer(&turn.assistant);
+        }
+        prompt + &self.format_question(system_prompt, input, history.is_empty())
+    }
+}
+
+// The names of `--user-name` and `--assistant-name`, shown as the labels of
+// the interactive mode and used as the roles of templates with named turns.
+#[derive(Debug, Clone, Default)]
+pub struct RoleNames {
+    pub user: Option<String>,
+    pub assistant: Option<String>,
+}
+
+impl RoleNames {
+    pub fn user_label(&self, language: &Language) -> String {
+        self.user.as_ref().map_or_else(
+            || language.user_label.to_string(),
+            |name| format!("{}:", name),
+        )
+    }
+
+    pub fn assistant_label(&self, language: &Language) -> String {
+        self.assistant.as_ref().map_or_else(
+            || language.assistant_label.to_string(),
+            |name| format!("{}:", name),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.user.is_none() && self.assistant.is_none()
+    }
+}
+
+// A name of `--user-name` or `--assistant-name`, which goes into the markup
+// of the template and so has to be a single non-empty line.
+pub fn check_role_name(flag: &str, name: String) -> Result<String, String> {
+    if name.trim().is_empty() || name.contains(['\n', '\r']) {
+        return Err(format!("{} must be a non-empty single line", flag));
+    }
+    Ok(name)
+}
+
+// Make a prompt readable as it's sent to the backend: control characters are
+// shown escaped, newlines also keep their line break, and the markers of the
+// template are highlighted as `«[INST]»`.
+pub fn show_prompt(prompt: &str, template: PromptTemplate) -> String {
+    let mut shown = String::new();
+    for c in prompt.chars() {
+        match c {
+            '\n' => shown += "\\n\n",
+            '\r' => shown += "\\r",
+            '\t' => shown += "\\t",
+            c if c.is_control() => shown += &c.escape_unicode().to_string(),
+            c => shown.push(c),
+        }
+    }
+    for marker in template.markers() {
+        shown = shown.replace(marker, &format!("«{}»", marker));
+    }
+    shown
+}
+
+// With `--strict-template`, the family the model declares if the chosen
+// template is a different one. The raw template is never a mismatch, the
+// input is formatted by the user, and neither is a declared template of an
+// unknown family.
+pub fn template_mismatch(chosen: PromptTemplate, chat_template: &str) -> Option<PromptTemplate> {
+    if chosen == PromptTemplate::Raw {
+        return None;
+    }
+    PromptTemplate::from_chat_template(chat_template).filter(|&declared| declared != chosen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::language;
+    use crate::Log;
+
+    #[test]
+    fn empty_turn_only_opens_the_answer() {
+        let templates = MODEL_FAMILIES.iter().map(|(_, template)| *template);
+        for template in templates {
+            let empty = template.format_question("Be brief.", "", false);
+            assert_eq!(empty, template.format_empty_turn());
+            assert!(template
+                .format_question("Be brief.", "Why?", false)
+                .ends_with(&empty));
+            // The first turn has no conversation to continue, so it's
+            // formatted as a question with the system prompt.
+            let first = template.format_question("Be brief.", "", true);
+            assert!(first.contains("Be brief."), "{:?}", template);
+        }
+        assert_eq!(PromptTemplate::Raw.format_question("", "", false), "");
+        assert_eq!(
+            PromptTemplate::ChatMl.format_question("", "", false),
+            "<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn history_answer_strips_echoed_markup() {
+        assert_eq!(
+            PromptTemplate::Llama2.history_answer(" [/INST] </s> Paris. \n"),
+            "Paris."
+        );
+        assert_eq!(
+            PromptTemplate::ChatMl.history_answer("<|im_start|>assistant\nParis.<|im_end|>"),
+            "Paris.<|im_end|>"
+        );
+        // Markup in the middle of the answer is the model's business.
+        assert_eq!(
+            PromptTemplate::Phi3.history_answer("Use <|assistant|> here."),
+            "Use <|assistant|> here."
+        );
+        assert_eq!(
+            PromptTemplate::Raw.history_answer(" [/INST] Paris. "),
+            " [/INST] Paris. "
+        );
+    }
+
+    #[test]
+    fn model_family_names_round_trip() {
+        for (family, template) in MODEL_FAMILIES {
+            assert_eq!(PromptTemplate::from_name(family), Some(template));
+            assert_eq!(template.name(), Some(family));
+        }
+        assert_eq!(PromptTemplate::from_name("Llama3"), None);
+        assert_eq!(PromptTemplate::Raw.name(), None);
+        assert_eq!(
+            PromptTemplate::names(),
+            "llama2, llama3, mistral, chatml, gemma, phi3"
+        );
+    }
+
+    fn roles() -> RoleNames {
+        RoleNames {
+            user: Some(String::from("Alice")),
+            assistant: Some(String::from("Bot")),
+        }
+    }
+
+    #[test]
+    fn role_names_are_the_labels() {
+        let english = language("en", Log::default());
+        assert_eq!(roles().user_label(english), "Alice:");
+        assert_eq!(roles().assistant_label(english), "Bot:");
+        assert_eq!(RoleNames::default().user_label(english), english.user_label);
+        assert!(check_role_name("--user-name", String::from(" ")).is_err());
+        assert_eq!(
+            check_role_name("--user-name", String::from("Al\nice")),
+            Err(String::from("--user-name must be a non-empty single line"))
+        );
+    }
+
+    #[test]
+    fn rename_roles_of_named_turns() {
+        let prompt = PromptTemplate::ChatMl.format("Be brief.", &[], "Hi");
+        let renamed = PromptTemplate::ChatMl.rename_roles(prompt, &roles());
+        assert!(renamed.contains("<|im_start|>Alice\nHi"), "{}", renamed);
+        assert!(renamed.ends_with("<|im_start|>Bot\n"), "{}", renamed);
+        let prompt = PromptTemplate::Llama2.format("Be brief.", &[], "Hi");
+        assert_eq!(
+            PromptTemplate::Llama2.rename_roles(prompt.clone(), &roles()),
+            prompt
+        );
+    }
+
+    #[test]
+    fn chat_template_families() {
+        let jinja = |marker: &str| {
+            format!(
+                "{{% for message in messages %}}{}{{{{ message['content'] }}}}{{% endfor %}}",
+                marker
+            )
+        };
+        assert_eq!(
+            PromptTemplate::from_chat_template(&jinja("<|start_header_id|>")),
+            Some(PromptTemplate::Llama3)
+        );
+        assert_eq!(
+            PromptTemplate::from_chat_template(&jinja("<<SYS>>[INST]")),
+            Some(PromptTemplate::Llama2)
+        );
+        assert_eq!(
+            PromptTemplate::from_chat_template(&jinja("[INST]")),
+            Some(PromptTemplate::Mistral)
+        );
+        assert_eq!(
+            PromptTemplate::from_chat_template(" chatml\n"),
+            Some(PromptTemplate::ChatMl)
+        );
+        assert_eq!(PromptTemplate::from_chat_template("{{ messages }}"), None);
+    }
+
+    #[test]
+    fn template_mismatch_only_for_known_families() {
+        assert_eq!(
+            template_mismatch(PromptTemplate::Llama2, "<|im_start|>"),
+            Some(PromptTemplate::ChatMl)
+        );
+        assert_eq!(
+            template_mismatch(PromptTemplate::ChatMl, "<|im_start|>"),
+            None
+        );
+        assert_eq!(template_mismatch(PromptTemplate::Raw, "<|im_start|>"), None);
+        assert_eq!(
+            template_mismatch(PromptTemplate::Gemma, "{{ unknown }}"),
+            None
+        );
+    }
+}
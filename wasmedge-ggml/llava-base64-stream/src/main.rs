@@ -4,7 +4,6 @@ use std::env;
 use std::io::{self, Write};
 use wasmedge_wasi_nn::{
     self, BackendError, Error, ExecutionTarget, GraphBuilder, GraphEncoding, GraphExecutionContext,
-    TensorType,
 };
 
 fn read_input() -> String {
@@ -49,10 +48,6 @@ fn get_options_from_env() -> HashMap<&'static str, Value> {
     options
 }
 
-fn set_data_to_context(context: &mut GraphExecutionContext, data: Vec<u8>) -> Result<(), Error> {
-    context.set_input(0, TensorType::U8, &[1], &data)
-}
-
 fn get_data_from_context(context: &GraphExecutionContext, index: usize, is_single: bool) -> String {
     // Preserve for 4096 tokens with average token length 6
     const MAX_OUTPUT_BUFFER_SIZE: usize = 4096 * 6;
@@ -68,7 +63,7 @@ fn get_data_from_context(context: &GraphExecutionContext, index: usize, is_singl
     };
     output_size = std::cmp::min(MAX_OUTPUT_BUFFER_SIZE, output_size);
 
-    return String::from_utf8_lossy(&output_buffer[..output_size]).to_string();
+    String::from_utf8_lossy(&output_buffer[..output_size]).to_string()
 }
 
 fn get_output_from_context(context: &GraphExecutionContext) -> String {
@@ -101,10 +96,11 @@ fn main() {
     if args.len() >= 3 {
         let prompt = &args[2];
         println!("Prompt:\n{}", prompt);
-        let tensor_data = prompt.as_bytes().to_vec();
-        context
-            .set_input(0, TensorType::U8, &[1], &tensor_data)
-            .expect("Failed to set input");
+        tensor_input::set_bytes_input(&mut context, 0, &[prompt.len()], prompt.as_bytes())
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
         println!("Response:");
         context.compute().expect("Failed to compute");
         let output = get_output_from_context(&context);
@@ -136,8 +132,16 @@ fn main() {
         }
 
         // Set prompt to the input tensor.
-        set_data_to_context(&mut context, saved_prompt.as_bytes().to_vec())
-            .expect("Failed to set input");
+        tensor_input::set_bytes_input(
+            &mut context,
+            0,
+            &[saved_prompt.len()],
+            saved_prompt.as_bytes(),
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
 
         // Execute the inference.
         let mut output = String::new();
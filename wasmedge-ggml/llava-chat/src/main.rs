@@ -0,0 +1,256 @@
+use serde_json::json;
+use serde_json::Value;
+use std::env;
+use std::io;
+use std::time::Instant;
+use wasmedge_wasi_nn::{
+    self, BackendError, Error, ExecutionTarget, GraphBuilder, GraphEncoding, GraphExecutionContext,
+    TensorType,
+};
+
+fn read_input() -> String {
+    loop {
+        let mut answer = String::new();
+        io::stdin()
+            .read_line(&mut answer)
+            .expect("Failed to read line");
+        if !answer.is_empty() && answer != "\n" && answer != "\r\n" {
+            return answer.trim().to_string();
+        }
+    }
+}
+
+fn get_env_option(name: &str, default: &str) -> Value {
+    let val = env::var(name).unwrap_or_else(|_| default.to_string());
+    serde_json::from_str(val.as_str())
+        .unwrap_or_else(|_| panic!("invalid value for {} option: {}", name, val))
+}
+
+// The options of the llava graph, which only describes the image.
+fn get_llava_options_from_env() -> Value {
+    let mut options = json!({});
+
+    // Required parameters for llava
+    if let Ok(val) = env::var("mmproj") {
+        options["mmproj"] = Value::from(val.as_str());
+    } else {
+        eprintln!("Failed to get mmproj model.");
+        std::process::exit(1);
+    }
+    if let Ok(val) = env::var("image") {
+        options["image"] = Value::from(val.as_str());
+    } else {
+        eprintln!("Failed to get the target image.");
+        std::process::exit(1);
+    }
+
+    // Optional parameters
+    options["enable-log"] = get_env_option("enable_log", "false");
+    options["n-gpu-layers"] = get_env_option("llava_n_gpu_layers", "0");
+    // llava 1.6 needs at least 4096 tokens for the image embeddings.
+    options["ctx-size"] = get_env_option("llava_ctx_size", "4096");
+    // A low temperature keeps the description to what's in the image.
+    options["temp"] = get_env_option("llava_temp", "0.1");
+    options
+}
+
+// The options of the chat graph, which answers the questions.
+fn get_chat_options_from_env() -> Value {
+    let mut options = json!({});
+    options["enable-log"] = get_env_option("enable_log", "false");
+    options["n-gpu-layers"] = get_env_option("chat_n_gpu_layers", "0");
+    options["ctx-size"] = get_env_option("chat_ctx_size", "4096");
+    options
+}
+
+fn set_data_to_context(context: &mut GraphExecutionContext, data: Vec<u8>) -> Result<(), Error> {
+    context.set_input(0, TensorType::U8, &[1], &data)
+}
+
+fn get_data_from_context(context: &GraphExecutionContext, index: usize) -> String {
+    // Preserve for 4096 tokens with average token length 6
+    const MAX_OUTPUT_BUFFER_SIZE: usize = 4096 * 6;
+    let mut output_buffer = vec![0u8; MAX_OUTPUT_BUFFER_SIZE];
+    let mut output_size = context
+        .get_output(index, &mut output_buffer)
+        .expect("Failed to get output");
+    output_size = std::cmp::min(MAX_OUTPUT_BUFFER_SIZE, output_size);
+
+    String::from_utf8_lossy(&output_buffer[..output_size]).to_string()
+}
+
+fn get_output_from_context(context: &GraphExecutionContext) -> String {
+    get_data_from_context(context, 0)
+}
+
+// Run the prompt and return the answer. Returns `None` if the context is
+// full or the prompt is too long, so the caller can start over.
+fn compute(context: &mut GraphExecutionContext, prompt: &str) -> Option<String> {
+    set_data_to_context(context, prompt.as_bytes().to_vec()).expect("Failed to set input");
+    match context.compute() {
+        Ok(_) => (),
+        Err(Error::BackendError(BackendError::ContextFull)) => {
+            println!("\n[INFO] Context full, we'll reset the context and continue.");
+            return None;
+        }
+        Err(Error::BackendError(BackendError::PromptTooLong)) => {
+            println!("\n[INFO] Prompt too long, we'll reset the context and continue.");
+            return None;
+        }
+        Err(err) => {
+            println!("\n[ERROR] {}", err);
+        }
+    }
+    Some(get_output_from_context(context).trim().to_string())
+}
+
+// The llava chat format is "<system_prompt>\nUSER:<image_embeddings>\n<textual_prompt>\nASSISTANT:",
+// where the plugin replaces the image placeholder with the embeddings of the image.
+fn llava_prompt(system_prompt: &str, question: &str) -> String {
+    let image_placeholder = "<image>";
+    format!(
+        "{}\nUSER:{}\n{}\nASSISTANT:",
+        system_prompt, image_placeholder, question
+    )
+}
+
+// The description goes into the system block, so it's kept for the whole conversation.
+fn chat_system_prompt(system_prompt: &str, description: &str) -> String {
+    format!(
+        "{} The user shares a photo with you, which you can't see yourself. Answer the questions about it from this detailed description of the photo:\n{}",
+        system_prompt, description
+    )
+}
+
+// Add a question to the conversation in the llama2 chat format. The first one
+// opens the conversation with the system block.
+fn push_question(saved_prompt: &str, chat_system_prompt: &str, input: &str) -> String {
+    if saved_prompt.is_empty() {
+        format!(
+            "[INST] <<SYS>> {} <</SYS>> {} [/INST]",
+            chat_system_prompt, input
+        )
+    } else {
+        format!("{} [INST] {} [/INST]", saved_prompt, input)
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    // The names of the preloaded models.
+    let llava_name = args.get(1).map_or("llava", String::as_str);
+    let chat_name = args.get(2).map_or("chat", String::as_str);
+
+    let system_prompt = "You are a helpful, respectful and honest assistant. Always answer as short as possible, while being safe.";
+
+    // Stage 1: Let the llava model describe the image in detail.
+    let start = Instant::now();
+    let llava_options = get_llava_options_from_env();
+    let llava_graph = GraphBuilder::new(GraphEncoding::Ggml, ExecutionTarget::AUTO)
+        .config(serde_json::to_string(&llava_options).expect("Failed to serialize options"))
+        .build_from_cache(llava_name)
+        .expect("Failed to build graph");
+    let description = {
+        let mut llava_context = llava_graph
+            .init_execution_context()
+            .expect("Failed to init context");
+        let describe_prompt = "Describe this image in as much detail as possible: the objects, people, text, colors and the setting.";
+        compute(
+            &mut llava_context,
+            &llava_prompt(system_prompt, describe_prompt),
+        )
+        .unwrap_or_else(|| {
+            eprintln!("[ERROR] Failed to describe the image, try a larger llava_ctx_size.");
+            std::process::exit(1);
+        })
+    };
+    println!("DESCRIPTION (llava):\n{}", description);
+    println!(
+        "[INFO] Describing the image took {:.2}s",
+        start.elapsed().as_secs_f64()
+    );
+
+    // The llava model isn't needed anymore, free its memory before loading the chat model.
+    if let Err(err) = llava_graph.unload() {
+        println!("[WARN] Failed to unload the llava model: {}", err);
+    }
+
+    // Stage 2: Answer questions about the image with the chat model, which
+    // only knows the image by its description.
+    let start = Instant::now();
+    let chat_options = get_chat_options_from_env();
+    let chat_graph = GraphBuilder::new(GraphEncoding::Ggml, ExecutionTarget::AUTO)
+        .config(serde_json::to_string(&chat_options).expect("Failed to serialize options"))
+        .build_from_cache(chat_name)
+        .expect("Failed to build graph");
+    let mut chat_context = chat_graph
+        .init_execution_context()
+        .expect("Failed to init context");
+    println!(
+        "[INFO] Loading the chat model took {:.2}s",
+        start.elapsed().as_secs_f64()
+    );
+
+    let chat_system_prompt = chat_system_prompt(system_prompt, &description);
+    let mut saved_prompt = String::new();
+    println!("Ask anything about the photo.");
+    loop {
+        println!("USER:");
+        let input = read_input();
+        saved_prompt = push_question(&saved_prompt, &chat_system_prompt, &input);
+
+        let start = Instant::now();
+        match compute(&mut chat_context, &saved_prompt) {
+            Some(output) => {
+                println!("ASSISTANT:\n{}", output);
+                saved_prompt = format!("{} {}", saved_prompt, output);
+            }
+            None => {
+                // The question wasn't answered, the conversation starts over.
+                println!("[INFO] The conversation was reset, please ask the question again. The description of the photo is kept.");
+                saved_prompt.clear();
+            }
+        }
+        println!(
+            "[INFO] Answering took {:.2}s",
+            start.elapsed().as_secs_f64()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn llava_prompt_has_the_image_placeholder() {
+        assert_eq!(
+            llava_prompt("Be brief.", "Describe this image."),
+            "Be brief.\nUSER:<image>\nDescribe this image.\nASSISTANT:"
+        );
+    }
+
+    #[test]
+    fn description_goes_into_the_system_block() {
+        let system = chat_system_prompt("Be brief.", "A red bicycle against a wall.");
+        assert!(system.starts_with("Be brief. The user shares a photo"));
+        assert!(system.ends_with("description of the photo:\nA red bicycle against a wall."));
+        let prompt = push_question("", &system, "What color is it?");
+        assert_eq!(
+            prompt,
+            format!(
+                "[INST] <<SYS>> {} <</SYS>> What color is it? [/INST]",
+                system
+            )
+        );
+    }
+
+    #[test]
+    fn later_questions_continue_the_conversation() {
+        let prompt = "[INST] <<SYS>> Be brief. <</SYS>> What color is it? [/INST] Red.";
+        assert_eq!(
+            push_question(prompt, "Be brief.", "Where is it?"),
+            format!("{} [INST] Where is it? [/INST]", prompt)
+        );
+    }
+}
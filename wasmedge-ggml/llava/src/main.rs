@@ -4,7 +4,6 @@ use std::env;
 use std::io;
 use wasmedge_wasi_nn::{
     self, BackendError, Error, ExecutionTarget, GraphBuilder, GraphEncoding, GraphExecutionContext,
-    TensorType,
 };
 
 fn read_input() -> String {
@@ -55,10 +54,6 @@ fn get_options_from_env() -> HashMap<&'static str, Value> {
     options
 }
 
-fn set_data_to_context(context: &mut GraphExecutionContext, data: Vec<u8>) -> Result<(), Error> {
-    context.set_input(0, TensorType::U8, &[1], &data)
-}
-
 fn get_data_from_context(context: &GraphExecutionContext, index: usize) -> String {
     // Preserve for 4096 tokens with average token length 6
     const MAX_OUTPUT_BUFFER_SIZE: usize = 4096 * 6;
@@ -104,10 +99,11 @@ fn main() {
         let prompt = &args[2];
         // Set the prompt.
         println!("Prompt:\n{}", prompt);
-        let tensor_data = prompt.as_bytes().to_vec();
-        context
-            .set_input(0, TensorType::U8, &[1], &tensor_data)
-            .expect("Failed to set input");
+        tensor_input::set_bytes_input(&mut context, 0, &[prompt.len()], prompt.as_bytes())
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
         println!("Response:");
 
         // Get the number of input tokens and llama.cpp versions.
@@ -159,8 +155,16 @@ fn main() {
         }
 
         // Set prompt to the input tensor.
-        set_data_to_context(&mut context, saved_prompt.as_bytes().to_vec())
-            .expect("Failed to set input");
+        tensor_input::set_bytes_input(
+            &mut context,
+            0,
+            &[saved_prompt.len()],
+            saved_prompt.as_bytes(),
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
 
         // Execute the inference.
         let mut reset_prompt = false;
@@ -1,7 +1,7 @@
 use std::env;
-use std::fs;
 use std::error::Error;
-use wasmedge_wasi_nn::{GraphBuilder, GraphEncoding, ExecutionTarget, TensorType};
+use std::fs;
+use wasmedge_wasi_nn::{ExecutionTarget, GraphBuilder, GraphEncoding};
 
 pub fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
@@ -11,7 +11,8 @@ pub fn main() -> Result<(), Box<dyn Error>> {
     let model_bin = fs::read(model_bin_name)?;
     println!("Read model, size in bytes: {}", model_bin.len());
 
-    let graph = GraphBuilder::new(GraphEncoding::Whisper, ExecutionTarget::CPU).build_from_bytes(&[&model_bin])?;
+    let graph = GraphBuilder::new(GraphEncoding::Whisper, ExecutionTarget::CPU)
+        .build_from_bytes([&model_bin])?;
     let mut ctx = graph.init_execution_context()?;
     println!("Loaded graph into wasi-nn with ID: {}", graph);
 
@@ -20,16 +21,21 @@ pub fn main() -> Result<(), Box<dyn Error>> {
     println!("Read input tensor, size in bytes: {}", wav_buf.len());
 
     // Set input.
-    ctx.set_input(0, TensorType::F32, &[1, wav_buf.len()], &wav_buf)?;
+    let samples = tensor_input::f32_from_le_bytes(&wav_buf)?;
+    tensor_input::set_f32_input(&mut ctx, 0, &[1, samples.len()], &samples)?;
 
     // Execute the inference.
     ctx.compute()?;
 
     // Retrieve the output.
     let mut output_buffer = vec![0u8; 2048];
-    _ = ctx.get_output(0, &mut output_buffer)?;
+    let size_in_bytes = ctx.get_output(0, &mut output_buffer)?;
+    output_buffer.truncate(size_in_bytes);
+
+    println!(
+        "Recognized from audio: \n{}",
+        String::from_utf8_lossy(&output_buffer)
+    );
 
-    println!("Recognized from audio: \n{}", String::from_utf8(output_buffer).unwrap());
-    
     Ok(())
 }